@@ -0,0 +1,430 @@
+//! Asynchronous UDFs, which let Excel carry on calculating other cells while a slow
+//! function, such as one that fetches data from a server, runs in the background. Such a
+//! function is registered with FunctionSpec::asynchronous, which gives it no return value
+//! and an extra last argument, the handle of the call. The function returns at once,
+//! after passing the handle and the work to `spawn`, which runs the work on a pool of
+//! threads and sends the result back to Excel through xlAsyncReturn:
+//!
+//! ```
+//! # use xladd::async_udf;
+//! # use xladd::registrator::{Reg, FunctionSpec, ArgType};
+//! # use xladd::variant::Variant;
+//! # use xladd::variant_ref::VariantRef;
+//! extern "system" fn slow_square(x: VariantRef, handle: VariantRef) {
+//!     let x = x.as_f64().unwrap_or(0.0);
+//!     async_udf::spawn("slow_square", handle, move || {
+//!         Variant::from_float(x * x)
+//!     });
+//! }
+//!
+//! fn register(reg: &Reg) {
+//!     let spec = FunctionSpec::new("slow_square")
+//!         .arg("x", ArgType::Variant, "the number to square")
+//!         .asynchronous();
+//!     reg.register(&spec).unwrap();
+//! }
+//! ```
+//!
+//! Note that the arguments must be copied out before the function returns, as Excel only
+//! lends them for the duration of the call.
+//!
+//! If the user cancels the calculation, for example by pressing Esc, Excel discards every
+//! outstanding call. Registering the first asynchronous function also installs a handler
+//! for this, which drops the work that has not started, and the results of the work that
+//! has, as Excel would refuse them. Long-running work can check `is_canceled` to stop
+//! early.
+//!
+//! The work runs on a pool with a thread per processor, unless set_executor supplies
+//! another way to run it, such as an existing thread pool or async runtime. When the addin
+//! closes, xlauto::close_all cancels all the work and waits for the pool to finish.
+
+#![allow(non_upper_case_globals)]
+
+use std::cell::Cell;
+use std::future::Future;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use std::time::Duration;
+use entrypoint::{excel12v, XlRetError};
+use events::{self, CalcEvent};
+use protect;
+use registrator::{Reg, RegisterError, debug_print};
+use variant::{Variant, XlOwnedVariant};
+use variant_ref::VariantRef;
+use xlcall::{XLOPER12, xltypeBigData, xlbitDLLFree, xlbitXLFree, xlAsyncReturn};
+use xlerror::XlError;
+
+/// A piece of work to be run in the background
+pub type Job = Box<dyn FnOnce() + Send>;
+
+type Executor = dyn Fn(Job) + Send + Sync;
+
+static EXECUTOR: RwLock<Option<Arc<Executor>>> = RwLock::new(None);
+static POOL: Mutex<Option<Pool>> = Mutex::new(None);
+static CANCEL_HANDLER: AtomicBool = AtomicBool::new(false);
+
+// The built-in pool, which is started when first needed
+struct Pool {
+    sender: mpsc::Sender<Job>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+// Incremented whenever a calculation is canceled, which makes every handle issued before
+// then stale
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // The generation of the call whose work is running on this thread, if any
+    static RUNNING: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// The handle of an asynchronous call, through which its result is returned to Excel. If
+/// the handle is dropped without a result having been returned, #VALUE! is returned, as
+/// otherwise the cell would wait for ever.
+pub struct AsyncHandle {
+    handle: XLOPER12,
+    generation: u64,
+    completed: bool,
+}
+
+// The handle is an opaque value that Excel allows to be returned from any thread
+unsafe impl Send for AsyncHandle {}
+
+impl AsyncHandle {
+    /// Takes the handle from the argument that Excel passes to an asynchronous function.
+    /// Returns None if the argument is not a handle.
+    pub fn new(arg: VariantRef) -> Option<AsyncHandle> {
+        let xloper = arg.as_xloper();
+        if xloper.xltype & !(xlbitDLLFree | xlbitXLFree) != xltypeBigData {
+            return None
+        }
+        Some(AsyncHandle { handle: *xloper, generation: GENERATION.load(Ordering::SeqCst), completed: false })
+    }
+
+    /// Whether the calculation this call belongs to has been canceled, in which case Excel
+    /// no longer wants the result
+    pub fn is_canceled(&self) -> bool {
+        self.generation != GENERATION.load(Ordering::SeqCst)
+    }
+
+    /// Returns the result to Excel. This may be called from any thread. If the calculation
+    /// has been canceled, Excel is not called, and the result is
+    /// Err(XlRetError::InvAsynchronousContext).
+    pub fn complete(mut self, result: Variant) -> Result<(), XlRetError> {
+        self.completed = true;
+        self.send(result)
+    }
+
+    fn send(&mut self, mut result: Variant) -> Result<(), XlRetError> {
+        if self.is_canceled() {
+            return Err(XlRetError::InvAsynchronousContext)
+        }
+        let mut res = *Variant::new().as_xloper();
+        let code = excel12v(xlAsyncReturn as i32, &mut res, &[&mut self.handle, result.as_mut_xloper()]);
+        drop(unsafe { XlOwnedVariant::from_result(res) });
+        XlRetError::check(code)
+    }
+}
+
+impl Drop for AsyncHandle {
+    fn drop(&mut self) {
+        if !self.completed && !self.is_canceled() {
+            let _ = self.send(Variant::from_err(XlError::Value));
+        }
+    }
+}
+
+/// Runs the work of an asynchronous call in the background, then returns its result to
+/// Excel. The handle is the last argument Excel passed to the function. The work runs
+/// with panic protection, so if it panics, the panic result is returned. The name of the
+/// function is only used to report panics.
+pub fn spawn<F>(function: &str, handle: VariantRef, work: F) where F: FnOnce() -> Variant + Send + 'static {
+    let handle = match AsyncHandle::new(handle) {
+        Some(handle) => handle,
+        None => {
+            debug_print(&format!("{} was not passed an asynchronous handle", function));
+            return
+        }
+    };
+    let function = function.to_string();
+    execute(Box::new(move || {
+        // work for a canceled calculation is dropped without running
+        if handle.is_canceled() {
+            return
+        }
+        RUNNING.with(|running| running.set(Some(handle.generation)));
+        let result = protect::value(&function, work);
+        RUNNING.with(|running| running.set(None));
+        let _ = handle.complete(result);
+    }));
+}
+
+/// Runs a future as the work of an asynchronous call, returning its output to Excel. The
+/// future is polled to completion on one of the background threads, unless the
+/// calculation is canceled, in which case it is dropped. Where the future needs a
+/// particular runtime, use that runtime to run it instead, then return its output with
+/// AsyncHandle::complete.
+pub fn spawn_future<F>(function: &str, handle: VariantRef, future: F) where F: Future<Output = Variant> + Send + 'static {
+    spawn(function, handle, move || block_on(future))
+}
+
+/// Whether the calculation that the work running on this thread belongs to has been
+/// canceled. Long-running work can check this from time to time and stop early, as its
+/// result will not be wanted. Always false outside the work passed to `spawn`.
+pub fn is_canceled() -> bool {
+    RUNNING.with(|running| running.get()).is_some_and(|generation| generation != GENERATION.load(Ordering::SeqCst))
+}
+
+/// Discards every outstanding asynchronous call. This is what the handler installed by
+/// register_cancel_handler does when the user cancels a calculation.
+pub fn cancel_all() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Arranges for cancel_all to be called whenever the user cancels a calculation, unless
+/// it has been arranged already. Reg::register does this when it registers an
+/// asynchronous function, so there is no need to call it directly.
+pub fn register_cancel_handler(reg: &Reg) -> Result<(), RegisterError> {
+    if CANCEL_HANDLER.swap(true, Ordering::SeqCst) {
+        return Ok(())
+    }
+    events::on(reg, CalcEvent::Canceled, cancel_all).inspect_err(|_| CANCEL_HANDLER.store(false, Ordering::SeqCst))
+}
+
+/// Cancels all outstanding work, then stops the built-in pool, waiting for any work that
+/// is running to finish, so that nothing is left running code from the dll once it is
+/// unloaded. This is called by xlauto::close_all. Work handed to an executor from
+/// set_executor is canceled, but cannot be waited for. The pool is started again if more
+/// work is spawned.
+pub fn shut_down() {
+    cancel_all();
+    CANCEL_HANDLER.store(false, Ordering::SeqCst);
+    let pool = POOL.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some(pool) = pool {
+        // the workers stop once the channel is closed and empty
+        drop(pool.sender);
+        for thread in pool.threads {
+            if thread.join().is_err() {
+                debug_print("an asynchronous thread panicked");
+            }
+        }
+    }
+}
+
+/// Sets how the work of asynchronous calls is run, in place of the built-in pool of
+/// threads. The executor is given each piece of work, and should run it on some other
+/// thread, for example with `move |job| { pool.execute(job) }`.
+pub fn set_executor<F>(executor: F) where F: Fn(Job) + Send + Sync + 'static {
+    *EXECUTOR.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(executor));
+}
+
+/// Goes back to running work on the built-in pool of threads
+pub fn reset_executor() {
+    *EXECUTOR.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+fn execute(job: Job) {
+    let executor = EXECUTOR.read().unwrap_or_else(|e| e.into_inner()).clone();
+    match executor {
+        Some(executor) => executor(job),
+        None => {
+            let mut pool = POOL.lock().unwrap_or_else(|e| e.into_inner());
+            let pool = pool.get_or_insert_with(start_pool);
+            if let Err(mpsc::SendError(job)) = pool.sender.send(job) {
+                // the threads have all gone, but dropping the job still returns #VALUE!
+                // to Excel through its handle
+                drop(job);
+            }
+        }
+    }
+}
+
+// Starts a thread per processor, which take turns to receive work from a channel
+fn start_pool() -> Pool {
+    let (sender, receiver) = mpsc::channel::<Job>();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let count = thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
+    let mut threads = Vec::with_capacity(count);
+    for i in 0..count {
+        let receiver = receiver.clone();
+        let spawned = thread::Builder::new().name(format!("xladd-async-{}", i)).spawn(move || loop {
+            let job = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+            match job {
+                Ok(job) => job(),
+                Err(_) => break
+            }
+        });
+        match spawned {
+            Ok(thread) => threads.push(thread),
+            Err(e) => debug_print(&format!("failed to start asynchronous thread: {}", e))
+        }
+    }
+    Pool { sender, threads }
+}
+
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+// Polls a future to completion on this thread, giving up if the calculation is canceled
+fn block_on<F: Future<Output = Variant>>(future: F) -> Variant {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(result) = future.as_mut().poll(&mut context) {
+            return result
+        }
+        if is_canceled() {
+            return Variant::from_err(XlError::NA)
+        }
+        thread::park_timeout(Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use registrator::{ArgType, FunctionSpec};
+    use testing::{self, FakeExcel};
+    use xlcall::{LPXLOPER12, xleventCalculationCanceled};
+
+    extern "system" fn slow_double(x: VariantRef, handle: VariantRef) {
+        let x = x.as_f64();
+        spawn("slow_double", handle, move || match x {
+            Some(x) => Variant::from_float(x * 2.0),
+            None => panic!("not a number")
+        });
+    }
+
+    // Pending on the first poll, to exercise the waker
+    struct Ready(bool);
+
+    impl Future for Ready {
+        type Output = Variant;
+        fn poll(mut self: ::std::pin::Pin<&mut Self>, cx: &mut Context) -> Poll<Variant> {
+            if self.0 {
+                Poll::Ready(Variant::from_str("done"))
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    extern "system" fn future_result(handle: VariantRef) {
+        spawn_future("future_result", handle, Ready(false));
+    }
+
+    #[test]
+    fn results_come_back_through_excel() {
+        let excel = FakeExcel::new("async.xll");
+        excel.export("slow_double", slow_double as extern "system" fn(VariantRef, VariantRef));
+        excel.export("future_result", future_result as extern "system" fn(VariantRef));
+        let _guard = excel.install();
+        shut_down();
+        events::clear();
+
+        // the cancel handler is registered along with the first asynchronous function
+        let reg = Reg::new();
+        reg.register(&FunctionSpec::new("slow_double").arg("x", ArgType::Variant, "").asynchronous()).unwrap();
+        reg.register(&FunctionSpec::new("future_result").asynchronous()).unwrap();
+        assert_eq!(excel.registration("slow_double").unwrap().type_text, ">QX");
+        assert!(excel.registration("xladd_CalculationCanceled").is_some());
+
+        assert_eq!(excel.call("slow_double", &[Variant::from_float(21.0)]).as_f64(), Some(42.0));
+        assert_eq!(excel.call("slow_double", &[Variant::from_str("x")]).as_error(), Some(XlError::Value));
+        assert_eq!(excel.call("future_result", &[]).to_string(), "done");
+
+        let generation = GENERATION.load(Ordering::SeqCst);
+        excel.fire_event(xleventCalculationCanceled);
+        assert_eq!(GENERATION.load(Ordering::SeqCst), generation + 1);
+    }
+
+    #[test]
+    fn closing_stops_the_pool() {
+        static RETURNED: AtomicUsize = AtomicUsize::new(0);
+        let _guard = testing::install(|xlfn: i32, _: &mut XLOPER12, _: &[LPXLOPER12]| {
+            if xlfn == xlAsyncReturn as i32 {
+                RETURNED.fetch_add(1, Ordering::SeqCst);
+            }
+            0
+        });
+        reset_executor();
+        let mut handle = Variant::new();
+        handle.as_mut_xloper().xltype = xltypeBigData;
+
+        let started = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+        let (start, finish) = (started.clone(), finished.clone());
+        spawn("slow", handle.view(), move || {
+            start.store(true, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            finish.store(true, Ordering::SeqCst);
+            Variant::new()
+        });
+        while !started.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // closing waits for the running work, and drops its result
+        shut_down();
+        assert!(finished.load(Ordering::SeqCst));
+        assert_eq!(RETURNED.load(Ordering::SeqCst), 0);
+        assert!(POOL.lock().unwrap().is_none());
+
+        // spawning again starts a new pool
+        spawn("again", handle.view(), Variant::new);
+        shut_down();
+        assert_eq!(RETURNED.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn canceled_work_is_dropped() {
+        static RETURNED: AtomicUsize = AtomicUsize::new(0);
+        let _guard = testing::install(|xlfn: i32, _: &mut XLOPER12, _: &[LPXLOPER12]| {
+            if xlfn == xlAsyncReturn as i32 {
+                RETURNED.fetch_add(1, Ordering::SeqCst);
+            }
+            0
+        });
+
+        let queue: Arc<Mutex<Vec<Job>>> = Arc::new(Mutex::new(Vec::new()));
+        let queued = queue.clone();
+        set_executor(move |job| queued.lock().unwrap().push(job));
+
+        let mut handle = Variant::new();
+        handle.as_mut_xloper().xltype = xltypeBigData;
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        spawn("canceled", handle.view(), move || { flag.store(true, Ordering::SeqCst); Variant::new() });
+        spawn("finished", handle.view(), Variant::new);
+
+        // run the second, then cancel before the first has started
+        let first = queue.lock().unwrap().remove(0);
+        let second = queue.lock().unwrap().remove(0);
+        second();
+        assert_eq!(RETURNED.load(Ordering::SeqCst), 1);
+        cancel_all();
+        first();
+        assert!(!ran.load(Ordering::SeqCst));
+        assert_eq!(RETURNED.load(Ordering::SeqCst), 1);
+
+        // a handle dropped unused returns #VALUE!, unless it has been canceled
+        drop(AsyncHandle::new(handle.view()));
+        assert_eq!(RETURNED.load(Ordering::SeqCst), 2);
+        let stale = AsyncHandle::new(handle.view()).unwrap();
+        cancel_all();
+        assert_eq!(stale.complete(Variant::new()), Err(XlRetError::InvAsynchronousContext));
+        assert_eq!(RETURNED.load(Ordering::SeqCst), 2);
+        reset_executor();
+    }
+}
@@ -0,0 +1,479 @@
+//! A cache of Rust objects, so that a UDF can return an object that Excel has no type for,
+//! such as a yield curve, and other UDFs can take it as an argument. The object is stored
+//! in the cache, and what the cell shows is a handle to it, a string such as "Curve:42".
+//! Excel passes the handle through its own calculation logic like any other string, and a
+//! UDF that receives it looks the object up again:
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use xladd::cache;
+//! # use xladd::xlerror::XlError;
+//! struct Curve { rate: f64 }
+//!
+//! fn make_curve(rate: f64) -> String {
+//!     cache::store(Curve { rate })
+//! }
+//!
+//! fn discount(curve: &str, years: f64) -> Result<f64, XlError> {
+//!     let curve: Arc<Curve> = cache::get(curve)?;
+//!     Ok((-curve.rate * years).exp())
+//! }
+//!
+//! let handle = make_curve(0.05);
+//! assert!(handle.starts_with("Curve:"));
+//! assert!(discount(&handle, 1.0).unwrap() < 1.0);
+//! assert_eq!(discount("Curve:0", 1.0), Err(XlError::Value));
+//! ```
+//!
+//! A `#[xl_func]` can also take an `Arc<T>` argument directly, which resolves the handle
+//! and gives #VALUE! if it is not a handle to a T.
+//!
+//! An object stored by a UDF called from a cell belongs to that cell, or the range of an
+//! array formula, which is found with xlfCaller. When the cell recalculates and stores a
+//! new object, the new one replaces the old, which is dropped once nothing else holds it.
+//! The handle then has a version number as well, such as "Curve:42:3", so that it changes
+//! each time and the cells that depend on it recalculate too.
+//!
+//! A cell can hold several objects, because its formula calls several UDFs that store
+//! objects, as in `=Price(MakeCurve(A1), MakeTrade(A2))`, or because one UDF stores more
+//! than one. Each object is told apart by the UDF that stored it, its tag, and how many
+//! objects that call to the UDF stored before it. The UDF is the function named to
+//! protect::udf or protect::value, which `#[xl_func]` functions go through. A formula that
+//! calls the same UDF twice to store objects with the same tag, as in
+//! `=Spread(MakeCurve(A1), MakeCurve(A2))`, cannot be told apart in this way, so the two
+//! calls share an object, and the second replaces the first.
+//!
+//! Objects stored other than from a cell, for example from a command, have no version and
+//! stay in the cache until they are removed. Call `sweep` from time to time to drop the
+//! objects belonging to sheets that no longer exist.
+
+#![allow(non_upper_case_globals)]
+
+use std::any::{self, Any};
+use std::cell::Cell as StdCell;
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{error, fmt};
+use entrypoint::try_excel12;
+use protect;
+use variant::Variant;
+use xlcall::{IDSHEET, XLREF12, xltypeRef, xltypeSRef, xlbitDLLFree, xlbitXLFree,
+    xlfCaller, xlSheetNm, xlSheetId};
+use xlerror::XlError;
+
+// The sheet, first and last rows, and first and last columns of the cell or range that
+// stored an object. The sheet ID is kept as a number, as IDSHEET is a pointer and so
+// cannot be shared between threads.
+type Area = (usize, i32, i32, i32, i32);
+
+// What an object stored from a cell is known by: the cell or range, the UDF that stored
+// it, its tag, and how many objects the same call stored before it
+type Owner = (Area, String, String, u32);
+
+struct Entry {
+    tag: String,
+    value: Arc<dyn Any + Send + Sync>,
+    owner: Option<Owner>,
+    version: u32,
+}
+
+struct Objects {
+    entries: BTreeMap<u64, Entry>,
+    // the entries stored from cells, by their owner
+    cells: BTreeMap<Owner, u64>,
+}
+
+impl Objects {
+    fn remove(&mut self, id: u64) -> Option<Entry> {
+        let entry = self.entries.remove(&id)?;
+        if let Some(ref owner) = entry.owner {
+            self.cells.remove(owner);
+        }
+        Some(entry)
+    }
+}
+
+static OBJECTS: Mutex<Objects> = Mutex::new(Objects { entries: BTreeMap::new(), cells: BTreeMap::new() });
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    // The call that last stored an object on this thread, and how many it has stored
+    static STORES: StdCell<(u64, u32)> = const { StdCell::new((0, 0)) };
+}
+
+/// The reason a handle could not be resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheError {
+    /// The text is not in the form of a handle, "Tag:number"
+    NotAHandle(String),
+    /// The handle is well formed, but there is no such object, perhaps because it has
+    /// been removed
+    NotFound(String),
+    /// The object is not of the type asked for
+    WrongType { handle: String, expected: &'static str },
+    /// The object is of the type asked for, but was stored by an xll built with a different
+    /// layout version of the type. See shared::SharedType.
+    Incompatible { handle: String, expected: u32, found: u32 },
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CacheError::NotAHandle(ref text) => write!(f, "\"{}\" is not a handle", text),
+            CacheError::NotFound(ref handle) => write!(f, "there is no object with handle {}", handle),
+            CacheError::WrongType { ref handle, expected } => write!(f, "{} is not a {}", handle, expected),
+            CacheError::Incompatible { ref handle, expected, found } =>
+                write!(f, "{} has layout version {}, but version {} was expected", handle, found, expected),
+        }
+    }
+}
+
+impl error::Error for CacheError {}
+
+/// A handle that cannot be resolved is shown as #VALUE! in Excel
+impl From<CacheError> for XlError {
+    fn from(_: CacheError) -> XlError {
+        XlError::Value
+    }
+}
+
+/// Stores an object, returning a handle to it that is tagged with the name of its type,
+/// such as "Curve:42". If this is called from a cell, the object replaces the one that the
+/// same UDF stored in its place when the cell last calculated.
+pub fn store<T: Any + Send + Sync>(value: T) -> String {
+    store_as(short_type_name::<T>(), value)
+}
+
+/// Stores an object, returning a handle to it with the given tag, such as "Trade:43". The
+/// tag is only for people to read. It should not contain a colon.
+pub fn store_as<T: Any + Send + Sync>(tag: &str, value: T) -> String {
+    let cell = caller_area().map(|area| owner(area, tag));
+    let value: Arc<dyn Any + Send + Sync> = Arc::new(value);
+    let mut objects = lock();
+    match cell.as_ref().and_then(|cell| objects.cells.get(cell).cloned()) {
+        Some(id) => {
+            let entry = objects.entries.get_mut(&id).expect("every owner has an entry");
+            entry.version += 1;
+            let handle = format!("{}:{}:{}", tag, id, entry.version);
+
+            // drop the previous object outside the lock, in case its drop uses the cache
+            let previous = mem::replace(&mut entry.value, value);
+            drop(objects);
+            drop(previous);
+            handle
+        },
+        None => {
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            objects.entries.insert(id, Entry { tag: tag.to_string(), value, owner: cell.clone(), version: 1 });
+            match cell {
+                Some(cell) => {
+                    objects.cells.insert(cell, id);
+                    format!("{}:{}:1", tag, id)
+                },
+                None => format!("{}:{}", tag, id)
+            }
+        }
+    }
+}
+
+/// Looks up the object with the given handle, which must be of type T
+pub fn get<T: Any + Send + Sync>(handle: &str) -> Result<Arc<T>, CacheError> {
+    let (tag, id, version) = parse(handle)?;
+    let value = {
+        let objects = lock();
+        match objects.entries.get(&id) {
+            Some(entry) if matches(entry, tag, version) => entry.value.clone(),
+            _ => return Err(CacheError::NotFound(handle.to_string()))
+        }
+    };
+    value.downcast::<T>().map_err(|_| CacheError::WrongType {
+        handle: handle.to_string(), expected: short_type_name::<T>() })
+}
+
+/// Removes the object with the given handle, returning whether there was one. Anything
+/// that still holds an Arc to the object keeps it alive until it is dropped.
+pub fn remove(handle: &str) -> bool {
+    let (tag, id, version) = match parse(handle) {
+        Ok(parsed) => parsed,
+        Err(_) => return false
+    };
+    let mut objects = lock();
+    if !objects.entries.get(&id).is_some_and(|entry| matches(entry, tag, version)) {
+        return false
+    }
+    let removed = objects.remove(id);
+    drop(objects);
+    removed.is_some()
+}
+
+/// Removes every object from the cache
+pub fn clear() {
+    let removed = {
+        let mut objects = lock();
+        objects.cells.clear();
+        mem::take(&mut objects.entries)
+    };
+    drop(removed);
+}
+
+/// Drops the objects stored from cells on sheets that no longer exist, because the sheet
+/// has been deleted or its workbook closed, returning how many were dropped. This asks
+/// Excel about each sheet, so it must be called where calls into Excel are allowed, such
+/// as from a command.
+pub fn sweep() -> usize {
+    let mut sheets: Vec<usize> = lock().cells.keys().map(|cell| (cell.0).0).collect();
+    sheets.dedup();
+    let gone: Vec<usize> = sheets.into_iter().filter(|&sheet| !sheet_exists(sheet)).collect();
+
+    let removed: Vec<Entry> = {
+        let mut objects = lock();
+        let ids: Vec<u64> = objects.cells.iter()
+            .filter(|(cell, _)| gone.contains(&(cell.0).0))
+            .map(|(_, &id)| id)
+            .collect();
+        ids.into_iter().filter_map(|id| objects.remove(id)).collect()
+    };
+    removed.len()
+}
+
+/// Returns the number of objects in the cache
+pub fn len() -> usize {
+    lock().entries.len()
+}
+
+/// Returns true if the cache holds no objects
+pub fn is_empty() -> bool {
+    len() == 0
+}
+
+fn lock() -> ::std::sync::MutexGuard<'static, Objects> {
+    OBJECTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Splits a handle into its tag, ID and version, if it has one
+fn parse(handle: &str) -> Result<(&str, u64, Option<u32>), CacheError> {
+    let not_a_handle = || CacheError::NotAHandle(handle.to_string());
+    let mut parts = handle.splitn(3, ':');
+    let tag = parts.next().ok_or_else(not_a_handle)?;
+    let id = parts.next().and_then(|id| id.parse::<u64>().ok()).ok_or_else(not_a_handle)?;
+    let version = match parts.next() {
+        Some(version) => Some(version.parse::<u32>().map_err(|_| not_a_handle())?),
+        None => None
+    };
+    Ok((tag, id, version))
+}
+
+// Whether a handle refers to the entry as it is now. Objects stored from a cell must
+// have the current version, as older versions have been replaced.
+fn matches(entry: &Entry, tag: &str, version: Option<u32>) -> bool {
+    entry.tag == tag && version == entry.owner.as_ref().map(|_| entry.version)
+}
+
+// Works out what an object stored from a cell is known by. Within one call to a UDF,
+// each object stored is numbered in turn. Outside protect::value there is no telling one
+// call from the next, so each object is numbered zero.
+fn owner(area: Area, tag: &str) -> Owner {
+    let (function, index) = match protect::current_call() {
+        Some((call, function)) => {
+            let index = STORES.with(|stores| {
+                let (last, count) = stores.get();
+                let index = if last == call { count } else { 0 };
+                stores.set((call, index + 1));
+                index
+            });
+            (function, index)
+        },
+        None => (String::new(), 0)
+    };
+    (area, function, tag.to_string(), index)
+}
+
+// Finds the cell or range that is calling us, if we are called from a cell. The caller
+// may be a reference with its sheet, or a single reference to the current sheet, whose ID
+// we get from its name.
+fn caller_area() -> Option<Area> {
+    let caller = try_excel12(xlfCaller, &mut []).ok()?;
+    let xloper = caller.as_xloper();
+    let area_of = |sheet: usize, area: XLREF12| (sheet, area.rwFirst, area.rwLast, area.colFirst, area.colLast);
+    match xloper.xltype & !(xlbitDLLFree | xlbitXLFree) {
+        xltypeRef => {
+            let area = first_area(&caller)?;
+            Some(area_of(unsafe { xloper.val.mref.idSheet } as usize, area))
+        },
+        xltypeSRef => {
+            let area = unsafe { xloper.val.sref.ref_ };
+            let name = try_excel12(xlSheetNm, &mut [caller]).ok()?;
+            let sheet = try_excel12(xlSheetId, &mut [name]).ok()?;
+            if sheet.as_xloper().xltype & !(xlbitDLLFree | xlbitXLFree) != xltypeRef {
+                return None
+            }
+            Some(area_of(unsafe { sheet.as_xloper().val.mref.idSheet } as usize, area))
+        },
+        _ => None
+    }
+}
+
+fn first_area(reference: &Variant) -> Option<XLREF12> {
+    let mref = unsafe { reference.as_xloper().val.mref.lpmref };
+    if mref.is_null() || unsafe { (*mref).count } == 0 {
+        None
+    } else {
+        Some(unsafe { (*mref).reftbl[0] })
+    }
+}
+
+// A sheet exists if Excel can tell us its name
+fn sheet_exists(sheet: usize) -> bool {
+    let cell = XLREF12 { rwFirst: 0, rwLast: 0, colFirst: 0, colLast: 0 };
+    try_excel12(xlSheetNm, &mut [Variant::from_refs(sheet as IDSHEET, &[cell])])
+        .is_ok_and(|name| name.as_string().is_some())
+}
+
+// The name of a type without its path or generic arguments, such as "Curve"
+fn short_type_name<T: Any>() -> &'static str {
+    let name = any::type_name::<T>();
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::FakeExcel;
+
+    struct Curve(f64);
+    struct Trade;
+
+    #[test]
+    fn store_and_resolve() {
+        // objects stored with no caller cell are not replaced
+        let _guard = FakeExcel::new("cache.xll").install();
+        let curve = store(Curve(0.05));
+        let trade = store_as("Swap", Trade);
+        assert!(curve.starts_with("Curve:"));
+        assert!(trade.starts_with("Swap:"));
+
+        assert_eq!(get::<Curve>(&curve).unwrap().0, 0.05);
+        assert!(get::<Trade>(&trade).is_ok());
+        assert_eq!(get::<Curve>(&trade).err(), Some(CacheError::WrongType { handle: trade.clone(), expected: "Curve" }));
+        assert_eq!(get::<Curve>("curve").err(), Some(CacheError::NotAHandle("curve".to_string())));
+
+        // the tag must match as well as the number
+        let renamed = curve.replace("Curve", "Other");
+        assert_eq!(get::<Curve>(&renamed).err(), Some(CacheError::NotFound(renamed.clone())));
+        assert!(!remove(&renamed));
+
+        let held = get::<Curve>(&curve).unwrap();
+        assert!(remove(&curve));
+        assert!(get::<Curve>(&curve).is_err());
+        assert_eq!(held.0, 0.05);
+        assert_eq!(XlError::from(CacheError::NotFound(curve)), XlError::Value);
+        assert!(remove(&trade));
+    }
+
+    struct Counted(Arc<AtomicU64>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn objects_belong_to_cells() {
+        let excel = FakeExcel::new("cache.xll");
+        let _guard = excel.install();
+        let dropped = Arc::new(AtomicU64::new(0));
+        let sheet = excel.add_sheet("[Book2]Curves");
+
+        excel.set_caller(Some((sheet, 2, 3)));
+        let first = store(Counted(dropped.clone()));
+        let second = store(Counted(dropped.clone()));
+        let (_, id, version) = parse(&second).unwrap();
+        assert_eq!(first, format!("Counted:{}:1", id));
+        assert_eq!(version, Some(2));
+        assert_eq!(dropped.load(Ordering::SeqCst), 1);
+        assert!(get::<Counted>(&first).is_err());
+        assert!(get::<Counted>(&second).is_ok());
+
+        excel.set_caller(Some((sheet, 2, 4)));
+        let neighbour = store(Counted(dropped.clone()));
+        assert_ne!(parse(&neighbour).unwrap().1, id);
+        excel.set_caller(None);
+        let loose = store(Counted(dropped.clone()));
+        assert_eq!(parse(&loose).unwrap().2, None);
+
+        excel.remove_sheet(sheet);
+        assert_eq!(sweep(), 2);
+        assert_eq!(dropped.load(Ordering::SeqCst), 3);
+        assert!(get::<Counted>(&second).is_err());
+        assert!(remove(&loose));
+    }
+
+    // Stores two curves from one call, as a UDF that builds a pair of curves would
+    fn make_pair(first: f64, second: f64) -> Vec<String> {
+        let handles = protect::value("MakePair", || {
+            Variant::from_str(&format!("{} {}", store(Curve(first)), store(Curve(second))))
+        });
+        handles.to_string().split(' ').map(|h| h.to_string()).collect()
+    }
+
+    #[test]
+    fn several_objects_in_one_cell() {
+        let excel = FakeExcel::new("cache.xll");
+        let _guard = excel.install();
+        let sheet = excel.add_sheet("[Book3]Pairs");
+        excel.set_caller(Some((sheet, 1, 1)));
+
+        let pair = make_pair(1.0, 2.0);
+        assert_ne!(parse(&pair[0]).unwrap().1, parse(&pair[1]).unwrap().1);
+        assert_eq!(get::<Curve>(&pair[0]).unwrap().0, 1.0);
+        assert_eq!(get::<Curve>(&pair[1]).unwrap().0, 2.0);
+
+        // recalculating replaces each object with its counterpart
+        let again = make_pair(3.0, 4.0);
+        for (old, new) in pair.iter().zip(again.iter()) {
+            assert_eq!(parse(old).unwrap().1, parse(new).unwrap().1);
+            assert_eq!(parse(new).unwrap().2, Some(2));
+            assert!(get::<Curve>(old).is_err());
+        }
+        assert_eq!(get::<Curve>(&again[0]).unwrap().0, 3.0);
+        assert_eq!(get::<Curve>(&again[1]).unwrap().0, 4.0);
+
+        // another UDF in the same formula has objects of its own
+        let trade = protect::value("MakeTrade", || Variant::from_str(&store(Curve(5.0)))).to_string();
+        assert_eq!(parse(&trade).unwrap().2, Some(1));
+        assert_eq!(get::<Curve>(&again[0]).unwrap().0, 3.0);
+
+        excel.remove_sheet(sheet);
+        assert_eq!(sweep(), 3);
+    }
+
+    #[test]
+    fn array_callers() {
+        let excel = FakeExcel::new("cache.xll");
+        let _guard = excel.install();
+        let sheet = excel.add_sheet("[Book4]Arrays");
+
+        // an array formula over A1:A3 is not the same owner as A1 alone
+        excel.set_caller_area(sheet, (0, 2), (0, 0));
+        let array = store(Curve(1.0));
+        excel.set_caller(Some((sheet, 0, 0)));
+        let single = store(Curve(2.0));
+        assert_ne!(parse(&array).unwrap().1, parse(&single).unwrap().1);
+
+        // the same range reported as a single reference is found through its sheet name
+        excel.set_caller_area(sheet, (0, 2), (0, 0));
+        excel.set_caller_sref(true);
+        let again = store(Curve(3.0));
+        assert_eq!(parse(&again).unwrap().1, parse(&array).unwrap().1);
+        assert_eq!(parse(&again).unwrap().2, Some(2));
+        assert_eq!(get::<Curve>(&single).unwrap().0, 2.0);
+
+        excel.set_caller_sref(false);
+        excel.remove_sheet(sheet);
+        assert_eq!(sweep(), 2);
+    }
+}
@@ -0,0 +1,79 @@
+//! Support for commands, which are functions that Excel runs in response to a menu, a
+//! button or a shortcut key, rather than from a cell. Excel can only call functions that
+//! the dll exports, but commands are usually Rust closures, so xladd exports a fixed pool
+//! of trampolines, `xladd_command_0` to `xladd_command_31`. Reg::add_command gives each
+//! command one of these to be registered under, and the trampoline runs the command.
+//!
+//! Commands are registered with Reg::add_command, and the slots are freed again when they
+//! are unregistered, as registrator::unregister_all does.
+
+use std::sync::{Arc, RwLock};
+use protect;
+
+/// The number of commands that can be registered at once
+pub const MAX_COMMANDS: usize = 32;
+
+type Command = Arc<dyn Fn() + Send + Sync>;
+
+static COMMANDS: RwLock<[Option<(String, Command)>; MAX_COMMANDS]> = RwLock::new([const { None }; MAX_COMMANDS]);
+
+// Stores the command in a free slot, or the slot it already has if a command of the same
+// name is registered again, returning the name of the trampoline for the slot
+pub(crate) fn allocate(name: &str, command: Command) -> Option<&'static str> {
+    let mut commands = COMMANDS.write().unwrap_or_else(|e| e.into_inner());
+    let slot = commands.iter().position(|c| c.as_ref().is_some_and(|c| c.0 == name))
+        .or_else(|| commands.iter().position(|c| c.is_none()))?;
+    commands[slot] = Some((name.to_string(), command));
+    Some(TRAMPOLINES[slot].0)
+}
+
+/// Frees the slot used by a command, if it has one
+pub(crate) fn free(name: &str) {
+    let mut commands = COMMANDS.write().unwrap_or_else(|e| e.into_inner());
+    for command in commands.iter_mut() {
+        if command.as_ref().is_some_and(|c| c.0 == name) {
+            *command = None;
+        }
+    }
+}
+
+/// Looks up the trampoline exported under the given name, so that a test harness such as
+/// FakeExcel can run commands without the dll being loaded
+pub fn trampoline(procedure: &str) -> Option<extern "system" fn() -> i32> {
+    TRAMPOLINES.iter().find(|t| t.0 == procedure).map(|t| t.1)
+}
+
+// Runs the command in a slot, returning 1 if it completes, or 0 if it panics or the slot
+// is empty. The lock is not held while the command runs, as it may register commands.
+fn run(slot: usize) -> i32 {
+    let command = COMMANDS.read().unwrap_or_else(|e| e.into_inner())[slot].clone();
+    match command {
+        Some((name, command)) => protect::auto(&name, || command()),
+        None => 0
+    }
+}
+
+macro_rules! trampolines {
+    ($($slot:expr => $export:ident),*) => {
+        $(
+            #[no_mangle]
+            #[doc(hidden)]
+            pub extern "system" fn $export() -> i32 {
+                run($slot)
+            }
+        )*
+
+        static TRAMPOLINES: [(&str, extern "system" fn() -> i32); MAX_COMMANDS] =
+            [$((stringify!($export), $export)),*];
+    }
+}
+
+trampolines!(
+    0 => xladd_command_0, 1 => xladd_command_1, 2 => xladd_command_2, 3 => xladd_command_3,
+    4 => xladd_command_4, 5 => xladd_command_5, 6 => xladd_command_6, 7 => xladd_command_7,
+    8 => xladd_command_8, 9 => xladd_command_9, 10 => xladd_command_10, 11 => xladd_command_11,
+    12 => xladd_command_12, 13 => xladd_command_13, 14 => xladd_command_14, 15 => xladd_command_15,
+    16 => xladd_command_16, 17 => xladd_command_17, 18 => xladd_command_18, 19 => xladd_command_19,
+    20 => xladd_command_20, 21 => xladd_command_21, 22 => xladd_command_22, 23 => xladd_command_23,
+    24 => xladd_command_24, 25 => xladd_command_25, 26 => xladd_command_26, 27 => xladd_command_27,
+    28 => xladd_command_28, 29 => xladd_command_29, 30 => xladd_command_30, 31 => xladd_command_31);
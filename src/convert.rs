@@ -0,0 +1,429 @@
+//! Conversions between Variant and ordinary Rust types, using the standard From and
+//! TryFrom traits. Converting into a Variant always succeeds. Converting out of a Variant
+//! follows Excel's own coercion rules where Excel has them, for example a numeric string
+//! can be read as a number and an empty cell reads as zero. Where the conversion is not
+//! possible, a ConversionError says what was expected and what was found.
+
+#![allow(non_upper_case_globals)]
+
+use std::convert::TryFrom;
+use std::{error, fmt};
+use variant::Variant;
+use variant_ref::VariantRef;
+use xlerror::XlError;
+use xlcall::{xltypeNum, xltypeStr, xltypeBool, xltypeRef, xltypeErr, xltypeFlow, xltypeMulti,
+    xltypeMissing, xltypeNil, xltypeSRef, xltypeInt, xlbitDLLFree, xlbitXLFree};
+
+/// The error returned when a Variant cannot be converted to the Rust type asked for
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionError {
+    expected: &'static str,
+    found: String,
+}
+
+impl ConversionError {
+    /// Creates an error, saying what was expected, such as "a number", and describing
+    /// the Variant that was found instead.
+    pub fn new(expected: &'static str, found: &Variant) -> ConversionError {
+        ConversionError::of(expected, found.view())
+    }
+
+    /// Creates an error, as `new` does, from a borrowed view of what was found
+    pub fn of(expected: &'static str, found: VariantRef) -> ConversionError {
+        ConversionError { expected, found: describe(found) }
+    }
+
+    /// What the conversion expected to find, such as "a number"
+    pub fn expected(&self) -> &str {
+        self.expected
+    }
+
+    /// A description of what the conversion found instead, such as "the string \"abc\""
+    pub fn found(&self) -> &str {
+        &self.found
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected {}, found {}", self.expected, self.found)
+    }
+}
+
+impl error::Error for ConversionError {}
+
+/// Types that can be stored in a single cell of an array. This is what allows a Vec of
+/// them to convert to a one-dimensional array, and a Vec of Vecs to a two-dimensional one.
+pub trait CellValue {}
+
+impl CellValue for f64 {}
+impl CellValue for i32 {}
+impl CellValue for bool {}
+impl CellValue for String {}
+impl CellValue for &str {}
+impl CellValue for Variant {}
+
+impl From<f64> for Variant {
+    fn from(value: f64) -> Variant {
+        Variant::from_float(value)
+    }
+}
+
+impl From<i32> for Variant {
+    fn from(value: i32) -> Variant {
+        Variant::from_int(value)
+    }
+}
+
+impl From<bool> for Variant {
+    fn from(value: bool) -> Variant {
+        Variant::from_bool(value)
+    }
+}
+
+impl From<&str> for Variant {
+    fn from(value: &str) -> Variant {
+        Variant::from_str(value)
+    }
+}
+
+impl From<String> for Variant {
+    fn from(value: String) -> Variant {
+        Variant::from_str(&value)
+    }
+}
+
+/// An error becomes the matching error cell
+impl From<XlError> for Variant {
+    fn from(value: XlError) -> Variant {
+        Variant::from_err(value)
+    }
+}
+
+/// A vector becomes a single column, with one row per element. Excel cannot show an empty
+/// array, so an empty vector becomes #N/A.
+impl<T: CellValue + Into<Variant>> From<Vec<T>> for Variant {
+    fn from(values: Vec<T>) -> Variant {
+        let rows = values.len();
+        if rows == 0 {
+            return Variant::from_err(XlError::NA)
+        }
+        let data: Vec<Variant> = values.into_iter().map(|v| v.into()).collect();
+        Variant::from_vec(1, rows, data)
+    }
+}
+
+/// A vector of vectors becomes a two-dimensional array, with each inner vector as a row.
+/// If the rows are of different lengths, the short ones are padded with #N/A. With no rows,
+/// or only empty ones, the result is #N/A, as for an empty vector.
+impl<T: CellValue + Into<Variant>> From<Vec<Vec<T>>> for Variant {
+    fn from(values: Vec<Vec<T>>) -> Variant {
+        let rows = values.len();
+        let columns = values.iter().map(|row| row.len()).max().unwrap_or(0);
+        if columns == 0 {
+            return Variant::from_err(XlError::NA)
+        }
+        let mut data = Vec::with_capacity(rows * columns);
+        for row in values {
+            let len = row.len();
+            data.extend(row.into_iter().map(|v| v.into()));
+            for _ in len..columns {
+                data.push(Variant::from_err(XlError::NA));
+            }
+        }
+        Variant::from_vec(columns, rows, data)
+    }
+}
+
+/// Reads a number. As in Excel, integers and booleans are read as numbers (TRUE is one),
+/// an empty cell is zero, and a string is parsed if it looks like a number, including
+/// percentages such as "5%".
+impl<'a> TryFrom<VariantRef<'a>> for f64 {
+    type Error = ConversionError;
+
+    fn try_from(value: VariantRef<'a>) -> Result<f64, ConversionError> {
+        let found = || ConversionError::of("a number", value);
+        match xltype(value) {
+            xltypeNum => value.as_f64().ok_or_else(found),
+            xltypeInt => value.as_i32().map(f64::from).ok_or_else(found),
+            xltypeBool => value.as_bool().map(|b| if b { 1.0 } else { 0.0 }).ok_or_else(found),
+            xltypeNil => Ok(0.0),
+            xltypeStr => value.as_str().and_then(|s| parse_number(&s)).ok_or_else(found),
+            _ => Err(found())
+        }
+    }
+}
+
+/// Reads an integer. Numbers are truncated towards zero, as Excel does for arguments that
+/// must be whole numbers. Anything that can be read as a number can be read as an integer,
+/// so long as it is in range.
+impl<'a> TryFrom<VariantRef<'a>> for i32 {
+    type Error = ConversionError;
+
+    fn try_from(value: VariantRef<'a>) -> Result<i32, ConversionError> {
+        if let Some(i) = value.as_i32() {
+            return Ok(i)
+        }
+        let num = f64::try_from(value).map_err(|_| ConversionError::of("an integer", value))?;
+        let truncated = num.trunc();
+        if truncated >= f64::from(i32::MIN) && truncated <= f64::from(i32::MAX) {
+            Ok(truncated as i32)
+        } else {
+            Err(ConversionError::of("an integer", value))
+        }
+    }
+}
+
+/// Reads a boolean. As in Excel, any non-zero number is TRUE, an empty cell is FALSE,
+/// and the strings "TRUE" and "FALSE" are accepted in any case.
+impl<'a> TryFrom<VariantRef<'a>> for bool {
+    type Error = ConversionError;
+
+    fn try_from(value: VariantRef<'a>) -> Result<bool, ConversionError> {
+        let found = || ConversionError::of("a boolean", value);
+        match xltype(value) {
+            xltypeBool => value.as_bool().ok_or_else(found),
+            xltypeNum => value.as_f64().map(|n| n != 0.0).ok_or_else(found),
+            xltypeInt => value.as_i32().map(|i| i != 0).ok_or_else(found),
+            xltypeNil => Ok(false),
+            xltypeStr => match value.as_str() {
+                Some(ref s) if s.eq_ignore_ascii_case("TRUE") => Ok(true),
+                Some(ref s) if s.eq_ignore_ascii_case("FALSE") => Ok(false),
+                _ => Err(found())
+            },
+            _ => Err(found())
+        }
+    }
+}
+
+/// Reads a string. Numbers and booleans are converted to text as Excel displays them, and
+/// an empty cell is an empty string.
+impl<'a> TryFrom<VariantRef<'a>> for String {
+    type Error = ConversionError;
+
+    fn try_from(value: VariantRef<'a>) -> Result<String, ConversionError> {
+        match xltype(value) {
+            xltypeStr | xltypeNum | xltypeInt | xltypeBool => Ok(value.to_string()),
+            xltypeNil => Ok(String::new()),
+            _ => Err(ConversionError::of("a string", value))
+        }
+    }
+}
+
+// Conversions from &Variant behave exactly as those from a VariantRef of it
+macro_rules! convert_by_view {
+    ($($t:ty)*) => {
+        $(
+            impl TryFrom<&Variant> for $t {
+                type Error = ConversionError;
+
+                fn try_from(value: &Variant) -> Result<$t, ConversionError> {
+                    <$t>::try_from(value.view())
+                }
+            }
+        )*
+    }
+}
+
+convert_by_view!(f64 i32 bool String);
+
+/// Reads every cell of an array, row by row. A scalar is treated as a single-cell array.
+impl<'a, T> TryFrom<&'a Variant> for Vec<T>
+    where T: CellValue + TryFrom<&'a Variant, Error = ConversionError> {
+    type Error = ConversionError;
+
+    fn try_from(value: &'a Variant) -> Result<Vec<T>, ConversionError> {
+        if xltype(value.view()) != xltypeMulti {
+            return T::try_from(value).map(|v| vec![v])
+        }
+        let (columns, rows) = value.dim();
+        let mut result = Vec::with_capacity(columns * rows);
+        for row in 0..rows {
+            for column in 0..columns {
+                result.push(T::try_from(cell(value, column, row))?);
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Reads an array as a vector of rows. A scalar is treated as a single-cell array.
+impl<'a, T> TryFrom<&'a Variant> for Vec<Vec<T>>
+    where T: CellValue + TryFrom<&'a Variant, Error = ConversionError> {
+    type Error = ConversionError;
+
+    fn try_from(value: &'a Variant) -> Result<Vec<Vec<T>>, ConversionError> {
+        if xltype(value.view()) != xltypeMulti {
+            return T::try_from(value).map(|v| vec![vec![v]])
+        }
+        let (columns, rows) = value.dim();
+        let mut result = Vec::with_capacity(rows);
+        for row in 0..rows {
+            let mut cells = Vec::with_capacity(columns);
+            for column in 0..columns {
+                cells.push(T::try_from(cell(value, column, row))?);
+            }
+            result.push(cells);
+        }
+        Ok(result)
+    }
+}
+
+/// Reads every cell of an array, row by row, as the conversion from &Variant does
+impl<'a, T> TryFrom<VariantRef<'a>> for Vec<T>
+    where T: CellValue + TryFrom<VariantRef<'a>, Error = ConversionError> {
+    type Error = ConversionError;
+
+    fn try_from(value: VariantRef<'a>) -> Result<Vec<T>, ConversionError> {
+        if xltype(value) != xltypeMulti {
+            return T::try_from(value).map(|v| vec![v])
+        }
+        value.array().iter().map(T::try_from).collect()
+    }
+}
+
+/// Reads an array as a vector of rows, as the conversion from &Variant does
+impl<'a, T> TryFrom<VariantRef<'a>> for Vec<Vec<T>>
+    where T: CellValue + TryFrom<VariantRef<'a>, Error = ConversionError> {
+    type Error = ConversionError;
+
+    fn try_from(value: VariantRef<'a>) -> Result<Vec<Vec<T>>, ConversionError> {
+        if xltype(value) != xltypeMulti {
+            return T::try_from(value).map(|v| vec![vec![v]])
+        }
+        value.array().rows_iter().map(|row| row.map(T::try_from).collect()).collect()
+    }
+}
+
+// Borrows an element of an array, which must be in range. Unlike Variant::at, this does not
+// clone the element, which means the result can live as long as the array.
+fn cell(array: &Variant, column: usize, row: usize) -> &Variant {
+    let xloper = array.as_xloper();
+    unsafe {
+        let columns = xloper.val.array.columns as usize;
+        &*(xloper.val.array.lparray.add(row * columns + column) as *const Variant)
+    }
+}
+
+fn xltype(value: VariantRef) -> u32 {
+    value.as_xloper().xltype & !(xlbitDLLFree | xlbitXLFree)
+}
+
+// Describes a Variant for an error message
+fn describe(value: VariantRef) -> String {
+    match xltype(value) {
+        xltypeNum | xltypeInt => format!("the number {}", value),
+        xltypeStr => format!("the string \"{}\"", value),
+        xltypeBool => format!("the boolean {}", value),
+        xltypeErr => format!("the error {}", value),
+        xltypeMulti => { let (c, r) = value.dim(); format!("an array of {} columns by {} rows", c, r) },
+        xltypeMissing => "a missing value".to_string(),
+        xltypeNil => "an empty value".to_string(),
+        xltypeRef | xltypeSRef => "a reference".to_string(),
+        xltypeFlow => "a flow control value".to_string(),
+        _ => "an unrecognised value".to_string()
+    }
+}
+
+// Parses a string as Excel would when coercing it to a number. We accept optional
+// surrounding whitespace, thousands separators and a trailing percent sign.
+fn parse_number(text: &str) -> Option<f64> {
+    let trimmed = text.trim();
+    let (digits, scale) = match trimmed.strip_suffix('%') {
+        Some(rest) => (rest.trim_end(), 0.01),
+        None => (trimmed, 1.0)
+    };
+    let digits = strip_thousands(digits)?;
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c)) {
+        return None
+    }
+    digits.parse::<f64>().ok().map(|n| n * scale)
+}
+
+// Removes the thousands separators from a number. Like Excel, we only accept them between
+// groups of three digits in the integer part, so "1,234.5" is a number but "1,2" is not.
+fn strip_thousands(text: &str) -> Option<String> {
+    let unsigned = text.trim_start_matches(['+', '-']);
+    let sign = &text[..text.len() - unsigned.len()];
+    let end = unsigned.find(['.', 'e', 'E']).unwrap_or(unsigned.len());
+    let (integer, rest) = unsigned.split_at(end);
+    if rest.contains(',') {
+        return None
+    }
+    if integer.contains(',') {
+        let mut groups = integer.split(',');
+        let first = groups.next().unwrap_or("");
+        if first.is_empty() || first.len() > 3 || groups.any(|g| g.len() != 3) {
+            return None
+        }
+    }
+    Some(format!("{}{}{}", sign, integer.replace(',', ""), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn into_variant() {
+        assert_eq!(Variant::from(2.5).as_f64(), Some(2.5));
+        assert_eq!(Variant::from(3).as_i32(), Some(3));
+        assert_eq!(Variant::from(true).as_bool(), Some(true));
+        assert_eq!(Variant::from("abc").as_string(), Some("abc".to_string()));
+        assert_eq!(Variant::from("abc".to_string()).as_string(), Some("abc".to_string()));
+
+        let column = Variant::from(vec![1.0, 2.0, 3.0]);
+        assert_eq!(column.dim(), (1, 3));
+        assert_eq!(column.at(0, 2).as_f64(), Some(3.0));
+
+        let table = Variant::from(vec![vec!["a", "b"], vec!["c"]]);
+        assert_eq!(table.dim(), (2, 2));
+        assert_eq!(table.at(1, 0).to_string(), "b");
+        assert_eq!(table.at(1, 1).to_string(), "#N/A");
+
+        assert_eq!(Variant::from(Vec::<f64>::new()).as_error(), Some(XlError::NA));
+        assert_eq!(Variant::from(Vec::<Vec<f64>>::new()).as_error(), Some(XlError::NA));
+        assert_eq!(Variant::from(vec![Vec::<f64>::new()]).as_error(), Some(XlError::NA));
+    }
+
+    #[test]
+    fn from_variant() {
+        assert_eq!(f64::try_from(&Variant::from_float(1.5)), Ok(1.5));
+        assert_eq!(f64::try_from(&Variant::from_str(" 1,250.5 ")), Ok(1250.5));
+        assert_eq!(f64::try_from(&Variant::from_str("5%")), Ok(0.05));
+        assert_eq!(f64::try_from(&Variant::from_bool(true)), Ok(1.0));
+        assert_eq!(f64::try_from(&Variant::new()), Ok(0.0));
+        assert_eq!(i32::try_from(&Variant::from_float(-2.7)), Ok(-2));
+        assert_eq!(bool::try_from(&Variant::from_str("false")), Ok(false));
+        assert_eq!(bool::try_from(&Variant::from_float(3.0)), Ok(true));
+        assert_eq!(String::try_from(&Variant::from_float(2.5)), Ok("2.5".to_string()));
+        assert_eq!(String::try_from(&Variant::from_bool(false)), Ok("FALSE".to_string()));
+
+        let err = f64::try_from(&Variant::from_str("abc")).unwrap_err();
+        assert_eq!(err.to_string(), "expected a number, found the string \"abc\"");
+        assert!(f64::try_from(&Variant::from_str("inf")).is_err());
+        assert_eq!(f64::try_from(&Variant::from_str("-1,234,567")), Ok(-1234567.0));
+        for bad in &["1,2,3", ",,5", ",123", "1234,567", "1,23", "1,234.5,6", "1.5e1,0", "1,,234"] {
+            assert!(f64::try_from(&Variant::from_str(bad)).is_err(), "{} should not be a number", bad);
+        }
+        assert!(i32::try_from(&Variant::from_float(1e10)).is_err());
+        assert_eq!(bool::try_from(&Variant::missing()).unwrap_err().found(), "a missing value");
+
+        let table = Variant::from(vec![vec![1.0, 2.0], vec![3.0, 4.0]]);
+        assert_eq!(Vec::<f64>::try_from(&table), Ok(vec![1.0, 2.0, 3.0, 4.0]));
+        assert_eq!(Vec::<Vec<i32>>::try_from(&table), Ok(vec![vec![1, 2], vec![3, 4]]));
+        assert_eq!(Vec::<f64>::try_from(&Variant::from_float(7.0)), Ok(vec![7.0]));
+        let mixed = Variant::from(vec![Variant::from(1.0), Variant::from("x")]);
+        assert_eq!(Vec::<f64>::try_from(&mixed).unwrap_err().expected(), "a number");
+    }
+
+    #[test]
+    fn from_view() {
+        let table = Variant::from(vec![vec!["1", "2"], vec!["3", "x"]]);
+        let view = table.view();
+        assert_eq!(i32::try_from(view.at(1, 0).unwrap()), Ok(2));
+        assert_eq!(Vec::<Vec<String>>::try_from(view).unwrap()[1], vec!["3", "x"]);
+        assert_eq!(Vec::<f64>::try_from(view).unwrap_err().found(), "the string \"x\"");
+        assert_eq!(Vec::<f64>::try_from(Variant::from_float(7.0).view()), Ok(vec![7.0]));
+    }
+}
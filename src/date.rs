@@ -0,0 +1,471 @@
+//! Excel dates and times. Excel has no date type: a date is stored as a number, the
+//! serial, counting days from an epoch, with the time of day as the fractional part. There
+//! are two epochs. In the 1900 date system, the default on Windows, 1900-01-01 is day 1,
+//! and for compatibility with Lotus 1-2-3 there is a fictitious 1900-02-29 as day 60. In
+//! the 1904 date system, used by old Mac workbooks, 1904-01-01 is day 0. A workbook can use
+//! either, so conversions take a DateSystem, which `caller_date_system` can find out.
+//!
+//! Conversions are provided for the plain Date, TimeOfDay and DateTime types here. With the
+//! `chrono` feature, they are also provided for NaiveDate, NaiveTime and NaiveDateTime, and
+//! with the `time` feature for Date, Time and PrimitiveDateTime from the time crate.
+
+use entrypoint::{try_excel12, try_excel12_1, XlRetError};
+use variant::Variant;
+use xlcall::{xlfCaller, xlfGetDocument, xlSheetNm};
+use xlerror::XlError;
+
+const MS_PER_DAY: f64 = 86_400_000.0;
+
+// The last day that Excel can show, 9999-12-31, in each date system
+const MAX_SERIAL_1900: i64 = 2_958_465;
+const MAX_SERIAL_1904: i64 = 2_957_003;
+
+// The GET.DOCUMENT info type that returns TRUE if the 1904 date system is in use
+const GET_DOCUMENT_1904: i32 = 20;
+
+/// Which of Excel's two date systems a workbook uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DateSystem {
+    /// Day 1 is 1900-01-01, and day 60 is the fictitious 1900-02-29. The default.
+    #[default]
+    Date1900,
+    /// Day 0 is 1904-01-01
+    Date1904,
+}
+
+/// A calendar date
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+/// A time of day, to the millisecond, which is as precise as Excel shows times
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct TimeOfDay {
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub millisecond: u32,
+}
+
+/// A date and a time of day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub date: Date,
+    pub time: TimeOfDay,
+}
+
+/// Types that can be converted to and from Excel serial numbers
+pub trait ExcelDate: Sized {
+    /// Converts to a serial number. Returns None if the value is before the start of the
+    /// date system or after 9999-12-31, as Excel cannot show such dates.
+    fn to_serial(&self, system: DateSystem) -> Option<f64>;
+
+    /// Converts from a serial number. Returns None if the serial is out of range, or
+    /// cannot be represented by this type.
+    fn from_serial(serial: f64, system: DateSystem) -> Option<Self>;
+}
+
+impl Date {
+    /// Constructs a date, returning None if it does not exist
+    pub fn new(year: i32, month: u32, day: u32) -> Option<Date> {
+        if (1..=12).contains(&month) && day >= 1 && day <= days_in_month(year, month) {
+            Some(Date { year, month, day })
+        } else {
+            None
+        }
+    }
+}
+
+impl TimeOfDay {
+    /// Constructs a time of day, returning None if any field is out of range
+    pub fn new(hour: u32, minute: u32, second: u32, millisecond: u32) -> Option<TimeOfDay> {
+        if hour < 24 && minute < 60 && second < 60 && millisecond < 1000 {
+            Some(TimeOfDay { hour, minute, second, millisecond })
+        } else {
+            None
+        }
+    }
+
+    fn to_ms(self) -> i64 {
+        (((self.hour * 60 + self.minute) * 60 + self.second) * 1000 + self.millisecond) as i64
+    }
+
+    fn from_ms(ms: i64) -> TimeOfDay {
+        let ms = ms as u32;
+        TimeOfDay {
+            hour: ms / 3_600_000,
+            minute: ms / 60_000 % 60,
+            second: ms / 1000 % 60,
+            millisecond: ms % 1000 }
+    }
+}
+
+impl ExcelDate for Date {
+    fn to_serial(&self, system: DateSystem) -> Option<f64> {
+        day_serial(*self, system).map(|serial| serial as f64)
+    }
+
+    fn from_serial(serial: f64, system: DateSystem) -> Option<Date> {
+        split_serial(serial).and_then(|(day, _)| serial_day(day, system))
+    }
+}
+
+/// A time of day is the fractional part of a serial. Any whole number of days is ignored,
+/// so the time of day of a date and time can be read directly.
+impl ExcelDate for TimeOfDay {
+    fn to_serial(&self, _system: DateSystem) -> Option<f64> {
+        Some(self.to_ms() as f64 / MS_PER_DAY)
+    }
+
+    fn from_serial(serial: f64, _system: DateSystem) -> Option<TimeOfDay> {
+        split_serial(serial).map(|(_, ms)| TimeOfDay::from_ms(ms))
+    }
+}
+
+impl ExcelDate for DateTime {
+    fn to_serial(&self, system: DateSystem) -> Option<f64> {
+        let day = day_serial(self.date, system)?;
+        Some(day as f64 + self.time.to_ms() as f64 / MS_PER_DAY)
+    }
+
+    fn from_serial(serial: f64, system: DateSystem) -> Option<DateTime> {
+        let (day, ms) = split_serial(serial)?;
+        Some(DateTime { date: serial_day(day, system)?, time: TimeOfDay::from_ms(ms) })
+    }
+}
+
+impl Variant {
+    /// Construct a variant containing a date, time of day or date and time, as an Excel
+    /// serial number in the given date system. If the value cannot be shown in Excel, for
+    /// example because it is before 1900, a #NUM! error is stored instead, which is what
+    /// Excel's DATE function does.
+    pub fn from_date<D: ExcelDate>(value: D, system: DateSystem) -> Variant {
+        match value.to_serial(system) {
+            Some(serial) => Variant::from_float(serial),
+            None => Variant::from_err(XlError::Num)
+        }
+    }
+
+    /// Converts this variant to a date, time of day or date and time, reading it as an
+    /// Excel serial number in the given date system. Returns None if we do not contain a
+    /// number, or it is not a valid serial.
+    pub fn as_date<D: ExcelDate>(&self, system: DateSystem) -> Option<D> {
+        self.as_f64()
+            .or_else(|| self.as_i32().map(f64::from))
+            .and_then(|serial| D::from_serial(serial, system))
+    }
+}
+
+/// Asks Excel which date system is used by the workbook containing the calling cell, or
+/// the active workbook if the caller is not a cell. This uses GET.DOCUMENT, which is only
+/// available to functions registered as macro sheet equivalents (with a `#` in the type
+/// string) and to commands.
+pub fn caller_date_system() -> Result<DateSystem, XlRetError> {
+    let caller = try_excel12(xlfCaller, &mut [])?;
+    let mut args = vec![Variant::from_int(GET_DOCUMENT_1904)];
+    if caller.is_ref() {
+        args.push(try_excel12_1(xlSheetNm, caller)?);
+    }
+    let uses_1904 = try_excel12(xlfGetDocument, &mut args)?;
+    if uses_1904.as_bool() == Some(true) {
+        Ok(DateSystem::Date1904)
+    } else {
+        Ok(DateSystem::Date1900)
+    }
+}
+
+// Rounds a serial to the nearest millisecond and splits it into whole days and
+// milliseconds into the day. Rounding first means that a time that is a hair before
+// midnight, because of floating point error, becomes midnight of the next day.
+fn split_serial(serial: f64) -> Option<(i64, i64)> {
+    if !serial.is_finite() || serial < 0.0 {
+        return None
+    }
+    let ms = (serial * MS_PER_DAY).round() as i64;
+    Some((ms / MS_PER_DAY as i64, ms % MS_PER_DAY as i64))
+}
+
+// Converts a date to a whole day serial
+fn day_serial(date: Date, system: DateSystem) -> Option<i64> {
+    let serial = match system {
+        DateSystem::Date1900 => {
+            if (date.year, date.month, date.day) == (1900, 2, 29) {
+                return Some(60)
+            }
+            let serial = days_from_civil(date.year, date.month, date.day) - days_from_civil(1899, 12, 31);
+            if serial >= 60 { serial + 1 } else { serial }
+        },
+        DateSystem::Date1904 => days_from_civil(date.year, date.month, date.day) - days_from_civil(1904, 1, 1)
+    };
+    let valid = Date::new(date.year, date.month, date.day).is_some();
+    if valid && serial >= min_serial(system) && serial <= max_serial(system) {
+        Some(serial)
+    } else {
+        None
+    }
+}
+
+// Converts a whole day serial to a date. Note that day 60 in the 1900 date system is the
+// fictitious 1900-02-29, which Date can represent even though it never existed.
+fn serial_day(serial: i64, system: DateSystem) -> Option<Date> {
+    if serial < min_serial(system) || serial > max_serial(system) {
+        return None
+    }
+    let (year, month, day) = match system {
+        DateSystem::Date1900 if serial == 60 => return Some(Date { year: 1900, month: 2, day: 29 }),
+        DateSystem::Date1900 if serial < 60 => civil_from_days(days_from_civil(1899, 12, 31) + serial),
+        DateSystem::Date1900 => civil_from_days(days_from_civil(1899, 12, 31) + serial - 1),
+        DateSystem::Date1904 => civil_from_days(days_from_civil(1904, 1, 1) + serial)
+    };
+    Some(Date { year, month, day })
+}
+
+fn min_serial(system: DateSystem) -> i64 {
+    match system {
+        DateSystem::Date1900 => 1,
+        DateSystem::Date1904 => 0
+    }
+}
+
+fn max_serial(system: DateSystem) -> i64 {
+    match system {
+        DateSystem::Date1900 => MAX_SERIAL_1900,
+        DateSystem::Date1904 => MAX_SERIAL_1904
+    }
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        _ => 31
+    }
+}
+
+// Days since 1970-01-01 in the proleptic Gregorian calendar. This is Howard Hinnant's
+// days_from_civil algorithm.
+fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year as i64 - 1 } else { year as i64 };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// The inverse of days_from_civil
+fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = (yoe + era * 400) as i32 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(feature = "chrono")]
+mod chrono_dates {
+    use super::*;
+    use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+
+    impl ExcelDate for NaiveDate {
+        fn to_serial(&self, system: DateSystem) -> Option<f64> {
+            Date { year: self.year(), month: self.month(), day: self.day() }.to_serial(system)
+        }
+
+        fn from_serial(serial: f64, system: DateSystem) -> Option<NaiveDate> {
+            let date = Date::from_serial(serial, system)?;
+            NaiveDate::from_ymd_opt(date.year, date.month, date.day)
+        }
+    }
+
+    impl ExcelDate for NaiveTime {
+        fn to_serial(&self, system: DateSystem) -> Option<f64> {
+            let millisecond = (self.nanosecond() / 1_000_000).min(999);
+            TimeOfDay { hour: self.hour(), minute: self.minute(), second: self.second(), millisecond }
+                .to_serial(system)
+        }
+
+        fn from_serial(serial: f64, system: DateSystem) -> Option<NaiveTime> {
+            let time = TimeOfDay::from_serial(serial, system)?;
+            NaiveTime::from_hms_milli_opt(time.hour, time.minute, time.second, time.millisecond)
+        }
+    }
+
+    impl ExcelDate for NaiveDateTime {
+        fn to_serial(&self, system: DateSystem) -> Option<f64> {
+            Some(self.date().to_serial(system)? + self.time().to_serial(system)?)
+        }
+
+        fn from_serial(serial: f64, system: DateSystem) -> Option<NaiveDateTime> {
+            let datetime = DateTime::from_serial(serial, system)?;
+            let date = NaiveDate::from_ymd_opt(datetime.date.year, datetime.date.month, datetime.date.day)?;
+            let time = datetime.time;
+            let time = NaiveTime::from_hms_milli_opt(time.hour, time.minute, time.second, time.millisecond)?;
+            Some(NaiveDateTime::new(date, time))
+        }
+    }
+}
+
+#[cfg(feature = "time")]
+mod time_dates {
+    use super::*;
+    use std::convert::TryFrom;
+    use time::{Month, PrimitiveDateTime, Time};
+
+    impl ExcelDate for time::Date {
+        fn to_serial(&self, system: DateSystem) -> Option<f64> {
+            Date { year: self.year(), month: self.month() as u32, day: self.day() as u32 }.to_serial(system)
+        }
+
+        fn from_serial(serial: f64, system: DateSystem) -> Option<time::Date> {
+            let date = Date::from_serial(serial, system)?;
+            let month = Month::try_from(date.month as u8).ok()?;
+            time::Date::from_calendar_date(date.year, month, date.day as u8).ok()
+        }
+    }
+
+    impl ExcelDate for Time {
+        fn to_serial(&self, system: DateSystem) -> Option<f64> {
+            TimeOfDay {
+                hour: self.hour() as u32,
+                minute: self.minute() as u32,
+                second: self.second() as u32,
+                millisecond: self.millisecond() as u32 }.to_serial(system)
+        }
+
+        fn from_serial(serial: f64, system: DateSystem) -> Option<Time> {
+            let time = TimeOfDay::from_serial(serial, system)?;
+            Time::from_hms_milli(time.hour as u8, time.minute as u8, time.second as u8, time.millisecond as u16).ok()
+        }
+    }
+
+    impl ExcelDate for PrimitiveDateTime {
+        fn to_serial(&self, system: DateSystem) -> Option<f64> {
+            Some(self.date().to_serial(system)? + self.time().to_serial(system)?)
+        }
+
+        fn from_serial(serial: f64, system: DateSystem) -> Option<PrimitiveDateTime> {
+            let date = time::Date::from_serial(serial, system)?;
+            let time = Time::from_serial(serial, system)?;
+            Some(PrimitiveDateTime::new(date, time))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entrypoint::ExcelBackend;
+    use testing;
+    use xlcall::{XLOPER12, LPXLOPER12, xlretSuccess};
+
+    fn date(year: i32, month: u32, day: u32) -> Date {
+        Date::new(year, month, day).unwrap_or(Date { year, month, day })
+    }
+
+    #[test]
+    fn serials_1900() {
+        let system = DateSystem::Date1900;
+        assert_eq!(date(1900, 1, 1).to_serial(system), Some(1.0));
+        assert_eq!(date(1900, 2, 28).to_serial(system), Some(59.0));
+        assert_eq!(date(1900, 2, 29).to_serial(system), Some(60.0));
+        assert_eq!(date(1900, 3, 1).to_serial(system), Some(61.0));
+        assert_eq!(date(2000, 1, 1).to_serial(system), Some(36526.0));
+        assert_eq!(date(9999, 12, 31).to_serial(system), Some(2958465.0));
+        assert_eq!(date(1899, 12, 31).to_serial(system), None);
+        assert_eq!(date(2001, 2, 29).to_serial(system), None);
+
+        for &serial in [1.0, 59.0, 60.0, 61.0, 36526.0, 45000.0].iter() {
+            let d = Date::from_serial(serial, system).unwrap();
+            assert_eq!(d.to_serial(system), Some(serial));
+        }
+        assert_eq!(Date::from_serial(60.0, system), Some(date(1900, 2, 29)));
+        assert_eq!(Date::from_serial(0.5, system), None);
+    }
+
+    #[test]
+    fn serials_1904() {
+        let system = DateSystem::Date1904;
+        assert_eq!(date(1904, 1, 1).to_serial(system), Some(0.0));
+        assert_eq!(date(2000, 1, 1).to_serial(system), Some(36526.0 - 1462.0));
+        assert_eq!(date(1900, 2, 29).to_serial(system), None);
+        assert_eq!(Date::from_serial(35064.0, system), Some(date(2000, 1, 1)));
+    }
+
+    #[test]
+    fn times() {
+        let system = DateSystem::Date1900;
+        assert_eq!(TimeOfDay::from_serial(0.5, system), TimeOfDay::new(12, 0, 0, 0));
+        assert_eq!(TimeOfDay::new(18, 0, 0, 0).unwrap().to_serial(system), Some(0.75));
+
+        let datetime = DateTime::from_serial(36526.75, system).unwrap();
+        assert_eq!(datetime.date, date(2000, 1, 1));
+        assert_eq!(datetime.time, TimeOfDay::new(18, 0, 0, 0).unwrap());
+
+        // a whisker before midnight rounds to midnight of the next day
+        let datetime = DateTime::from_serial(36_526.999_999_999, system).unwrap();
+        assert_eq!(datetime.date, date(2000, 1, 2));
+        assert_eq!(datetime.time, TimeOfDay::default());
+
+        let var = Variant::from_date(datetime, system);
+        assert_eq!(var.as_f64(), Some(36527.0));
+        assert_eq!(var.as_date::<Date>(system), Some(date(2000, 1, 2)));
+        assert_eq!(Variant::from_date(date(1800, 1, 1), system).as_error(), Some(XlError::Num));
+        assert_eq!(Variant::from_str("2000-01-01").as_date::<Date>(system), None);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn chrono_dates() {
+        use chrono::{NaiveDate, NaiveDateTime};
+        let system = DateSystem::Date1900;
+        let d = NaiveDate::from_ymd_opt(2024, 2, 29).unwrap();
+        let serial = d.to_serial(system).unwrap();
+        assert_eq!(NaiveDate::from_serial(serial, system), Some(d));
+        assert_eq!(NaiveDate::from_serial(60.0, system), None);
+        let dt = NaiveDateTime::from_serial(serial + 0.25, system).unwrap();
+        assert_eq!(dt.to_string(), "2024-02-29 06:00:00");
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn time_dates() {
+        use time::{Month, PrimitiveDateTime};
+        let system = DateSystem::Date1904;
+        let d = time::Date::from_calendar_date(2024, Month::February, 29).unwrap();
+        let serial = d.to_serial(system).unwrap();
+        assert_eq!(time::Date::from_serial(serial, system), Some(d));
+        let dt = PrimitiveDateTime::from_serial(serial + 0.5, system).unwrap();
+        assert_eq!(dt.time().hour(), 12);
+    }
+
+    struct Uses1904;
+
+    impl ExcelBackend for Uses1904 {
+        fn excel12v(&self, xlfn: i32, oper_res: &mut XLOPER12, opers: &[LPXLOPER12]) -> i32 {
+            let result = match xlfn as u32 {
+                n if n == xlfGetDocument && unsafe { Variant::from_xloper(opers[0]) }.as_i32() == Some(GET_DOCUMENT_1904) =>
+                    Variant::from_bool(true),
+                _ => Variant::from_err(XlError::Value)
+            };
+            *oper_res = result.into_xloper();
+            xlretSuccess as i32
+        }
+    }
+
+    #[test]
+    fn date_system_from_excel() {
+        let _guard = testing::install(Uses1904);
+        assert_eq!(caller_date_system(), Ok(DateSystem::Date1904));
+    }
+}
@@ -1,28 +1,194 @@
 //! Entry point code for xladd, based on the sample C++ code
 //! supplied with the Microsoft Excel12 SDK
+//!
+//! All the calls into Excel in this module, excel12 and its variants and excel_free, are
+//! safe to call concurrently from any number of threads, as is dropping a Variant (which
+//! may call excel_free). This matters for functions registered as threadsafe, which
+//! Excel may call on many calculation threads at once. Whether a particular Excel
+//! function may be called from such a thread is up to Excel: if not, the call fails with
+//! xlretNotThreadSafe.
 
-use std::ptr;
+use std::{error, fmt, ptr};
+#[cfg(windows)]
 use std::mem;
-use xlcall::{LPXLOPER12, XLOPER12, xlretFailed, xlFree};
-use variant::Variant;
+use std::sync::{Arc, RwLock};
+#[cfg(windows)]
+use std::sync::OnceLock;
+use xlcall::{LPXLOPER12, XLOPER12, xlFree, xlretSuccess, xlretAbort, xlretInvXlfn,
+    xlretInvCount, xlretInvXloper, xlretStackOvfl, xlretFailed, xlretUncalced,
+    xlretNotThreadSafe, xlretInvAsynchronousContext, xlretNotClusterSafe};
+use variant::{Variant, XlOwnedVariant};
 use registrator::debug_print;
+#[cfg(windows)]
 use winapi::um::libloaderapi::{GetModuleHandleW, GetProcAddress};
-use winapi::shared::minwindef::HMODULE;
+#[cfg(windows)]
 use widestring::U16CString;
+#[cfg(windows)]
 use std::ffi::CStr;
 
+#[cfg(windows)]
 const EXCEL12ENTRYPT: &[u8] = b"MdCallBack12\0";
+#[cfg(windows)]
 const XLCALL32DLL: &str = "XLCall32";
+#[cfg(windows)]
 const XLCALL32ENTRYPT: &[u8] = b"GetExcel12EntryPt\0";
-type EXCEL12PROC = extern "stdcall" fn(
+type EXCEL12PROC = extern "system" fn(
     xlfn: ::std::os::raw::c_int, 
     count: ::std::os::raw::c_int,
     rgpxloper12: *const LPXLOPER12,
     xloper12res: LPXLOPER12) -> ::std::os::raw::c_int;
-type FNGETEXCEL12ENTRYPT = extern "stdcall" fn() -> usize;
+#[cfg(windows)]
+type FNGETEXCEL12ENTRYPT = extern "system" fn() -> usize;
 
-static mut XLCALL_HMODULE: HMODULE = ptr::null_mut();
-static mut PEXCEL12: usize = 0;
+#[cfg(windows)]
+static PEXCEL12: OnceLock<usize> = OnceLock::new();
+static BACKEND: RwLock<Option<Arc<dyn ExcelBackend>>> = RwLock::new(None);
+
+/// A replacement for the MdCallBack12 entry point that Excel exports. Normally, every
+/// call from the addin into Excel goes through that entry point. If a backend is
+/// installed, calls go to the backend instead, which means addin code can be run and
+/// tested in a process that has no Excel, for example under `cargo test`.
+pub trait ExcelBackend: Send + Sync {
+    /// Services a call into Excel. The arguments are the same as for excel12v: the
+    /// function number as defined in xlcall, the XLOPER12 to write the result into, and
+    /// pointers to the arguments. Returns one of the xlret codes, such as xlretSuccess.
+    ///
+    /// Any result written into oper_res is later released by the addin through
+    /// XlOwnedVariant, either as a Variant (if it has xlbitDLLFree set, which means it
+    /// must have been made by Variant) or via `free` below (if it has xlbitXLFree set).
+    fn excel12v(&self, xlfn: i32, oper_res: &mut XLOPER12, opers: &[LPXLOPER12]) -> i32;
+
+    /// Services a call to xlFree, for a result previously returned by this backend. The
+    /// default does nothing, which is right for backends that return only numbers, or
+    /// strings and arrays owned by the addin (xlbitDLLFree).
+    fn free(&self, _xloper: LPXLOPER12) -> i32 {
+        xlretSuccess as i32
+    }
+}
+
+/// Any closure with the signature of excel12v can be used as a backend. Note that the
+/// closure arguments need their types annotated, for example
+/// `|xlfn: i32, res: &mut XLOPER12, opers: &[LPXLOPER12]| { ... }`
+impl<F> ExcelBackend for F
+    where F: Fn(i32, &mut XLOPER12, &[LPXLOPER12]) -> i32 + Send + Sync {
+    fn excel12v(&self, xlfn: i32, oper_res: &mut XLOPER12, opers: &[LPXLOPER12]) -> i32 {
+        self(xlfn, oper_res, opers)
+    }
+}
+
+/// Installs a backend that services all subsequent calls into Excel from any thread,
+/// in place of the entry point exported by Excel. Any previously installed backend is
+/// replaced.
+pub fn set_backend<B: ExcelBackend + 'static>(backend: B) {
+    *BACKEND.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(backend));
+}
+
+/// Removes any installed backend, so calls go to Excel again.
+pub fn clear_backend() {
+    *BACKEND.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+// Returns the installed backend if there is one. We clone the Arc so the lock is not
+// held while the backend runs, as backends may well call back into excel12 themselves.
+fn backend() -> Option<Arc<dyn ExcelBackend>> {
+    BACKEND.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// The reason a call into Excel failed, decoded from the xlret return code. Excel
+/// defines the return codes as bit flags. In practice only one is ever set, but if there
+/// are several, the lowest is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XlRetError {
+    /// xlretAbort: the macro was halted, for example by the user pressing Esc
+    Abort,
+    /// xlretInvXlfn: the function number is not valid
+    InvXlfn,
+    /// xlretInvCount: the wrong number of arguments was passed
+    InvCount,
+    /// xlretInvXloper: one of the arguments is not a valid XLOPER12
+    InvXloper,
+    /// xlretStackOvfl: Excel ran out of stack
+    StackOvfl,
+    /// xlretFailed: the call failed, for example a command-equivalent function failed,
+    /// or there is no Excel to call
+    Failed,
+    /// xlretUncalced: the call tried to read a cell that has not yet been calculated.
+    /// A function that sees this must return promptly, and Excel will call it again
+    /// once the cell has been calculated.
+    Uncalced,
+    /// xlretNotThreadSafe: a function registered as threadsafe made a call that is not
+    NotThreadSafe,
+    /// xlretInvAsynchronousContext: the asynchronous function handle is not valid
+    InvAsynchronousContext,
+    /// xlretNotClusterSafe: the call is not supported on a compute cluster
+    NotClusterSafe,
+    /// A return code that has none of the flags above
+    Unknown(i32),
+}
+
+impl XlRetError {
+    /// Decodes a return code from Excel. Returns Ok(()) for xlretSuccess.
+    pub fn check(code: i32) -> Result<(), XlRetError> {
+        if code == xlretSuccess as i32 {
+            return Ok(())
+        }
+
+        let flags = [
+            (xlretAbort, XlRetError::Abort),
+            (xlretInvXlfn, XlRetError::InvXlfn),
+            (xlretInvCount, XlRetError::InvCount),
+            (xlretInvXloper, XlRetError::InvXloper),
+            (xlretStackOvfl, XlRetError::StackOvfl),
+            (xlretFailed, XlRetError::Failed),
+            (xlretUncalced, XlRetError::Uncalced),
+            (xlretNotThreadSafe, XlRetError::NotThreadSafe),
+            (xlretInvAsynchronousContext, XlRetError::InvAsynchronousContext),
+            (xlretNotClusterSafe, XlRetError::NotClusterSafe)];
+        for &(flag, error) in flags.iter() {
+            if (code as u32) & flag != 0 {
+                return Err(error)
+            }
+        }
+        Err(XlRetError::Unknown(code))
+    }
+
+    /// Returns the xlret code that this error was decoded from
+    pub fn code(&self) -> i32 {
+        (match *self {
+            XlRetError::Abort => xlretAbort,
+            XlRetError::InvXlfn => xlretInvXlfn,
+            XlRetError::InvCount => xlretInvCount,
+            XlRetError::InvXloper => xlretInvXloper,
+            XlRetError::StackOvfl => xlretStackOvfl,
+            XlRetError::Failed => xlretFailed,
+            XlRetError::Uncalced => xlretUncalced,
+            XlRetError::NotThreadSafe => xlretNotThreadSafe,
+            XlRetError::InvAsynchronousContext => xlretInvAsynchronousContext,
+            XlRetError::NotClusterSafe => xlretNotClusterSafe,
+            XlRetError::Unknown(code) => return code,
+        }) as i32
+    }
+}
+
+impl fmt::Display for XlRetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            XlRetError::Abort => write!(f, "xlretAbort: the macro was halted"),
+            XlRetError::InvXlfn => write!(f, "xlretInvXlfn: invalid function number"),
+            XlRetError::InvCount => write!(f, "xlretInvCount: invalid number of arguments"),
+            XlRetError::InvXloper => write!(f, "xlretInvXloper: invalid XLOPER12 argument"),
+            XlRetError::StackOvfl => write!(f, "xlretStackOvfl: stack overflow"),
+            XlRetError::Failed => write!(f, "xlretFailed: the call into Excel failed"),
+            XlRetError::Uncalced => write!(f, "xlretUncalced: tried to read an uncalculated cell"),
+            XlRetError::NotThreadSafe => write!(f, "xlretNotThreadSafe: call is not threadsafe"),
+            XlRetError::InvAsynchronousContext => write!(f, "xlretInvAsynchronousContext: invalid asynchronous function handle"),
+            XlRetError::NotClusterSafe => write!(f, "xlretNotClusterSafe: call is not supported on a cluster"),
+            XlRetError::Unknown(code) => write!(f, "unknown xlret code {}", code),
+        }
+    }
+}
+
+impl error::Error for XlRetError {}
 
 /// Call into Excel, passing a function number as defined in xlcall and a slice
 /// of Variant, and returning a Variant. To find out the number and type of
@@ -31,72 +197,194 @@ static mut PEXCEL12: usize = 0;
 /// Note that this is a slightly inefficient call, in that it allocates a vector
 /// of pointers. For example, if you have a single argument, it is faster to invoke
 /// the single arg version.
+/// 
+/// If the call fails, the failure is logged and a nil Variant is returned. Use
+/// try_excel12 if you need to know why, for example to handle xlretUncalced.
 pub fn excel12(xlfn: u32, opers: &mut [Variant]) -> Variant {
+    try_excel12(xlfn, opers).unwrap_or_else(|e| {
+        debug_print(&format!("excel12({}) failed: {}", xlfn, e));
+        Variant::new()
+    })
+}
+
+/// Single argument version of excel12
+pub fn excel12_1(xlfn: u32, oper: Variant) -> Variant {
+    try_excel12_1(xlfn, oper).unwrap_or_else(|e| {
+        debug_print(&format!("excel12({}) failed: {}", xlfn, e));
+        Variant::new()
+    })
+}
+
+/// Call into Excel, like excel12, but returning an error if Excel returns anything other
+/// than xlretSuccess. The result is copied into a Variant, and Excel's copy freed.
+pub fn try_excel12(xlfn: u32, opers: &mut [Variant]) -> Result<Variant, XlRetError> {
+    try_excel12_xl(xlfn, opers).map(XlOwnedVariant::into_variant)
+}
+
+/// Single argument version of try_excel12
+pub fn try_excel12_1(xlfn: u32, mut oper: Variant) -> Result<Variant, XlRetError> {
+    let mut result = *Variant::new().as_xloper();
+    let code = excel12v(xlfn as i32, &mut result, &[oper.as_mut_xloper()]);
+    let result = unsafe { XlOwnedVariant::from_result(result) };
+    XlRetError::check(code)?;
+    Ok(result.into_variant())
+}
+
+/// Call into Excel, like try_excel12, but returning the result as Excel owns it. This
+/// avoids copying a large result, such as the values of a range, which can be read in
+/// place through `view` and is freed when dropped.
+pub fn try_excel12_xl(xlfn: u32, opers: &mut [Variant]) -> Result<XlOwnedVariant, XlRetError> {
     debug_print(&format!("excel12({},{})", xlfn, opers.len()));
-    let mut result = Variant::new();
+    let mut result = *Variant::new().as_xloper();
     let mut args: Vec<LPXLOPER12> = Vec::with_capacity(opers.len());    
     for oper in opers.iter_mut() {
         debug_print(&format!("arg: {}", oper));
         args.push(oper.as_mut_xloper());
     }
-    excel12v(xlfn as i32, result.as_mut_xloper(), &mut args);
-    result
+    let code = excel12v(xlfn as i32, &mut result, &args);
+    let result = unsafe { XlOwnedVariant::from_result(result) };
+    XlRetError::check(code)?;
+    Ok(result)
 }
 
-pub fn excel12_1(xlfn: u32, mut oper: Variant) -> Variant {
-    let mut result = Variant::new();
-    excel12v(xlfn as i32, result.as_mut_xloper(), &[oper.as_mut_xloper()]);
-    result
-}
-
-fn fetch_excel12_entry_pt() {
+// Finds the Excel12 callback, either through XLCall32.dll or directly from the Excel
+// executable. Resolution happens once, the first time it succeeds, after which the entry
+// point is read without locking. If it fails, for example because we are not loaded in
+// Excel, we try again next time. Two threads may resolve it at the same time, but they
+// find the same address, so it does not matter which of them wins.
+#[cfg(windows)]
+fn excel12_entry_pt() -> Option<EXCEL12PROC> {
+    if let Some(&entry_pt) = PEXCEL12.get() {
+        return Some(unsafe { mem::transmute::<usize, EXCEL12PROC>(entry_pt) })
+    }
 
+    let mut entry_pt: usize = 0;
     unsafe {
-        if XLCALL_HMODULE.is_null() {
-            let wcstr = U16CString::from_str(XLCALL32DLL).unwrap();
-            XLCALL_HMODULE = GetModuleHandleW(wcstr.as_ptr());
-            if !XLCALL_HMODULE.is_null() {
-                let cstr = CStr::from_bytes_with_nul(XLCALL32ENTRYPT).unwrap();
-                let entry_pt: usize = GetProcAddress(XLCALL_HMODULE, cstr.as_ptr()) as usize;
-                if entry_pt != 0 {
-                    PEXCEL12 = mem::transmute::<usize, FNGETEXCEL12ENTRYPT>(entry_pt)();
-                }
+        let wcstr = U16CString::from_str(XLCALL32DLL).unwrap();
+        let hmodule = GetModuleHandleW(wcstr.as_ptr());
+        if !hmodule.is_null() {
+            let cstr = CStr::from_bytes_with_nul(XLCALL32ENTRYPT).unwrap();
+            let get_entry_pt: usize = GetProcAddress(hmodule, cstr.as_ptr()) as usize;
+            if get_entry_pt != 0 {
+                entry_pt = mem::transmute::<usize, FNGETEXCEL12ENTRYPT>(get_entry_pt)();
             }
         }
 
-        if PEXCEL12 == 0 {
-            XLCALL_HMODULE = GetModuleHandleW(ptr::null());
-            if !XLCALL_HMODULE.is_null() {
+        if entry_pt == 0 {
+            let hmodule = GetModuleHandleW(ptr::null());
+            if !hmodule.is_null() {
                 let cstr = CStr::from_bytes_with_nul(EXCEL12ENTRYPT).unwrap();
-
-                PEXCEL12 = GetProcAddress(XLCALL_HMODULE, cstr.as_ptr()) as usize;
+                entry_pt = GetProcAddress(hmodule, cstr.as_ptr()) as usize;
             }
         }
     }
+
+    if entry_pt == 0 {
+        None
+    } else {
+        let entry_pt = *PEXCEL12.get_or_init(|| entry_pt);
+        Some(unsafe { mem::transmute::<usize, EXCEL12PROC>(entry_pt) })
+    }
+}
+
+// There is no Excel to call back into except on Windows, so unless a backend has been
+// installed, all calls fail with xlretFailed.
+#[cfg(not(windows))]
+fn excel12_entry_pt() -> Option<EXCEL12PROC> {
+    None
 }
 
+/// Calls Excel12v, or the installed backend if there is one. Like the other calls into
+/// Excel, this may be called from any thread, for example by a function registered as
+/// threadsafe that is running on one of Excel's calculation threads.
 pub fn excel12v(xlfn: i32, oper_res: &mut XLOPER12, opers: &[LPXLOPER12]) -> i32 {
-	fetch_excel12_entry_pt();
+    if let Some(backend) = backend() {
+        return backend.excel12v(xlfn, oper_res, opers)
+    }
 
-    unsafe {
-        if PEXCEL12 == 0 {
-            xlretFailed as i32
-        } else {
-            let p = opers.as_ptr();
-            let len = opers.len();
-            mem::transmute::<usize, EXCEL12PROC>(PEXCEL12)(xlfn, len as i32, p, oper_res)
-        }
+    match excel12_entry_pt() {
+        Some(excel12proc) => excel12proc(xlfn, opers.len() as i32, opers.as_ptr(), oper_res),
+        None => xlretFailed as i32
     }
 }
 
+/// Asks Excel (or the installed backend) to free the memory of an XLOPER12 that it
+/// returned. This is safe to call from any thread.
 pub fn excel_free(xloper: LPXLOPER12) -> i32 {
-	fetch_excel12_entry_pt();
+    if let Some(backend) = backend() {
+        return backend.free(xloper)
+    }
 
-    unsafe {
-        if PEXCEL12 == 0 {
-            xlretFailed as i32
-        } else {
-            mem::transmute::<usize, EXCEL12PROC>(PEXCEL12)(xlFree as i32, 1, &xloper, ptr::null_mut())
+    match excel12_entry_pt() {
+        Some(excel12proc) => excel12proc(xlFree as i32, 1, &xloper, ptr::null_mut()),
+        None => xlretFailed as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xlcall::{xlGetName, xlfRegister, xlCoerce};
+    use testing;
+
+    #[test]
+    #[allow(non_upper_case_globals)]
+    fn backend_answers_calls() {
+        let _guard = testing::install(|xlfn: i32, res: &mut XLOPER12, opers: &[LPXLOPER12]| {
+            match xlfn as u32 {
+                xlGetName => {
+                    *res = Variant::from_str("C:\\addins\\test.xll").into_xloper();
+                    xlretSuccess as i32
+                },
+                xlfRegister => {
+                    *res = Variant::from_int(opers.len() as i32).into_xloper();
+                    xlretSuccess as i32
+                },
+                _ => xlretInvXlfn as i32
+            }
+        });
+
+        assert_eq!(excel12(xlGetName, &mut []).to_string(), "C:\\addins\\test.xll");
+        let mut opers = [Variant::from_str("a"), Variant::from_str("b")];
+        assert_eq!(excel12(xlfRegister, &mut opers).as_i32(), Some(2));
+    }
+
+    #[test]
+    fn errors_are_decoded() {
+        let _guard = testing::install(|xlfn: i32, _res: &mut XLOPER12, _opers: &[LPXLOPER12]| {
+            if xlfn as u32 == xlCoerce { xlretUncalced as i32 } else { xlretInvXlfn as i32 }
+        });
+
+        assert_eq!(try_excel12_1(xlCoerce, Variant::new()).err(), Some(XlRetError::Uncalced));
+        assert_eq!(try_excel12(xlGetName, &mut []).err(), Some(XlRetError::InvXlfn));
+        assert!(excel12(xlGetName, &mut []).as_string().is_none());
+
+        assert_eq!(XlRetError::check(0), Ok(()));
+        assert_eq!(XlRetError::check((xlretFailed | xlretUncalced) as i32), Err(XlRetError::Failed));
+        assert_eq!(XlRetError::check(1 << 20), Err(XlRetError::Unknown(1 << 20)));
+        assert_eq!(XlRetError::Uncalced.code(), xlretUncalced as i32);
+        assert_eq!(XlRetError::Uncalced.to_string(), "xlretUncalced: tried to read an uncalculated cell");
+    }
+
+    #[test]
+    fn concurrent_calls_and_frees() {
+        use std::thread;
+
+        // FakeExcel hands back a copy of its argument for xlCoerce of a non-reference,
+        // which the Variant drop method must then return through xlFree.
+        let excel = testing::FakeExcel::new("test.xll");
+        let _guard = excel.install();
+        let threads: Vec<_> = (0..16).map(|t| thread::spawn(move || {
+            for i in 0..500 {
+                let text = format!("thread {} call {}", t, i);
+                let result = excel12_1(xlCoerce, Variant::from_str(&text));
+                assert_eq!(result.to_string(), text);
+            }
+        })).collect();
+        for thread in threads {
+            thread.join().unwrap();
         }
+
+        assert_eq!(excel.unfreed_results(), 0);
     }
 }
@@ -0,0 +1,143 @@
+//! Hooks that run Rust closures when Excel finishes or cancels a calculation, for example
+//! to flush work that has been batched up during the recalc, or to sweep the cache.
+//!
+//! Excel reports these events by running a command registered with xlEventRegister, so
+//! the first hook for an event registers a hidden command, `xladd_CalculationEnded` or
+//! `xladd_CalculationCanceled`, which runs every hook for that event in turn:
+//!
+//! ```
+//! # use xladd::events::{self, CalcEvent};
+//! # use xladd::registrator::Reg;
+//! # use xladd::testing::FakeExcel;
+//! # let excel = FakeExcel::new("mine.xll");
+//! # let _guard = excel.install();
+//! let reg = Reg::new();
+//! events::on(&reg, CalcEvent::Ended, || {
+//!     xladd::cache::sweep();
+//! }).unwrap();
+//! ```
+//!
+//! The hooks are removed, and Excel told to stop reporting the events, by
+//! xlauto::close_all.
+
+#![allow(non_upper_case_globals)]
+
+use std::sync::{Arc, Mutex};
+use entrypoint::try_excel12;
+use protect;
+use registrator::{Reg, RegisterError, debug_print};
+use variant::Variant;
+use xlcall::{xlEventRegister, xleventCalculationEnded, xleventCalculationCanceled};
+use xlerror::XlError;
+
+/// A calculation event that hooks can be registered for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalcEvent {
+    /// Excel has finished a calculation
+    Ended,
+    /// The user has canceled a calculation, for example by pressing Esc
+    Canceled,
+}
+
+impl CalcEvent {
+    const ALL: [CalcEvent; 2] = [CalcEvent::Ended, CalcEvent::Canceled];
+
+    /// The code for the event passed to xlEventRegister
+    pub fn code(self) -> i32 {
+        match self {
+            CalcEvent::Ended => xleventCalculationEnded as i32,
+            CalcEvent::Canceled => xleventCalculationCanceled as i32,
+        }
+    }
+
+    /// The name of the command that Excel runs for the event
+    pub fn command(self) -> &'static str {
+        match self {
+            CalcEvent::Ended => "xladd_CalculationEnded",
+            CalcEvent::Canceled => "xladd_CalculationCanceled",
+        }
+    }
+}
+
+type Hook = Arc<dyn Fn() + Send + Sync>;
+
+static HOOKS: Mutex<Vec<(CalcEvent, Hook)>> = Mutex::new(Vec::new());
+
+/// Adds a hook to be run whenever the event happens. Hooks run in the order they were
+/// added, and a hook that panics does not stop the others from running. This can only be
+/// called from within xlAutoOpen.
+pub fn on<F>(reg: &Reg, event: CalcEvent, hook: F) -> Result<(), RegisterError>
+    where F: Fn() + Send + Sync + 'static {
+
+    let first = !HOOKS.lock().unwrap_or_else(|e| e.into_inner()).iter().any(|h| h.0 == event);
+    if first {
+        reg.add_command(event.command(), None, move || run(event))?;
+        let mut opers = [Variant::from_str(event.command()), Variant::from_int(event.code())];
+        match try_excel12(xlEventRegister, &mut opers) {
+            Ok(ref result) if result.as_bool() == Some(true) => {},
+            Ok(_) => return Err(RegisterError::Rejected(event.command().to_string(), XlError::Value)),
+            Err(e) => return Err(RegisterError::Failed(event.command().to_string(), e))
+        }
+    }
+    HOOKS.lock().unwrap_or_else(|e| e.into_inner()).push((event, Arc::new(hook)));
+    Ok(())
+}
+
+/// Removes every hook, and tells Excel to stop reporting the events. This is called by
+/// xlauto::close_all, before the commands are unregistered.
+pub fn clear() {
+    let hooks = ::std::mem::take(&mut *HOOKS.lock().unwrap_or_else(|e| e.into_inner()));
+    for event in CalcEvent::ALL.iter() {
+        if hooks.iter().any(|h| h.0 == *event) {
+            let mut opers = [Variant::missing(), Variant::from_int(event.code())];
+            if let Err(e) = try_excel12(xlEventRegister, &mut opers) {
+                debug_print(&format!("failed to stop {} events: {}", event.command(), e));
+            }
+        }
+    }
+}
+
+// Runs the hooks for an event. The lock is not held while they run, as they may add hooks.
+fn run(event: CalcEvent) {
+    let hooks: Vec<Hook> = HOOKS.lock().unwrap_or_else(|e| e.into_inner()).iter()
+        .filter(|h| h.0 == event)
+        .map(|h| h.1.clone())
+        .collect();
+    for hook in hooks {
+        let _ = protect::catch(event.command(), || hook());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use testing::FakeExcel;
+
+    #[test]
+    fn hooks_run_on_events() {
+        static ENDED: AtomicUsize = AtomicUsize::new(0);
+        static CANCELED: AtomicUsize = AtomicUsize::new(0);
+        let excel = FakeExcel::new("events.xll");
+        let _guard = excel.install();
+        clear();
+
+        let reg = Reg::new();
+        on(&reg, CalcEvent::Ended, || { ENDED.fetch_add(1, Ordering::SeqCst); }).unwrap();
+        on(&reg, CalcEvent::Ended, || panic!("a broken hook")).unwrap();
+        on(&reg, CalcEvent::Ended, || { ENDED.fetch_add(10, Ordering::SeqCst); }).unwrap();
+        on(&reg, CalcEvent::Canceled, || { CANCELED.fetch_add(1, Ordering::SeqCst); }).unwrap();
+
+        excel.fire_event(xleventCalculationEnded);
+        assert_eq!(ENDED.load(Ordering::SeqCst), 11);
+        assert_eq!(CANCELED.load(Ordering::SeqCst), 0);
+        excel.fire_event(xleventCalculationCanceled);
+        assert_eq!(CANCELED.load(Ordering::SeqCst), 1);
+
+        clear();
+        excel.fire_event(xleventCalculationEnded);
+        excel.fire_event(xleventCalculationCanceled);
+        assert_eq!(ENDED.load(Ordering::SeqCst), 11);
+        assert_eq!(CANCELED.load(Ordering::SeqCst), 1);
+    }
+}
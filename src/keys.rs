@@ -0,0 +1,330 @@
+//! Binding keys to Rust closures, through xlcOnKey.
+//!
+//! Keys are written as Excel writes them for OnKey. A key is a single character, such as
+//! `r` or `5`, or the name of a key in braces, such as `{F5}` or `{DELETE}`, optionally
+//! preceded by any of the modifiers `+` for Shift, `^` for Ctrl and `%` for Alt. `~` is
+//! Enter, and the characters that have special meanings, `+ ^ % ~ ( ) { } [ ]`, must be
+//! put in braces to be bound themselves, as in `^{+}`. Letters are not case sensitive, so
+//! Ctrl+Shift+R is written `^+r` or `^+R`.
+//!
+//! Each binding registers a hidden command, which runs the closure, and which is
+//! unregistered again when the key is unbound, freeing its command slot:
+//!
+//! ```
+//! # use xladd::keys;
+//! # use xladd::testing::FakeExcel;
+//! # let excel = FakeExcel::new("mine.xll");
+//! # let _guard = excel.install();
+//! keys::bind_key("^+R", || {
+//!     // refresh something
+//! }).unwrap();
+//! ```
+//!
+//! Binding a key that this addin has already bound hides the earlier binding, which is
+//! restored when the key is unbound again. Once the addin's last binding of a key is
+//! unbound, which xlauto::close_all does for every key still bound, the key gets back the
+//! binding it had before the addin bound it. Excel cannot say what that was, so it must be
+//! given to bind_key_over, as the name of the macro or command the key ran:
+//!
+//! ```
+//! # use xladd::keys;
+//! # use xladd::testing::FakeExcel;
+//! # let excel = FakeExcel::new("mine.xll");
+//! # let _guard = excel.install();
+//! keys::bind_key_over("^+R", "MyWorkbook.xlsm!RefreshAll", || {
+//!     // refresh something
+//! }).unwrap();
+//! ```
+//!
+//! Keys bound with bind_key are given back their normal meaning in Excel instead, so any
+//! binding a macro or another addin made to them is lost.
+
+#![allow(non_upper_case_globals)]
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+use entrypoint::{try_excel12, XlRetError};
+use registrator::{Reg, RegisterError, debug_print, unregister};
+use variant::Variant;
+use xlcall::xlcOnKey;
+
+// The names of the keys that can be written in braces, apart from the function keys
+const KEY_NAMES: &[&str] = &["BACKSPACE", "BS", "BREAK", "CAPSLOCK", "CLEAR", "DELETE", "DEL",
+    "DOWN", "END", "ENTER", "ESCAPE", "ESC", "HELP", "HOME", "INSERT", "LEFT", "NUMLOCK",
+    "PGDN", "PGUP", "RETURN", "RIGHT", "SCROLLLOCK", "TAB", "UP"];
+
+// The characters that must be written in braces
+const SPECIAL: &str = "+^%~(){}[]";
+
+/// The reasons binding a key can fail
+#[derive(Debug)]
+pub enum KeyError {
+    /// The key is not written in Excel's syntax. Holds the key and what is wrong with it.
+    Invalid(String, String),
+    /// The command that runs the closure could not be registered
+    Register(RegisterError),
+    /// Excel refused the xlcOnKey, for example because it was called from a UDF
+    Failed(String, XlRetError),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyError::Invalid(ref key, ref reason) => write!(f, "invalid key \"{}\": {}", key, reason),
+            KeyError::Register(ref e) => write!(f, "cannot register the key command: {}", e),
+            KeyError::Failed(ref key, ref e) => write!(f, "cannot bind \"{}\": {}", key, e),
+        }
+    }
+}
+
+impl Error for KeyError {}
+
+// How a key is bound by this addin
+#[derive(Default)]
+struct Binding {
+    // the macro the key ran before this addin bound it, if we were told
+    original: Option<String>,
+    // the commands bound by this addin, the current one last
+    commands: Vec<String>,
+}
+
+struct Bindings {
+    next_id: u32,
+    // by the key in canonical form
+    keys: BTreeMap<String, Binding>,
+}
+
+static BINDINGS: Mutex<Bindings> = Mutex::new(Bindings { next_id: 1, keys: BTreeMap::new() });
+
+/// Checks that a key is written in Excel's syntax, returning it in a canonical form, with
+/// the modifiers in the order `+^%`, letters in lower case and key names in upper case.
+/// So `^+R` becomes `+^r`, and `%{f4}` becomes `%{F4}`.
+pub fn parse_key(key: &str) -> Result<String, KeyError> {
+    let invalid = |reason: &str| KeyError::Invalid(key.to_string(), reason.to_string());
+
+    let mut modifiers = String::new();
+    let mut rest = key;
+    while let Some(c) = rest.chars().next().filter(|c| "+^%".contains(*c)) {
+        if modifiers.contains(c) {
+            return Err(invalid(&format!("the modifier {} is repeated", c)))
+        }
+        modifiers.push(c);
+        rest = &rest[1..];
+    }
+    let modifiers: String = "+^%".chars().filter(|c| modifiers.contains(*c)).collect();
+
+    let mut chars = rest.chars();
+    let code = match (chars.next(), chars.next()) {
+        (None, _) => return Err(invalid("there is no key")),
+        (Some('~'), None) => "~".to_string(),
+        (Some(c), None) if SPECIAL.contains(c) => return Err(invalid(&format!("{} must be written in braces", c))),
+        (Some(c), None) if c.is_control() || c.is_whitespace() => return Err(invalid("the key cannot be typed")),
+        (Some(c), None) => c.to_lowercase().collect(),
+        (Some('{'), Some(_)) if rest.ends_with('}') => {
+            let name = &rest[1..rest.len() - 1];
+            let mut name_chars = name.chars();
+            match (name_chars.next(), name_chars.next()) {
+                (Some(c), None) if SPECIAL.contains(c) => format!("{{{}}}", c),
+                _ => {
+                    let name = name.to_uppercase();
+                    if !KEY_NAMES.contains(&&*name) && !is_function_key(&name) {
+                        return Err(invalid(&format!("there is no key named {}", name)))
+                    }
+                    format!("{{{}}}", name)
+                }
+            }
+        },
+        _ => return Err(invalid("key names must be written in braces")),
+    };
+    Ok(modifiers + &code)
+}
+
+/// Binds a key to a closure, hiding any earlier binding of the same key by this addin
+/// until this one is unbound. This can be called from xlAutoOpen or a command, but not
+/// from a UDF. Each key bound uses one of the MAX_COMMANDS command slots until unbound.
+///
+/// Once the addin has unbound the key as many times as it bound it, the key has its
+/// normal meaning in Excel. Use bind_key_over to have it bound to another macro instead.
+pub fn bind_key<F>(key: &str, command: F) -> Result<(), KeyError> where F: Fn() + Send + Sync + 'static {
+    bind(key, None, command)
+}
+
+/// Binds a key to a closure, as bind_key does, recording that before this addin bound the
+/// key, it ran the macro or command named `previous`. Once the addin's last binding of the
+/// key is unbound, which xlauto::close_all does, the key is bound to `previous` again.
+pub fn bind_key_over<F>(key: &str, previous: &str, command: F) -> Result<(), KeyError> where F: Fn() + Send + Sync + 'static {
+    bind(key, Some(previous), command)
+}
+
+fn bind<F>(key: &str, original: Option<&str>, command: F) -> Result<(), KeyError> where F: Fn() + Send + Sync + 'static {
+    let key = parse_key(key)?;
+    let name = {
+        let mut bindings = BINDINGS.lock().unwrap_or_else(|e| e.into_inner());
+        let name = format!("xladd_OnKey_{}", bindings.next_id);
+        bindings.next_id += 1;
+        name
+    };
+
+    Reg::new().add_command(&name, None, command).map_err(KeyError::Register)?;
+    if let Err(e) = on_key(&key, Some(&name)) {
+        unregister(&name);
+        return Err(KeyError::Failed(key, e))
+    }
+    let mut bindings = BINDINGS.lock().unwrap_or_else(|e| e.into_inner());
+    let binding = bindings.keys.entry(key).or_default();
+    if let Some(original) = original {
+        binding.original = Some(original.to_string());
+    }
+    binding.commands.push(name);
+    Ok(())
+}
+
+/// Unbinds the latest binding of a key by this addin, restoring the binding before it by
+/// this addin if there is one, or otherwise the macro given to bind_key_over, or otherwise
+/// the key's normal meaning in Excel. The command is unregistered. Returns false if the key
+/// is not bound, or is not valid.
+pub fn unbind_key(key: &str) -> bool {
+    let key = match parse_key(key) {
+        Ok(key) => key,
+        Err(_) => return false
+    };
+    let (name, previous) = {
+        let mut bindings = BINDINGS.lock().unwrap_or_else(|e| e.into_inner());
+        let binding = match bindings.keys.get_mut(&key) {
+            Some(binding) => binding,
+            None => return false
+        };
+        let name = binding.commands.pop();
+        match binding.commands.last().cloned() {
+            Some(previous) => (name, Some(previous)),
+            None => {
+                let original = binding.original.take();
+                bindings.keys.remove(&key);
+                (name, original)
+            }
+        }
+    };
+
+    restore(&key, previous.as_deref());
+    if let Some(name) = name {
+        unregister(&name);
+    }
+    true
+}
+
+/// Gives every key bound by bind_key or bind_key_over back the binding it had before the
+/// addin bound it, as far as it is known, and unregisters the commands. This is called by
+/// xlauto::close_all.
+pub fn unbind_all() {
+    let keys = ::std::mem::take(&mut BINDINGS.lock().unwrap_or_else(|e| e.into_inner()).keys);
+    for (key, binding) in keys {
+        restore(&key, binding.original.as_deref());
+        for name in binding.commands {
+            unregister(&name);
+        }
+    }
+}
+
+fn on_key(key: &str, command: Option<&str>) -> Result<(), XlRetError> {
+    let mut opers = vec![Variant::from_str(key)];
+    opers.extend(command.map(Variant::from_str));
+    try_excel12(xlcOnKey, &mut opers).map(|_| ())
+}
+
+// Binds the key to the given macro, or to nothing, which gives it its normal meaning
+fn restore(key: &str, command: Option<&str>) {
+    if let Err(e) = on_key(key, command) {
+        debug_print(&format!("failed to give \"{}\" back to {}: {}", key, command.unwrap_or("Excel"), e));
+    }
+}
+
+fn is_function_key(name: &str) -> bool {
+    name.strip_prefix('F').and_then(|n| n.parse::<u32>().ok()).is_some_and(|n| (1..=15).contains(&n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use command::MAX_COMMANDS;
+    use registrator::registered;
+    use testing::FakeExcel;
+
+    #[test]
+    fn key_syntax() {
+        assert_eq!(parse_key("^+R").unwrap(), "+^r");
+        assert_eq!(parse_key("%{f4}").unwrap(), "%{F4}");
+        assert_eq!(parse_key("^{+}").unwrap(), "^{+}");
+        assert_eq!(parse_key("+~").unwrap(), "+~");
+        assert_eq!(parse_key("{Delete}").unwrap(), "{DELETE}");
+        for bad in &["", "^", "^^r", "^+", "F5", "{F16}", "{NOSUCHKEY}", "^ ", "%(", "rr"] {
+            assert!(parse_key(bad).is_err(), "{} should not parse", bad);
+        }
+    }
+
+    #[test]
+    fn keys_run_closures() {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let excel = FakeExcel::new("keys.xll");
+        let _guard = excel.install();
+        unbind_all();
+
+        bind_key("^+R", || { RUNS.fetch_add(1, Ordering::SeqCst); }).unwrap();
+        bind_key("%{F4}", || { RUNS.fetch_add(100, Ordering::SeqCst); }).unwrap();
+        assert!(bind_key("^{", || {}).is_err());
+
+        assert_eq!(excel.press("^+r"), 1);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+
+        // binding again hides the earlier binding, which unbinding restores
+        bind_key("+^r", || { RUNS.fetch_add(10, Ordering::SeqCst); }).unwrap();
+        assert_eq!(excel.press("^+R"), 1);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 11);
+        assert!(unbind_key("^+R"));
+        assert_eq!(excel.press("^+R"), 1);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 12);
+
+        assert!(unbind_key("^+R"));
+        assert!(!unbind_key("^+R"));
+        assert_eq!(excel.press("^+R"), 0);
+        unbind_all();
+        assert_eq!(excel.press("%{F4}"), 0);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 12);
+        assert!(!registered().iter().any(|r| r.name.starts_with("xladd_OnKey")));
+    }
+
+    #[test]
+    fn unbinding_frees_the_command() {
+        let excel = FakeExcel::new("keys.xll");
+        let _guard = excel.install();
+        unbind_all();
+
+        for _ in 0..2 * MAX_COMMANDS {
+            bind_key("^k", || {}).unwrap();
+            assert!(unbind_key("^k"));
+        }
+        assert!(!registered().iter().any(|r| r.name.starts_with("xladd_OnKey")));
+    }
+
+    #[test]
+    fn original_bindings_are_restored() {
+        let excel = FakeExcel::new("keys.xll");
+        let _guard = excel.install();
+        unbind_all();
+
+        bind_key_over("^m", "Book1.xlsm!Mine", || {}).unwrap();
+        bind_key("^m", || {}).unwrap();
+        assert!(unbind_key("^m"));
+        assert!(excel.key_binding("^m").is_some_and(|m| m.starts_with("xladd_OnKey")));
+        assert!(unbind_key("^m"));
+        assert_eq!(excel.key_binding("^m"), Some("Book1.xlsm!Mine".to_string()));
+
+        bind_key_over("^n", "Other.xla!Command", || {}).unwrap();
+        bind_key("^o", || {}).unwrap();
+        unbind_all();
+        assert_eq!(excel.key_binding("^n"), Some("Other.xla!Command".to_string()));
+        assert_eq!(excel.key_binding("^o"), None);
+    }
+}
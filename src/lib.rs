@@ -1,11 +1,40 @@
 pub mod xlcall;
 pub mod entrypoint;
 pub mod variant;
+pub mod variant_ref;
+pub mod convert;
+pub mod xlerror;
+pub mod date;
 pub mod registrator;
+pub mod protect;
+pub mod command;
+pub mod udf;
+pub mod async_udf;
+pub mod events;
+pub mod timers;
+pub mod keys;
+pub mod cache;
+pub mod shared;
 pub mod xlauto;
+pub mod testing;
 
+#[macro_use]
+extern crate inventory;
+extern crate xladd_macros;
+#[cfg(windows)]
 extern crate winapi;
+#[cfg(windows)]
 extern crate widestring;
+#[cfg(feature = "chrono")]
+extern crate chrono;
+#[cfg(feature = "time")]
+extern crate time;
+
+pub use xladd_macros::xl_func;
+
+// The generated code submits to inventory through xladd, so addins need not depend on it
+#[doc(hidden)]
+pub use inventory::submit as __submit;
 
 #[cfg(test)]
 mod tests {
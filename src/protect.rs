@@ -0,0 +1,243 @@
+//! Protection against panics in code called by Excel. A Rust panic that unwinds out of an
+//! `extern "system"` function is undefined behaviour, and in practice takes Excel down
+//! along with any unsaved workbooks. Every function that Excel calls should therefore run
+//! its body through one of the functions here, which catch the panic, report it, and give
+//! Excel something sensible instead:
+//!
+//! ```
+//! # use xladd::protect;
+//! # use xladd::variant::Variant;
+//! # use xladd::variant_ref::VariantRef;
+//! # use xladd::xlcall::LPXLOPER12;
+//! extern "system" fn checked_sqrt(x: VariantRef) -> LPXLOPER12 {
+//!     protect::udf("checked_sqrt", || {
+//!         let x = x.as_f64().expect("a number");
+//!         Variant::from_float(x.sqrt())
+//!     })
+//! }
+//! ```
+//!
+//! A UDF that panics returns #VALUE!, unless set_panic_result says otherwise. The panic
+//! message and where it happened are reported through registrator::debug_print, unless
+//! set_panic_logger says otherwise.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Once, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::fmt;
+use registrator::debug_print;
+use variant::Variant;
+use xlcall::LPXLOPER12;
+use xlerror::XlError;
+
+type PanicResult = dyn Fn(&PanicReport) -> Variant + Send + Sync;
+type PanicLogger = dyn Fn(&PanicReport) + Send + Sync;
+
+static PANIC_RESULT: RwLock<Option<Arc<PanicResult>>> = RwLock::new(None);
+static PANIC_LOGGER: RwLock<Option<Arc<PanicLogger>>> = RwLock::new(None);
+static INSTALL_HOOK: Once = Once::new();
+static NEXT_CALL: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    // Where the most recent panic on this thread happened, recorded by our panic hook
+    static LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    // The function whose body `value` is running on this thread, if any, with a number
+    // that is different for every call
+    static CALL: RefCell<Option<(u64, String)>> = const { RefCell::new(None) };
+}
+
+/// A description of a panic that was caught
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PanicReport {
+    /// The name of the function that panicked, as passed to the protect function
+    pub function: String,
+    /// The panic message, if the panic had one
+    pub message: String,
+    /// The file, line and column where the panic happened, if known
+    pub location: Option<String>,
+}
+
+impl fmt::Display for PanicReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            Some(ref location) => write!(f, "panic in {} at {}: {}", self.function, location, self.message),
+            None => write!(f, "panic in {}: {}", self.function, self.message)
+        }
+    }
+}
+
+/// Sets what a UDF returns to Excel if it panics. The default is #VALUE!. For example,
+/// to show the message in the cell instead:
+/// `set_panic_result(|report| Variant::from_str(&report.message))`
+pub fn set_panic_result<F>(result: F) where F: Fn(&PanicReport) -> Variant + Send + Sync + 'static {
+    *PANIC_RESULT.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(result));
+}
+
+/// Sets how caught panics are reported. The default writes them with debug_print.
+pub fn set_panic_logger<F>(logger: F) where F: Fn(&PanicReport) + Send + Sync + 'static {
+    *PANIC_LOGGER.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(logger));
+}
+
+/// Restores the default panic result and logger
+pub fn reset_panic_handling() {
+    *PANIC_RESULT.write().unwrap_or_else(|e| e.into_inner()) = None;
+    *PANIC_LOGGER.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Runs a closure, catching any panic. If it panics, the panic is reported and returned as
+/// an Err. The name of the function is only used in the report. This is the building block
+/// for the other functions here, and can be used directly where none of them fit.
+pub fn catch<R, F: FnOnce() -> R>(function: &str, f: F) -> Result<R, PanicReport> {
+    INSTALL_HOOK.call_once(install_hook);
+
+    // Excel's own state is not touched by unwinding, and anything of ours that the closure
+    // captured is discarded along with its result, so asserting unwind safety is fine.
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let report = PanicReport {
+            function: function.to_string(),
+            message: panic_message(&*payload),
+            location: LOCATION.with(|location| location.borrow_mut().take()) };
+        log(&report);
+        report
+    })
+}
+
+/// Runs the body of a UDF, returning its result to Excel with Variant::into_excel_return.
+/// If the body panics, the panic is reported and the panic result returned instead.
+pub fn udf<F: FnOnce() -> Variant>(function: &str, f: F) -> LPXLOPER12 {
+    value(function, f).into_excel_return()
+}
+
+/// Runs a closure that produces a Variant, returning the panic result if it panics. Use
+/// this rather than `udf` for a UDF that returns its result some other way, such as with
+/// Variant::into_excel_return_slot.
+pub fn value<F: FnOnce() -> Variant>(function: &str, f: F) -> Variant {
+    let call = (NEXT_CALL.fetch_add(1, Ordering::Relaxed), function.to_string());
+    let outer = CALL.with(|current| current.replace(Some(call)));
+    let result = catch(function, f).unwrap_or_else(|report| panic_result(&report));
+    CALL.with(|current| *current.borrow_mut() = outer);
+    result
+}
+
+// The number and name of the call to `value` running on this thread, so that the cache can
+// tell the objects stored by one call from those stored by the next
+pub(crate) fn current_call() -> Option<(u64, String)> {
+    CALL.with(|current| current.borrow().clone())
+}
+
+/// Runs the body of a function that Excel expects to return an int, such as xlAutoOpen,
+/// xlAutoClose or a command. Returns 1 if the body completes, or 0 if it panics, for
+/// example because a call to Reg::add failed.
+///
+/// ```
+/// # use xladd::protect;
+/// # use xladd::registrator::Reg;
+/// #[no_mangle]
+/// pub extern "system" fn xlAutoOpen() -> i32 {
+///     protect::auto("xlAutoOpen", || {
+///         let reg = Reg::new();
+///         reg.add("checked_sqrt", "QQ$", "x", "Maths", "Square root", &[]);
+///     })
+/// }
+/// ```
+pub fn auto<F: FnOnce()>(function: &str, f: F) -> i32 {
+    if catch(function, f).is_ok() { 1 } else { 0 }
+}
+
+fn panic_result(report: &PanicReport) -> Variant {
+    let result = PANIC_RESULT.read().unwrap_or_else(|e| e.into_inner()).clone();
+    match result {
+        // the panic result could itself panic, which we must not let escape
+        Some(result) => panic::catch_unwind(AssertUnwindSafe(|| result(report)))
+            .unwrap_or_else(|_| Variant::from_err(XlError::Value)),
+        None => Variant::from_err(XlError::Value)
+    }
+}
+
+fn log(report: &PanicReport) {
+    let logger = PANIC_LOGGER.read().unwrap_or_else(|e| e.into_inner()).clone();
+    match logger {
+        Some(logger) => { let _ = panic::catch_unwind(AssertUnwindSafe(|| logger(report))); },
+        // logging is best effort, and must not panic out of catch either
+        None => { let _ = panic::catch_unwind(|| debug_print(&report.to_string())); }
+    }
+}
+
+// The location of a panic is only available to the panic hook, so we install a hook that
+// records it for the thread, then passes the panic on to whatever hook was there before.
+fn install_hook() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let location = info.location().map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()));
+        let _ = LOCATION.try_with(|cell| *cell.borrow_mut() = location);
+        previous(info);
+    }));
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panic with a non-string payload".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use xlauto::xlAutoFree12;
+
+    // The panic result and logger are process-wide, so tests that change them take turns
+    static SETTINGS_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn panics_become_errors() {
+        let _lock = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        reset_panic_handling();
+
+        let result = udf("test_panic", || panic!("bad input {}", 42));
+        assert_eq!(unsafe { Variant::from_xloper(result) }.as_error(), Some(XlError::Value));
+        xlAutoFree12(result);
+
+        let result = udf("test_fine", || Variant::from_float(1.0));
+        assert_eq!(unsafe { Variant::from_xloper(result) }.as_f64(), Some(1.0));
+        xlAutoFree12(result);
+
+        let report = catch("test_panic", || -> i32 { panic!("oops") }).unwrap_err();
+        assert_eq!(report.message, "oops");
+        assert!(report.location.as_ref().is_some_and(|l| l.contains("protect.rs")));
+        assert!(report.to_string().starts_with("panic in test_panic at "));
+
+        // a NUL cannot be written to OutputDebugStringA, but must not panic again
+        let report = catch("test_nul", || -> i32 { panic!("bad {}", "a\0b") }).unwrap_err();
+        assert_eq!(report.message, "bad a\0b");
+
+        assert_eq!(auto("xlAutoOpen", || {}), 1);
+        assert_eq!(auto("xlAutoOpen", || panic!("registration failed")), 0);
+    }
+
+    #[test]
+    fn configured_result_and_logger() {
+        let _lock = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let sink = logged.clone();
+        set_panic_logger(move |report| sink.lock().unwrap().push(report.to_string()));
+        set_panic_result(|report| Variant::from_str(&format!("#PANIC: {}", report.message)));
+
+        let result = value("test_message", || panic!("divide by {}", 0));
+        assert_eq!(result.to_string(), "#PANIC: divide by 0");
+        assert_eq!(logged.lock().unwrap().len(), 1);
+        assert!(logged.lock().unwrap()[0].contains("divide by 0"));
+
+        // a panic result that panics itself still gives an error
+        set_panic_result(|_| panic!("worse"));
+        assert_eq!(value("test_worse", || panic!("bad")).as_error(), Some(XlError::Value));
+        reset_panic_handling();
+    }
+}
@@ -1,12 +1,26 @@
+use std::{error, fmt};
+use std::sync::{Arc, Mutex};
+use async_udf;
+use command::{self, MAX_COMMANDS};
 use variant::Variant;
-use entrypoint::excel12;
-use xlcall::{ xlGetName, xlfRegister };
+use entrypoint::{excel12, try_excel12, XlRetError};
+use xlcall::{ xlGetName, xlfRegister, xlfUnregister };
+use xlerror::XlError;
+#[cfg(windows)]
 use std::ffi::CString;
+#[cfg(windows)]
 use winapi::um::debugapi::OutputDebugStringA;
 
+// Everything registered through any Reg, so it can be unregistered when the addin closes
+static REGISTERED: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+
 /// Allow xlls to register their exported functions with Excel so they can be
 /// used in a spreadsheet or macro. These functions can only be called from 
 /// within an implementation of xlAutoOpen.
+///
+/// Every function that is successfully registered is remembered, along with its register
+/// ID, so that `unregister_all` can remove them all again. The xlAutoClose generated by
+/// `xl_addin!` does this when the addin is closed.
 pub struct Reg {
     dll_name: Variant
 }
@@ -55,6 +69,9 @@ impl Reg {
     /// * `$` - Marks the function as threadsafe, so it can be called from any thread
     /// * `#` - Allows the function to be called even before the args are evaluated
     ///
+    /// Failures are only reported through debug_print. Use `try_add` to have them returned
+    /// instead, or `register` with a FunctionSpec to have the types checked as well.
+    ///
     /// # Example
     /// 
     /// reg.add("myAdd", "QQQ$", "first, second", "MyCategory", "Adds two numbers or ranges"
@@ -69,6 +86,38 @@ impl Reg {
         help_text: &str,
         arg_help: &[&str]) {
 
+        if let Err(e) = self.try_add(name, arg_types, arg_text, category, help_text, arg_help) {
+            debug_print(&e.to_string());
+        }
+    }
+
+    /// Adds an exported function to Excel, as `add` does, but returns the register ID, or
+    /// the reason it could not be registered. The texts are checked against Excel's limits
+    /// before Excel is called, though the type string is not checked. This function can
+    /// only be called from within xlAutoOpen.
+    pub fn try_add(
+        &self,
+        name: &str,
+        arg_types: &str,
+        arg_text: &str,
+        category: &str,
+        help_text: &str,
+        arg_help: &[&str]) -> Result<f64, RegisterError> {
+
+        if arg_help.len() > MAX_ARGS {
+            return Err(RegisterError::Invalid(name.to_string(),
+                format!("{} arguments is more than the {} Excel allows", arg_help.len(), MAX_ARGS)));
+        }
+        check_arg_help(name, arg_help.iter().cloned())?;
+        let mut texts = vec![
+            ("name", name.to_string()),
+            ("type text", arg_types.to_string()),
+            ("argument text", arg_text.to_string()),
+            ("category", category.to_string()),
+            ("help", help_text.to_string())];
+        texts.extend(arg_help.iter().map(|help| ("argument help", help.to_string())));
+        check_texts(name, texts)?;
+
         let mut opers = vec![
             self.dll_name.clone(),
             Variant::from_str(name),
@@ -81,17 +130,701 @@ impl Reg {
             Variant::missing(),              // no help url for now. If we add it, it needn't mean another argument to add
             Variant::from_str(help_text)];
 
-        // append any argument help strings
-        for arg in arg_help.iter() {
+        // append any argument help strings. Excel has room for the first MAX_ARG_HELP only,
+        // and check_arg_help has made sure the rest are empty.
+        for arg in arg_help.iter().take(MAX_ARG_HELP) {
             opers.push(Variant::from_str(arg));
         }
 
-        let result = excel12(xlfRegister, opers.as_mut_slice());
+        let result = try_excel12(xlfRegister, opers.as_mut_slice())
+            .map_err(|e| RegisterError::Failed(name.to_string(), e))?;
         debug_print(&format!("Registered {}: result = {}", name, result));
+        self.accepted(name, name, arg_types, &result)
+    }
+
+    /// Registers a function described by a FunctionSpec, returning the register ID that
+    /// Excel assigns it. Unlike `add`, the spec is checked before Excel is called, so
+    /// mistakes are reported as errors rather than as a silent failure. This function can
+    /// only be called from within xlAutoOpen.
+    ///
+    /// ```
+    /// # use xladd::registrator::{Reg, FunctionSpec, ArgType, ReturnType};
+    /// # use xladd::testing::FakeExcel;
+    /// # let excel = FakeExcel::new("mine.xll");
+    /// # let _guard = excel.install();
+    /// let spec = FunctionSpec::new("myAdd")
+    ///     .returns(ReturnType::Double)
+    ///     .arg("first", ArgType::Double, "the first number")
+    ///     .arg("second", ArgType::Double, "the second number")
+    ///     .category("MyCategory")
+    ///     .help("Adds two numbers")
+    ///     .threadsafe();
+    /// let register_id = Reg::new().register(&spec).unwrap();
+    /// ```
+    pub fn register(&self, spec: &FunctionSpec) -> Result<f64, RegisterError> {
+        spec.validate()?;
+
+        // Excel abandons asynchronous calls when a calculation is canceled, and their
+        // handles must not be used after that. The handler goes first, so that a function
+        // is never live in Excel without it.
+        if spec.async_handles() > 0 {
+            async_udf::register_cancel_handler(self)?;
+        }
+
+        let arg_text = spec.arg_text();
+        let mut opers = vec![
+            self.dll_name.clone(),
+            Variant::from_str(spec.procedure.as_ref().unwrap_or(&spec.name)),
+            Variant::from_str(&spec.type_text()),
+            Variant::from_str(&spec.name),
+            Variant::from_str(&arg_text),
+            Variant::from_int(spec.macro_type),
+            Variant::from_str(&spec.category),
+            spec.shortcut.map(|c| Variant::from_str(&c.to_string())).unwrap_or_else(Variant::missing),
+            optional_str(&spec.help_topic),
+            Variant::from_str(&spec.help)];
+
+        // Excel takes at most 255 arguments to xlfRegister, which leaves room for help on
+        // the first 245 arguments only. validate has checked that the rest have none.
+        for (_, _, help) in spec.args.iter().take(MAX_ARG_HELP) {
+            opers.push(Variant::from_str(help));
+        }
+
+        let result = try_excel12(xlfRegister, opers.as_mut_slice())
+            .map_err(|e| RegisterError::Failed(spec.name.clone(), e))?;
+        self.accepted(&spec.name, spec.procedure.as_ref().unwrap_or(&spec.name), &spec.type_text(), &result)
+    }
+
+    /// Registers a command, which Excel can run from a button, a menu or a shortcut key,
+    /// returning its register ID. The command is a closure that xladd runs with panic
+    /// protection, through one of its exported trampolines, so there is no need to export
+    /// anything. At most MAX_COMMANDS commands can be registered at once. Registering a
+    /// command with the same name again replaces it.
+    ///
+    /// The shortcut is a letter, which runs the command with Ctrl, or with Ctrl+Shift if
+    /// the letter is uppercase. This function can only be called from within xlAutoOpen.
+    ///
+    /// ```
+    /// # use xladd::registrator::Reg;
+    /// # use xladd::testing::FakeExcel;
+    /// # let excel = FakeExcel::new("mine.xll");
+    /// # let _guard = excel.install();
+    /// Reg::new().add_command("myRefresh", Some('R'), || {
+    ///     // refresh something
+    /// }).unwrap();
+    /// ```
+    pub fn add_command<F>(&self, name: &str, shortcut: Option<char>, command: F) -> Result<f64, RegisterError>
+        where F: Fn() + Send + Sync + 'static {
+
+        let mut spec = FunctionSpec::new(name).returns(ReturnType::Int);
+        spec.macro_type = 2;
+        spec.shortcut = shortcut;
+        spec.validate()?;
+
+        let procedure = command::allocate(name, Arc::new(command)).ok_or_else(||
+            RegisterError::Invalid(name.to_string(), format!("all {} command slots are in use", MAX_COMMANDS)))?;
+        spec.procedure = Some(procedure.to_string());
+        self.register(&spec).inspect_err(|_| command::free(name))
+    }
+
+    // Interprets the result of xlfRegister, remembering the function if Excel accepted it
+    fn accepted(&self, name: &str, procedure: &str, type_text: &str, result: &Variant) -> Result<f64, RegisterError> {
+        match (result.as_f64(), result.as_error()) {
+            (Some(id), _) => {
+                self.remember(name, procedure, type_text, id);
+                Ok(id)
+            },
+            (None, Some(err)) => Err(RegisterError::Rejected(name.to_string(), err)),
+            (None, None) => Err(RegisterError::Rejected(name.to_string(), XlError::Value))
+        }
+    }
+
+    fn remember(&self, name: &str, procedure: &str, type_text: &str, register_id: f64) {
+        let mut registered = REGISTERED.lock().unwrap_or_else(|e| e.into_inner());
+
+        // registering the same name again replaces the earlier registration
+        registered.retain(|r| r.name != name);
+        registered.push(Registered {
+            name: name.to_string(),
+            register_id,
+            dll_name: self.dll_name.as_string().unwrap_or_default(),
+            procedure: procedure.to_string(),
+            type_text: type_text.to_string() });
+    }
+}
+
+/// A function that has been registered with Excel through Reg
+#[derive(Debug, Clone)]
+pub struct Registered {
+    /// The name of the function in Excel
+    pub name: String,
+    /// The register ID that Excel returned, which identifies the function to xlfUnregister
+    pub register_id: f64,
+    dll_name: String,
+    procedure: String,
+    type_text: String,
+}
+
+/// Lists the functions registered through Reg that have not been unregistered
+pub fn registered() -> Vec<Registered> {
+    REGISTERED.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Unregisters every function registered through Reg. This is called by
+/// xlauto::close_all, so that stale functions are not left pointing at the dll once it is
+/// unloaded.
+///
+/// xlfUnregister alone leaves the names in the function wizard, so as the Excel SDK
+/// suggests, each function is first registered again as hidden, then unregistered. Failures
+/// are logged with debug_print, and do not stop the remaining functions being unregistered.
+pub fn unregister_all() {
+    let registered: Vec<Registered> = REGISTERED.lock().unwrap_or_else(|e| e.into_inner()).drain(..).collect();
+    for function in registered {
+        unregister_function(function);
+    }
+}
+
+// Unregisters one function or command registered through Reg, in the same way as
+// unregister_all, returning false if there is none of that name
+pub(crate) fn unregister(name: &str) -> bool {
+    let function = {
+        let mut registered = REGISTERED.lock().unwrap_or_else(|e| e.into_inner());
+        match registered.iter().position(|r| r.name == name) {
+            Some(index) => registered.remove(index),
+            None => return false
+        }
+    };
+    unregister_function(function);
+    true
+}
+
+fn unregister_function(function: Registered) {
+    command::free(&function.name);
+
+    let mut opers = vec![
+        Variant::from_str(&function.dll_name),
+        Variant::from_str(&function.procedure),
+        Variant::from_str(&function.type_text),
+        Variant::from_str(&function.name),
+        Variant::missing(),
+        Variant::from_int(0)];      // type 0 means hidden
+    let register_id = match try_excel12(xlfRegister, &mut opers) {
+        Ok(ref result) => result.as_f64().unwrap_or(function.register_id),
+        Err(_) => function.register_id
+    };
+
+    match try_excel12(xlfUnregister, &mut [Variant::from_float(register_id)]) {
+        Ok(ref result) if result.as_bool() == Some(true) => {},
+        Ok(result) => debug_print(&format!("Failed to unregister {}: result = {}", function.name, result)),
+        Err(e) => debug_print(&format!("Failed to unregister {}: {}", function.name, e))
+    }
+}
+
+fn optional_str(text: &Option<String>) -> Variant {
+    match *text {
+        Some(ref text) => Variant::from_str(text),
+        None => Variant::missing()
+    }
+}
+
+impl Default for Reg {
+    fn default() -> Reg {
+        Reg::new()
+    }
+}
+
+// Excel's limits on what can be registered
+const MAX_TEXT: usize = 255;
+const MAX_ARGS: usize = 255;
+const MAX_ARG_HELP: usize = 245;
+
+/// The type of an argument to a registered function. Each corresponds to one of the type
+/// letters in the Excel SDK.
+///
+/// Our recommendation is to use Variant for most arguments, and to convert them with the
+/// methods on VariantRef, as then you have control over the coercion and error handling
+/// where the arguments are the wrong type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgType {
+    /// `A`: a boolean, passed as an i16 that is zero or one
+    Bool,
+    /// `B`: an f64
+    Double,
+    /// `I`: an i16
+    Short,
+    /// `J`: an i32
+    Int,
+    /// `L`: a pointer to an i16 boolean, which is null if the argument is missing
+    BoolPtr,
+    /// `E`: a pointer to an f64, which is null if the argument is missing
+    DoublePtr,
+    /// `M`: a pointer to an i16, which is null if the argument is missing
+    ShortPtr,
+    /// `N`: a pointer to an i32, which is null if the argument is missing
+    IntPtr,
+    /// `C%`: a null-terminated UTF-16 string
+    Str,
+    /// `D%`: a UTF-16 string prefixed by its length
+    CountedStr,
+    /// `K%`: an FP12 array of f64
+    Array,
+    /// `Q`: an XLOPER12, with any reference coerced to its values
+    Variant,
+    /// `U`: an XLOPER12, which may be a reference
+    Reference,
+    /// `X`: the handle of an asynchronous call. A function with this argument must return
+    /// ReturnType::Void. FunctionSpec::asynchronous adds one after the other arguments.
+    AsyncHandle,
+}
+
+impl ArgType {
+    /// The letters for this type in a type string
+    pub fn code(self) -> &'static str {
+        match self {
+            ArgType::Bool => "A",
+            ArgType::Double => "B",
+            ArgType::Short => "I",
+            ArgType::Int => "J",
+            ArgType::BoolPtr => "L",
+            ArgType::DoublePtr => "E",
+            ArgType::ShortPtr => "M",
+            ArgType::IntPtr => "N",
+            ArgType::Str => "C%",
+            ArgType::CountedStr => "D%",
+            ArgType::Array => "K%",
+            ArgType::Variant => "Q",
+            ArgType::Reference => "U",
+            ArgType::AsyncHandle => "X",
+        }
     }
 }
 
+/// The type returned by a registered function. Each corresponds to one of the type letters
+/// in the Excel SDK.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReturnType {
+    /// `A`: a boolean, returned as an i16 that is zero or one
+    Bool,
+    /// `B`: an f64
+    Double,
+    /// `I`: an i16
+    Short,
+    /// `J`: an i32
+    Int,
+    /// `C%`: a null-terminated UTF-16 string
+    Str,
+    /// `D%`: a UTF-16 string prefixed by its length
+    CountedStr,
+    /// `K%`: an FP12 array of f64
+    Array,
+    /// `Q`: an XLOPER12, such as one made by Variant::into_excel_return
+    Variant,
+    /// `U`: an XLOPER12, which may be a reference
+    Reference,
+    /// `>`: nothing. This is only for asynchronous functions, which return their result
+    /// through the handle passed as an ArgType::AsyncHandle argument.
+    Void,
+}
+
+impl ReturnType {
+    /// The letters for this type in a type string
+    pub fn code(self) -> &'static str {
+        match self {
+            ReturnType::Bool => "A",
+            ReturnType::Double => "B",
+            ReturnType::Short => "I",
+            ReturnType::Int => "J",
+            ReturnType::Str => "C%",
+            ReturnType::CountedStr => "D%",
+            ReturnType::Array => "K%",
+            ReturnType::Variant => "Q",
+            ReturnType::Reference => "U",
+            ReturnType::Void => ">",
+        }
+    }
+}
+
+/// A description of a function to register with Reg::register. This is built up a piece
+/// at a time, starting from the name the function has in Excel. Anything not given takes
+/// a default: the function returns a Variant, is exported under the same name, is shown
+/// in the function wizard, and has no arguments, category or help.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionSpec {
+    name: String,
+    procedure: Option<String>,
+    returns: ReturnType,
+    args: Vec<(String, ArgType, String)>,
+    category: String,
+    help: String,
+    help_topic: Option<String>,
+    macro_type: i32,
+    shortcut: Option<char>,
+    asynchronous: bool,
+    volatile: bool,
+    threadsafe: bool,
+    macro_equivalent: bool,
+    cluster_safe: bool,
+}
+
+impl FunctionSpec {
+    /// Starts the description of a function with the given name in Excel
+    pub fn new(name: &str) -> FunctionSpec {
+        FunctionSpec {
+            name: name.to_string(),
+            procedure: None,
+            returns: ReturnType::Variant,
+            args: Vec::new(),
+            category: String::new(),
+            help: String::new(),
+            help_topic: None,
+            macro_type: 1,
+            shortcut: None,
+            asynchronous: false,
+            volatile: false,
+            threadsafe: false,
+            macro_equivalent: false,
+            cluster_safe: false }
+    }
+
+    /// Sets the name the function is exported under, if it is not the name in Excel
+    pub fn procedure(mut self, procedure: &str) -> FunctionSpec {
+        self.procedure = Some(procedure.to_string());
+        self
+    }
+
+    /// Sets the return type
+    pub fn returns(mut self, returns: ReturnType) -> FunctionSpec {
+        self.returns = returns;
+        self
+    }
+
+    /// Adds an argument, with its name and help as shown in the function wizard
+    pub fn arg(mut self, name: &str, arg_type: ArgType, help: &str) -> FunctionSpec {
+        self.args.push((name.to_string(), arg_type, help.to_string()));
+        self
+    }
+
+    /// Sets the category, either a built-in one such as Information or your own choice
+    pub fn category(mut self, category: &str) -> FunctionSpec {
+        self.category = category.to_string();
+        self
+    }
+
+    /// Sets the short help description for the function wizard
+    pub fn help(mut self, help: &str) -> FunctionSpec {
+        self.help = help.to_string();
+        self
+    }
+
+    /// Sets the help topic, in the form "path!context" for a help file, or "url!0" for a
+    /// web page
+    pub fn help_topic(mut self, help_topic: &str) -> FunctionSpec {
+        self.help_topic = Some(help_topic.to_string());
+        self
+    }
+
+    /// Hides the function, so it does not appear in the function wizard. It can still be
+    /// called through its register ID, for example with Application.Run from VBA.
+    pub fn hidden(mut self) -> FunctionSpec {
+        self.macro_type = 0;
+        self
+    }
+
+    /// Makes the function asynchronous. It returns nothing, and takes the handle of the
+    /// call as an extra argument after the others, which is not shown in the function
+    /// wizard. See async_udf for how to write such a function.
+    pub fn asynchronous(mut self) -> FunctionSpec {
+        self.returns = ReturnType::Void;
+        self.asynchronous = true;
+        self
+    }
+
+    /// Marks the function as volatile, so it is called on every recalculation (`!`)
+    pub fn volatile(mut self) -> FunctionSpec {
+        self.volatile = true;
+        self
+    }
+
+    /// Marks the function as threadsafe, so Excel can call it from any of its calculation
+    /// threads (`$`)
+    pub fn threadsafe(mut self) -> FunctionSpec {
+        self.threadsafe = true;
+        self
+    }
+
+    /// Marks the function as equivalent to a macro sheet function, so it can read
+    /// uncalculated cells and call information functions (`#`). Such a function cannot
+    /// also be threadsafe or cluster safe.
+    pub fn macro_equivalent(mut self) -> FunctionSpec {
+        self.macro_equivalent = true;
+        self
+    }
+
+    /// Marks the function as safe to run on a compute cluster (`&`)
+    pub fn cluster_safe(mut self) -> FunctionSpec {
+        self.cluster_safe = true;
+        self
+    }
+
+    /// Returns the type string, such as "QQQ$", with the return type, then the argument
+    /// types, then the flags
+    pub fn type_text(&self) -> String {
+        let mut text = self.returns.code().to_string();
+        for &(_, arg_type, _) in self.args.iter() {
+            text.push_str(arg_type.code());
+        }
+        if self.asynchronous {
+            text.push_str(ArgType::AsyncHandle.code());
+        }
+        if self.volatile {
+            text.push('!');
+        }
+        if self.macro_equivalent {
+            text.push('#');
+        }
+        if self.threadsafe {
+            text.push('$');
+        }
+        if self.cluster_safe {
+            text.push('&');
+        }
+        text
+    }
+
+    /// Returns the names of the arguments, separated by commas
+    pub fn arg_text(&self) -> String {
+        self.args.iter().map(|a| a.0.as_str()).collect::<Vec<_>>().join(", ")
+    }
+
+    // The number of AsyncHandle arguments, including the one added by `asynchronous`
+    fn async_handles(&self) -> usize {
+        self.args.iter().filter(|a| a.1 == ArgType::AsyncHandle).count() + self.asynchronous as usize
+    }
+
+    /// Checks that Excel will accept this spec, without registering it. Reg::register
+    /// calls this first, so there is no need to call it separately.
+    pub fn validate(&self) -> Result<(), RegisterError> {
+        let invalid = |problem: String| Err(RegisterError::Invalid(self.name.clone(), problem));
+
+        let mut chars = self.name.chars();
+        match chars.next() {
+            Some(c) if c.is_alphabetic() || c == '_' => {},
+            _ => return invalid("the name must start with a letter or underscore".to_string())
+        }
+        if let Some(c) = chars.find(|&c| !(c.is_alphanumeric() || c == '_' || c == '.')) {
+            return invalid(format!("the name cannot contain '{}'", c));
+        }
+        if let Some(c) = self.shortcut.filter(|c| !c.is_ascii_alphabetic()) {
+            return invalid(format!("the shortcut must be a letter, not '{}'", c));
+        }
+        if self.args.len() > MAX_ARGS {
+            return invalid(format!("{} arguments is more than the {} Excel allows", self.args.len(), MAX_ARGS));
+        }
+        check_arg_help(&self.name, self.args.iter().map(|a| a.2.as_str()))?;
+        if let Some((name, _, _)) = self.args.iter().find(|a| a.0.is_empty() || a.0.contains(',')) {
+            return invalid(format!("argument name \"{}\" must be non-empty and without commas", name));
+        }
+
+        let mut texts = vec![
+            ("name", self.name.clone()),
+            ("procedure", self.procedure.clone().unwrap_or_default()),
+            ("type text", self.type_text()),
+            ("argument text", self.arg_text()),
+            ("category", self.category.clone()),
+            ("help topic", self.help_topic.clone().unwrap_or_default()),
+            ("help", self.help.clone())];
+        texts.extend(self.args.iter().map(|a| ("argument help", a.2.clone())));
+        check_texts(&self.name, texts)?;
+
+        if self.threadsafe && self.macro_equivalent {
+            return invalid("a function cannot be both threadsafe and macro equivalent".to_string());
+        }
+        if self.cluster_safe && self.macro_equivalent {
+            return invalid("a function cannot be both cluster safe and macro equivalent".to_string());
+        }
+        let handles = self.async_handles();
+        if handles > 1 {
+            return invalid("a function can only have one AsyncHandle argument".to_string());
+        }
+        if (handles == 1) != (self.returns == ReturnType::Void) {
+            return invalid("an asynchronous function must take an AsyncHandle and return Void".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Excel only has room for help on the first MAX_ARG_HELP arguments
+fn check_arg_help<'a, I: Iterator<Item = &'a str>>(name: &str, arg_help: I) -> Result<(), RegisterError> {
+    match arg_help.skip(MAX_ARG_HELP).position(|help| !help.is_empty()) {
+        Some(index) => Err(RegisterError::Invalid(name.to_string(), format!(
+            "argument {} has help, but Excel only shows help for the first {}", index + MAX_ARG_HELP + 1, MAX_ARG_HELP))),
+        None => Ok(())
+    }
+}
+
+// Checks that each of the texts passed to xlfRegister fits, described by what it is
+fn check_texts(name: &str, texts: Vec<(&str, String)>) -> Result<(), RegisterError> {
+    for (what, text) in texts {
+        let len = text.encode_utf16().count();
+        if len > MAX_TEXT {
+            return Err(RegisterError::Invalid(name.to_string(),
+                format!("the {} is {} characters long, but Excel allows at most {}", what, len, MAX_TEXT)));
+        }
+    }
+    Ok(())
+}
+
+/// The reason Reg::register or Reg::try_add failed. Each holds the name of the function concerned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegisterError {
+    /// The FunctionSpec would not be accepted by Excel, for the reason given
+    Invalid(String, String),
+    /// The call to xlfRegister failed
+    Failed(String, XlRetError),
+    /// Excel returned an error rather than a register ID, usually because the dll does not
+    /// export the procedure
+    Rejected(String, XlError),
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RegisterError::Invalid(ref name, ref problem) => write!(f, "cannot register {}: {}", name, problem),
+            RegisterError::Failed(ref name, ref err) => write!(f, "cannot register {}: {}", name, err),
+            RegisterError::Rejected(ref name, err) => write!(f, "cannot register {}: Excel returned {}", name, err),
+        }
+    }
+}
+
+impl error::Error for RegisterError {}
+
+/// Writes a diagnostic message. On Windows, this goes to OutputDebugString, so it can
+/// be seen in a debugger or a tool such as DebugView. Elsewhere, it goes to stderr.
+#[cfg(windows)]
 pub fn debug_print(message: &str) {
-    let cstr = CString::new(message).unwrap();
+    // OutputDebugStringA stops at a NUL, and CString cannot hold one
+    let cstr = CString::new(message.replace('\0', "\\0")).unwrap_or_default();
     unsafe { OutputDebugStringA(cstr.as_ptr()) };
 }
+
+/// Writes a diagnostic message. On Windows, this goes to OutputDebugString, so it can
+/// be seen in a debugger or a tool such as DebugView. Elsewhere, it goes to stderr.
+#[cfg(not(windows))]
+pub fn debug_print(message: &str) {
+    eprintln!("{}", message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::FakeExcel;
+
+    #[test]
+    fn register_spec() {
+        let excel = FakeExcel::new("C:\\addins\\spec.xll");
+        let _guard = excel.install();
+
+        let spec = FunctionSpec::new("specAdd")
+            .procedure("spec_add")
+            .returns(ReturnType::Double)
+            .arg("first", ArgType::Double, "the first number")
+            .arg("second", ArgType::DoublePtr, "the second number")
+            .category("Test")
+            .help("Adds two numbers")
+            .help_topic("https://example.com/add!0")
+            .volatile()
+            .threadsafe();
+        let id = Reg::new().register(&spec).unwrap();
+
+        let registration = excel.registration("specAdd").unwrap();
+        assert_eq!(registration.register_id, id);
+        assert_eq!(registration.procedure, "spec_add");
+        assert_eq!(registration.type_text, "BBE!$");
+        assert_eq!(registration.argument_text, "first, second");
+        assert_eq!(registration.help_topic, "https://example.com/add!0");
+        assert_eq!(registration.argument_help, vec!["the first number", "the second number"]);
+    }
+
+    #[test]
+    fn unregister_everything() {
+        let excel = FakeExcel::new("C:\\addins\\close.xll");
+        let _guard = excel.install();
+        unregister_all();       // anything left over by other tests
+
+        let reg = Reg::new();
+        reg.add("closeOne", "QQ", "x", "Test", "One", &[]);
+        reg.register(&FunctionSpec::new("closeTwo").arg("x", ArgType::Variant, "")).unwrap();
+        reg.add("closeOne", "QQQ", "x, y", "Test", "One again", &[]);
+        let names: Vec<String> = registered().into_iter().map(|r| r.name).collect();
+        assert_eq!(names, vec!["closeTwo", "closeOne"]);
+        assert_eq!(registered()[1].register_id, excel.registration("closeOne").unwrap().register_id);
+
+        // try_add checks what add would only log
+        let long = "x".repeat(256);
+        assert!(matches!(reg.try_add("closeThree", "QQ", "x", "Test", &long, &[]), Err(RegisterError::Invalid(..))));
+        assert!(reg.try_add("closeThree", "QQ", "x", "Test", "Three", &["x"]).is_ok());
+        assert_eq!(registered().len(), 3);
+
+        unregister_all();
+        assert!(registered().is_empty());
+        assert!(excel.registrations().is_empty());
+    }
+
+    #[test]
+    fn commands_and_hidden_functions() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let excel = FakeExcel::new("C:\\addins\\commands.xll");
+        let _guard = excel.install();
+        unregister_all();
+
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let reg = Reg::new();
+        reg.add_command("cmdCount", Some('K'), || { RUNS.fetch_add(1, Ordering::SeqCst); }).unwrap();
+        reg.add_command("cmdPanic", None, || panic!("command failed")).unwrap();
+        reg.register(&FunctionSpec::new("hiddenHelper").hidden()).unwrap();
+
+        let count = excel.registration("cmdCount").unwrap();
+        assert_eq!((count.macro_type, count.type_text.as_str(), count.shortcut_text.as_str()), (2, "J", "K"));
+        assert!(count.procedure.starts_with("xladd_command_"));
+        assert_eq!(excel.registration("hiddenHelper").unwrap().macro_type, 0);
+
+        assert_eq!(excel.run("cmdCount"), 1);
+        assert_eq!(excel.run("cmdCount"), 1);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 2);
+        assert_eq!(excel.run("cmdPanic"), 0);
+
+        match reg.add_command("cmdBad", Some('1'), || {}) {
+            Err(RegisterError::Invalid(_, problem)) => assert!(problem.contains("shortcut")),
+            other => panic!("expected an invalid shortcut, got {:?}", other)
+        }
+
+        unregister_all();
+        assert_eq!(excel.run("cmdCount"), 0);
+        assert_eq!(command::trampoline(&count.procedure).map(|f| f()), Some(0));
+    }
+
+    #[test]
+    fn invalid_specs() {
+        let problem = |spec: FunctionSpec| match spec.validate() {
+            Err(RegisterError::Invalid(_, problem)) => problem,
+            other => panic!("expected an invalid spec, got {:?}", other)
+        };
+
+        assert!(FunctionSpec::new("ok.name_1").arg("x", ArgType::Variant, "").validate().is_ok());
+        assert!(problem(FunctionSpec::new("1st")).contains("start with"));
+        assert!(problem(FunctionSpec::new("a b")).contains("' '"));
+        assert!(problem(FunctionSpec::new("f").threadsafe().macro_equivalent()).contains("threadsafe"));
+        assert!(problem(FunctionSpec::new("f").cluster_safe().macro_equivalent()).contains("cluster"));
+        assert!(problem(FunctionSpec::new("f").returns(ReturnType::Void)).contains("AsyncHandle"));
+        assert!(problem(FunctionSpec::new("f").arg("h", ArgType::AsyncHandle, "")).contains("AsyncHandle"));
+        assert!(problem(FunctionSpec::new("f").help(&"x".repeat(256))).contains("the help is 256"));
+
+        let many = (0..256).fold(FunctionSpec::new("f"), |spec, i| spec.arg(&format!("a{}", i), ArgType::Int, ""));
+        assert!(problem(many).contains("256 arguments"));
+        let helped = (0..246).fold(FunctionSpec::new("f"), |spec, i| spec.arg(&format!("a{}", i), ArgType::Int, "help"));
+        assert!(problem(helped).contains("argument 246 has help"));
+        let long = (0..60).fold(FunctionSpec::new("f"), |spec, i| spec.arg(&format!("arg{}", i), ArgType::Int, ""));
+        assert!(problem(long).contains("argument text"));
+    }
+}
@@ -0,0 +1,571 @@
+//! A cache of objects shared between all the xladd-based xlls loaded in an Excel process,
+//! so that, for example, a curve built by one xll can be priced against by another. The
+//! cache module is private to each xll, as each has its own statics. This module instead
+//! uses a registry that every xladd xll exports through a function named
+//! `xladd_shared_registry`. Each xll looks through the modules loaded in the process, in
+//! the order they were loaded, and uses the registry of the first one that exports it, so
+//! they all end up using the same registry.
+//!
+//! An object can only be shared if its type implements SharedType, which gives it a name
+//! that is stable between xlls, such as "acme.Curve", and a layout version. The xll that
+//! looks an object up must ask for the same name and version that the xll that stored it
+//! used, or the lookup is refused with CacheError::Incompatible. Bump the version whenever
+//! the type changes, so that an xll built against the old definition cannot misread it.
+//!
+//! ```
+//! # use xladd::shared::{self, SharedType};
+//! #[repr(C)]
+//! struct Curve { rate: f64 }
+//!
+//! unsafe impl SharedType for Curve {
+//!     const TYPE_NAME: &'static str = "acme.Curve";
+//!     const LAYOUT_VERSION: u32 = 1;
+//! }
+//!
+//! // in the xll that builds curves
+//! let handle = shared::store(Curve { rate: 0.05 });
+//!
+//! // in the xll that prices against them
+//! let curve = shared::get::<Curve>(&handle).unwrap();
+//! assert_eq!(curve.rate, 0.05);
+//! ```
+//!
+//! An object is dropped by the xll that stored it, when it has been removed and nothing
+//! else holds it. The objects an xll stores are removed when it closes, by
+//! xlauto::close_all, after which looking them up is refused with CacheError::NotFound.
+//!
+//! Other xlls call into the xll that holds the registry, and into the xll that stored an
+//! object whenever they clone or drop it, so neither may be unloaded while that can still
+//! happen. An xll that uses the registry of another therefore pins that xll in memory, and
+//! an xll that closes while others still hold its objects pins itself, so that it stays
+//! loaded until Excel exits. This only costs the memory of the dll, as everything else it
+//! did is undone when it closes.
+
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::os::raw::c_void;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::{fmt, ptr, slice, str};
+use cache::CacheError;
+use registrator::debug_print;
+#[cfg(windows)]
+use std::mem;
+#[cfg(windows)]
+use std::ffi::CStr;
+#[cfg(windows)]
+use winapi::shared::minwindef::{DWORD, HMODULE};
+#[cfg(windows)]
+use winapi::um::libloaderapi::{GetModuleHandleExW, GetProcAddress, GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
+    GET_MODULE_HANDLE_EX_FLAG_PIN};
+#[cfg(windows)]
+use winapi::um::winnt::LPCWSTR;
+#[cfg(windows)]
+use winapi::um::processthreadsapi::GetCurrentProcess;
+#[cfg(windows)]
+use winapi::um::psapi::EnumProcessModules;
+
+/// A type whose objects can be shared between xlls. The name must be the same in every
+/// xll that shares the type, and should be qualified to avoid clashes, such as
+/// "acme.Curve".
+///
+/// # Safety
+///
+/// This is unsafe to implement, because the xll that looks an object up reads it with its
+/// own definition of the type. The implementer promises that every definition with the same
+/// name and layout version has the same layout, for example because the type is `#[repr(C)]`
+/// with the same fields. Types such as String and Vec have layouts that may differ between
+/// compiler versions, so they should only be shared between xlls built together.
+pub unsafe trait SharedType: Send + Sync + 'static {
+    /// The name of the type, which is the same in every xll that shares it
+    const TYPE_NAME: &'static str;
+    /// The version of the layout of the type, which changes whenever the type does
+    const LAYOUT_VERSION: u32;
+}
+
+// The version of RegistryAbi and SharedObject. A registry with a different version is
+// ignored.
+const ABI_VERSION: u32 = 1;
+#[cfg(windows)]
+const EXPORT_NAME: &[u8] = b"xladd_shared_registry\0";
+
+// The results of RegistryAbi::get
+const FOUND: u32 = 0;
+const NOT_FOUND: u32 = 1;
+const WRONG_TYPE: u32 = 2;
+const INCOMPATIBLE: u32 = 3;
+
+// The table of functions through which xlls use a registry. Everything that crosses
+// between xlls is repr(C), and objects are only ever retained, released and dropped by
+// code in the xll that stored them.
+#[repr(C)]
+struct RegistryAbi {
+    abi_version: u32,
+    store: unsafe extern "C" fn(object: *const SharedObject) -> u64,
+    get: unsafe extern "C" fn(id: u64, type_name: *const u8, type_name_len: usize,
+        layout_version: u32, out: *mut SharedObject) -> u32,
+    remove: unsafe extern "C" fn(id: u64) -> bool,
+    remove_owned: unsafe extern "C" fn(owner: usize) -> usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SharedObject {
+    type_name: *const u8,
+    type_name_len: usize,
+    layout_version: u32,
+    owner: usize,
+    pointer: *const c_void,
+    retain: unsafe extern "C" fn(*const c_void),
+    release: unsafe extern "C" fn(*const c_void),
+}
+
+// What the registry keeps for each object. The pointer is kept as a number, so that the
+// registry can be shared between threads.
+struct Entry {
+    type_name: String,
+    layout_version: u32,
+    owner: usize,
+    pointer: usize,
+    retain: unsafe extern "C" fn(*const c_void),
+    release: unsafe extern "C" fn(*const c_void),
+}
+
+static LOCAL_REGISTRY: RegistryAbi = RegistryAbi {
+    abi_version: ABI_VERSION,
+    store: local_store,
+    get: local_get,
+    remove: local_remove,
+    remove_owned: local_remove_owned,
+};
+static ENTRIES: Mutex<BTreeMap<u64, Entry>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static REGISTRY: OnceLock<usize> = OnceLock::new();
+
+// Its address identifies this xll as the owner of the objects it stores
+static OWNER: u8 = 0;
+
+// The number of objects stored by this xll that are still alive, in the registry or held
+// by anyone, and whether this xll has had to pin itself because some outlived it
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PINNED: AtomicBool = AtomicBool::new(false);
+
+// Wraps every object this xll stores, so that it can count them. It is repr(C) with the
+// value first, so a pointer to it is also a pointer to the value, which is all that other
+// xlls see.
+#[repr(C)]
+struct Stored<T> {
+    value: T,
+}
+
+impl<T> Drop for Stored<T> {
+    fn drop(&mut self) {
+        LIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Returns the registry of this xll, for other xlls to find. This is not for calling
+/// directly.
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn xladd_shared_registry() -> *const c_void {
+    &LOCAL_REGISTRY as *const RegistryAbi as *const c_void
+}
+
+/// A shared object, as returned by `get`. This holds the object alive, even if it is
+/// removed from the registry, until it is dropped.
+pub struct Shared<T: SharedType> {
+    pointer: *const T,
+    retain: unsafe extern "C" fn(*const c_void),
+    release: unsafe extern "C" fn(*const c_void),
+    _marker: PhantomData<T>,
+}
+
+// The object is T, which is Send and Sync, and its count is atomic, as it is an Arc
+unsafe impl<T: SharedType> Send for Shared<T> {}
+unsafe impl<T: SharedType> Sync for Shared<T> {}
+
+impl<T: SharedType> Deref for Shared<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.pointer }
+    }
+}
+
+impl<T: SharedType> Clone for Shared<T> {
+    fn clone(&self) -> Shared<T> {
+        unsafe { (self.retain)(self.pointer as *const c_void) };
+        Shared { pointer: self.pointer, retain: self.retain, release: self.release, _marker: PhantomData }
+    }
+}
+
+impl<T: SharedType> Drop for Shared<T> {
+    fn drop(&mut self) {
+        unsafe { (self.release)(self.pointer as *const c_void) };
+    }
+}
+
+impl<T: SharedType + fmt::Debug> fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Stores an object in the shared registry, returning a handle to it such as
+/// "acme.Curve:42", which any xladd xll in the process can look up
+pub fn store<T: SharedType>(value: T) -> String {
+    LIVE.fetch_add(1, Ordering::SeqCst);
+    let object = SharedObject {
+        type_name: T::TYPE_NAME.as_ptr(),
+        type_name_len: T::TYPE_NAME.len(),
+        layout_version: T::LAYOUT_VERSION,
+        owner: owner(),
+        pointer: Arc::into_raw(Arc::new(Stored { value })) as *const c_void,
+        retain: retain::<Stored<T>>,
+        release: release::<Stored<T>> };
+    let id = unsafe { (registry().store)(&object) };
+    format!("{}:{}", T::TYPE_NAME, id)
+}
+
+/// Looks up an object in the shared registry, which must be a T, with the same layout
+/// version as the xll that stored it
+pub fn get<T: SharedType>(handle: &str) -> Result<Shared<T>, CacheError> {
+    let (type_name, id) = parse(handle)?;
+    if type_name != T::TYPE_NAME {
+        return Err(CacheError::WrongType { handle: handle.to_string(), expected: T::TYPE_NAME })
+    }
+
+    let mut object = SharedObject {
+        type_name: T::TYPE_NAME.as_ptr(),
+        type_name_len: T::TYPE_NAME.len(),
+        layout_version: T::LAYOUT_VERSION,
+        owner: 0,
+        pointer: ptr::null(),
+        retain: retain::<T>,
+        release: release::<T> };
+    let status = unsafe {
+        (registry().get)(id, T::TYPE_NAME.as_ptr(), T::TYPE_NAME.len(), T::LAYOUT_VERSION, &mut object)
+    };
+    match status {
+        FOUND => Ok(Shared {
+            pointer: object.pointer as *const T,
+            retain: object.retain,
+            release: object.release,
+            _marker: PhantomData }),
+        WRONG_TYPE => Err(CacheError::WrongType { handle: handle.to_string(), expected: T::TYPE_NAME }),
+        INCOMPATIBLE => Err(CacheError::Incompatible {
+            handle: handle.to_string(), expected: T::LAYOUT_VERSION, found: object.layout_version }),
+        _ => Err(CacheError::NotFound(handle.to_string()))
+    }
+}
+
+/// Removes an object from the shared registry, returning whether there was one. Anything
+/// that still holds it keeps it alive until it is dropped.
+pub fn remove(handle: &str) -> bool {
+    match parse(handle) {
+        Ok((_, id)) => unsafe { (registry().remove)(id) },
+        Err(_) => false
+    }
+}
+
+/// Removes every object this xll stored in the shared registry, returning how many there
+/// were. This is called by xlauto::close_all, as the objects cannot be dropped once this
+/// xll is unloaded. If other xlls still hold some of them, this xll is pinned in memory,
+/// so that they can still be dropped.
+pub fn remove_owned() -> usize {
+    let removed = unsafe { (registry().remove_owned)(owner()) };
+    let live = LIVE.load(Ordering::SeqCst);
+    if live > 0 && !PINNED.swap(true, Ordering::SeqCst) {
+        debug_print(&format!("{} shared objects are still held, so this xll will stay loaded", live));
+        pin_module(&OWNER as *const u8 as *const c_void);
+    }
+    removed
+}
+
+fn owner() -> usize {
+    &OWNER as *const u8 as usize
+}
+
+fn parse(handle: &str) -> Result<(&str, u64), CacheError> {
+    handle.rsplit_once(':')
+        .and_then(|(type_name, id)| id.parse::<u64>().ok().map(|id| (type_name, id)))
+        .ok_or_else(|| CacheError::NotAHandle(handle.to_string()))
+}
+
+unsafe extern "C" fn retain<T>(pointer: *const c_void) {
+    Arc::increment_strong_count(pointer as *const T);
+}
+
+unsafe extern "C" fn release<T>(pointer: *const c_void) {
+    Arc::decrement_strong_count(pointer as *const T);
+}
+
+// The registry this xll uses. One found in another xll is pinned there, as we keep
+// calling into it.
+fn registry() -> &'static RegistryAbi {
+    let registry = *REGISTRY.get_or_init(|| {
+        let registry = find_registry().unwrap_or(&LOCAL_REGISTRY) as *const RegistryAbi;
+        if !ptr::eq(registry, &LOCAL_REGISTRY) {
+            pin_module(registry as *const c_void);
+        }
+        registry as usize
+    });
+    unsafe { &*(registry as *const RegistryAbi) }
+}
+
+// Keeps the module containing the address loaded until the process exits
+#[cfg(windows)]
+fn pin_module(address: *const c_void) {
+    let mut module: HMODULE = ptr::null_mut();
+    let flags = GET_MODULE_HANDLE_EX_FLAG_PIN | GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS;
+    if unsafe { GetModuleHandleExW(flags, address as LPCWSTR, &mut module) } == 0 {
+        debug_print("failed to pin a module that shares objects");
+    }
+}
+
+// Elsewhere modules are never unloaded under us
+#[cfg(not(windows))]
+fn pin_module(_address: *const c_void) {
+}
+
+// Finds the registry of the first module in the process that exports one with our
+// version of the ABI. Modules are listed in the order they were loaded, so every xll
+// finds the same one.
+#[cfg(windows)]
+fn find_registry() -> Option<&'static RegistryAbi> {
+    let name = CStr::from_bytes_with_nul(EXPORT_NAME).unwrap();
+    let mut modules: Vec<HMODULE> = vec![ptr::null_mut(); 256];
+    unsafe {
+        loop {
+            let size = (modules.len() * mem::size_of::<HMODULE>()) as DWORD;
+            let mut needed: DWORD = 0;
+            if EnumProcessModules(GetCurrentProcess(), modules.as_mut_ptr(), size, &mut needed) == 0 {
+                return None
+            }
+            let count = needed as usize / mem::size_of::<HMODULE>();
+            if count <= modules.len() {
+                modules.truncate(count);
+                break
+            }
+            modules = vec![ptr::null_mut(); count];
+        }
+
+        for module in modules {
+            let export = GetProcAddress(module, name.as_ptr());
+            if export.is_null() {
+                continue
+            }
+            let export = mem::transmute::<_, extern "C" fn() -> *const c_void>(export);
+            let registry = export() as *const RegistryAbi;
+            if !registry.is_null() && (*registry).abi_version == ABI_VERSION {
+                return Some(&*registry)
+            }
+        }
+    }
+    None
+}
+
+// Elsewhere there is only ever one xll in the process, so we use our own registry
+#[cfg(not(windows))]
+fn find_registry() -> Option<&'static RegistryAbi> {
+    None
+}
+
+fn entries() -> ::std::sync::MutexGuard<'static, BTreeMap<u64, Entry>> {
+    ENTRIES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+unsafe extern "C" fn local_store(object: *const SharedObject) -> u64 {
+    let object = &*object;
+    let type_name = str::from_utf8(slice::from_raw_parts(object.type_name, object.type_name_len))
+        .unwrap_or_default();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    entries().insert(id, Entry {
+        type_name: type_name.to_string(),
+        layout_version: object.layout_version,
+        owner: object.owner,
+        pointer: object.pointer as usize,
+        retain: object.retain,
+        release: object.release });
+    id
+}
+
+unsafe extern "C" fn local_get(id: u64, type_name: *const u8, type_name_len: usize,
+    layout_version: u32, out: *mut SharedObject) -> u32 {
+
+    let type_name = slice::from_raw_parts(type_name, type_name_len);
+    let entries = entries();
+    let entry = match entries.get(&id) {
+        Some(entry) => entry,
+        None => return NOT_FOUND
+    };
+    if entry.type_name.as_bytes() != type_name {
+        return WRONG_TYPE
+    }
+    (*out).layout_version = entry.layout_version;
+    if entry.layout_version != layout_version {
+        return INCOMPATIBLE
+    }
+
+    // retain under the lock, so the object cannot be released in between
+    (entry.retain)(entry.pointer as *const c_void);
+    (*out).pointer = entry.pointer as *const c_void;
+    (*out).retain = entry.retain;
+    (*out).release = entry.release;
+    FOUND
+}
+
+unsafe extern "C" fn local_remove(id: u64) -> bool {
+    // release outside the lock, as dropping the object may use the registry
+    let removed = entries().remove(&id);
+    match removed {
+        Some(entry) => {
+            (entry.release)(entry.pointer as *const c_void);
+            true
+        },
+        None => false
+    }
+}
+
+unsafe extern "C" fn local_remove_owned(owner: usize) -> usize {
+    let removed: Vec<Entry> = {
+        let mut entries = entries();
+        let ids: Vec<u64> = entries.iter().filter(|(_, e)| e.owner == owner).map(|(&id, _)| id).collect();
+        ids.into_iter().filter_map(|id| entries.remove(&id)).collect()
+    };
+    for entry in removed.iter() {
+        (entry.release)(entry.pointer as *const c_void);
+    }
+    removed.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    // Both tests close this xll, which removes the other's objects, so they take turns
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    #[repr(C)]
+    struct Curve(f64);
+
+    impl Drop for Curve {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    unsafe impl SharedType for Curve {
+        const TYPE_NAME: &'static str = "test.Curve";
+        const LAYOUT_VERSION: u32 = 1;
+    }
+
+    // The same type, as another xll built against a later definition might see it
+    #[repr(C)]
+    struct NewCurve(f64, f64);
+
+    unsafe impl SharedType for NewCurve {
+        const TYPE_NAME: &'static str = "test.Curve";
+        const LAYOUT_VERSION: u32 = 2;
+    }
+
+    #[repr(C)]
+    struct Trade;
+
+    unsafe impl SharedType for Trade {
+        const TYPE_NAME: &'static str = "test.Trade";
+        const LAYOUT_VERSION: u32 = 1;
+    }
+
+    #[test]
+    fn share_objects() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let dropped = DROPPED.load(Ordering::SeqCst);
+        let handle = store(Curve(0.05));
+        assert!(handle.starts_with("test.Curve:"));
+        let curve = get::<Curve>(&handle).unwrap();
+        let copy = curve.clone();
+        assert_eq!(copy.0, 0.05);
+
+        assert_eq!(get::<NewCurve>(&handle).err(), Some(CacheError::Incompatible {
+            handle: handle.clone(), expected: 2, found: 1 }));
+        assert!(matches!(get::<Trade>(&handle), Err(CacheError::WrongType { .. })));
+        let disguised = handle.replace("test.Curve", "test.Trade");
+        assert!(matches!(get::<Trade>(&disguised), Err(CacheError::WrongType { .. })));
+
+        // removing leaves the object alive until the last holder drops it
+        assert!(remove(&handle));
+        assert!(!remove(&handle));
+        assert!(matches!(get::<Curve>(&handle), Err(CacheError::NotFound(_))));
+        drop(curve);
+        assert_eq!(DROPPED.load(Ordering::SeqCst), dropped);
+        drop(copy);
+        assert_eq!(DROPPED.load(Ordering::SeqCst), dropped + 1);
+
+        let trade = store(Trade);
+        assert!(remove_owned() >= 1);
+        assert!(get::<Trade>(&trade).is_err());
+
+        let registry = xladd_shared_registry() as *const RegistryAbi;
+        assert_eq!(unsafe { (*registry).abi_version }, ABI_VERSION);
+    }
+
+    // Another xll, which finds this one's registry and stores a trade through it
+    static OTHER_XLL: u8 = 0;
+
+    fn other_store() -> u64 {
+        let object = SharedObject {
+            type_name: Trade::TYPE_NAME.as_ptr(),
+            type_name_len: Trade::TYPE_NAME.len(),
+            layout_version: 1,
+            owner: &OTHER_XLL as *const u8 as usize,
+            pointer: Arc::into_raw(Arc::new(Trade)) as *const c_void,
+            retain: retain::<Trade>,
+            release: release::<Trade> };
+        unsafe { (registry().store)(&object) }
+    }
+
+    fn other_get(id: u64, type_name: &str) -> u32 {
+        let mut object = SharedObject {
+            type_name: type_name.as_ptr(),
+            type_name_len: type_name.len(),
+            layout_version: 1,
+            owner: 0,
+            pointer: ptr::null(),
+            retain: retain::<Trade>,
+            release: release::<Trade> };
+        let status = unsafe { (registry().get)(id, type_name.as_ptr(), type_name.len(), 1, &mut object) };
+        if status == FOUND {
+            unsafe { (object.release)(object.pointer) };
+        }
+        status
+    }
+
+    #[test]
+    fn host_closes() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let theirs = other_store();
+        let ours = store(Curve(1.0));
+        let (_, our_id) = parse(&ours).unwrap();
+        let held = get::<Curve>(&ours).unwrap();
+
+        // this xll, which holds the registry, closes while its curve is still held, so it
+        // stays loaded rather than leaving the holder to call into unmapped code
+        assert!(remove_owned() >= 1);
+        assert!(PINNED.load(Ordering::SeqCst));
+
+        // the other xll can still use the registry, and is refused the closed xll's curve
+        assert_eq!(other_get(theirs, Trade::TYPE_NAME), FOUND);
+        assert_eq!(other_get(our_id, Curve::TYPE_NAME), NOT_FOUND);
+        assert!(matches!(get::<Curve>(&ours), Err(CacheError::NotFound(_))));
+        assert_eq!(held.0, 1.0);
+        drop(held);
+
+        assert_eq!(unsafe { (registry().remove_owned)(&OTHER_XLL as *const u8 as usize) }, 1);
+        assert_eq!(other_get(theirs, Trade::TYPE_NAME), NOT_FOUND);
+    }
+}
@@ -0,0 +1,904 @@
+//! An in-process stand-in for Excel, so that addins can be tested without Excel, for
+//! example under `cargo test` on Linux. FakeExcel is an ExcelBackend that behaves like a
+//! small Excel. It keeps a table of the functions registered with xlfRegister, knows the
+//! path of the dll, has a toy grid of cells that references can be coerced from, and can
+//! invoke registered functions by name, freeing their results through xlAutoFree12.
+//!
+//! A test typically creates a FakeExcel, tells it about the functions the addin exports,
+//! installs it, then runs the addin's xlAutoOpen code and calls the functions:
+//!
+//! ```
+//! # use xladd::testing::FakeExcel;
+//! # use xladd::registrator::Reg;
+//! # use xladd::variant::Variant;
+//! # use xladd::variant_ref::VariantRef;
+//! # use xladd::xlcall::LPXLOPER12;
+//! extern "system" fn my_double(arg: VariantRef) -> LPXLOPER12 {
+//!     let result = Variant::from_float(arg.as_f64().unwrap_or(0.0) * 2.0);
+//!     result.into_excel_return()
+//! }
+//!
+//! let excel = FakeExcel::new("C:\\addins\\mine.xll");
+//! excel.export("my_double", my_double as extern "system" fn(VariantRef) -> LPXLOPER12);
+//! let _guard = excel.install();
+//!
+//! Reg::new().add("my_double", "QQ", "value", "Test", "Doubles a number", &[]);
+//! assert_eq!(excel.call("my_double", &[Variant::from_float(2.5)]).as_f64(), Some(5.0));
+//! ```
+
+#![allow(non_upper_case_globals)]
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use command;
+use entrypoint::{ExcelBackend, set_backend, clear_backend};
+use keys;
+use variant::Variant;
+use variant_ref::VariantRef;
+use xlerror::XlError;
+use xlauto::xlAutoFree12;
+use xlcall::{XLOPER12, LPXLOPER12, XLREF12, XLMREF12, IDSHEET, xloper12__bindgen_ty_1,
+    xloper12__bindgen_ty_1__bindgen_ty_1, xloper12__bindgen_ty_1__bindgen_ty_2, xltypeStr, xltypeMulti, xltypeRef,
+    xloper12__bindgen_ty_1__bindgen_ty_5, xloper12__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1,
+    xltypeSRef, xltypeNum, xltypeBigData, xlbitDLLFree, xlbitXLFree,
+    xlretSuccess, xlretFailed, xlretInvXlfn, xlretInvCount, xlretInvAsynchronousContext,
+    xlGetName, xlfRegister, xlfUnregister, xlCoerce, xlfCaller, xlSheetNm, xlSheetId, xlFree,
+    xlAsyncReturn, xlEventRegister, xlfNow, xlcOnTime, xlcOnKey};
+
+// Only one backend can be installed at a time, as the backend is process-wide. Tests run
+// in parallel, so installing takes this lock, which serializes them.
+static INSTALL_LOCK: Mutex<()> = Mutex::new(());
+
+/// Installs any backend, such as a closure, for the duration of a test. The backend is
+/// removed when the returned guard is dropped. While the guard exists, no other backend
+/// can be installed through this function or FakeExcel::install, so tests that use them
+/// run one at a time.
+pub fn install<B: ExcelBackend + 'static>(backend: B) -> BackendGuard {
+    let lock = INSTALL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    set_backend(backend);
+    BackendGuard { _lock: lock }
+}
+
+/// A function that has been registered with the FakeExcel, as described by the arguments
+/// to xlfRegister.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Registration {
+    pub register_id: f64,
+    pub dll_name: String,
+    pub procedure: String,
+    pub type_text: String,
+    pub function_text: String,
+    pub argument_text: String,
+    pub macro_type: i32,
+    pub category: String,
+    pub shortcut_text: String,
+    pub help_topic: String,
+    pub function_help: String,
+    pub argument_help: Vec<String>,
+}
+
+/// A function exported by the addin under test, which FakeExcel can invoke. This is
+/// implemented for `extern "system"` functions taking up to eight LPXLOPER12 arguments,
+/// or up to eight VariantRef arguments, and returning LPXLOPER12, which is what xladd
+/// functions registered with Q arguments look like. It is also implemented for functions
+/// taking VariantRef arguments and returning nothing, which is what asynchronous
+/// functions look like.
+pub trait Export: Send + Sync {
+    /// The number of arguments the function takes
+    fn arity(&self) -> usize;
+
+    /// Invokes the function. There are always exactly `arity` arguments.
+    fn invoke(&self, args: &[LPXLOPER12]) -> LPXLOPER12;
+}
+
+macro_rules! xloper_arg {
+    ($a:ident) => { LPXLOPER12 }
+}
+
+macro_rules! impl_export {
+    ($($a:ident)*) => {
+        impl Export for extern "system" fn($(xloper_arg!($a)),*) -> LPXLOPER12 {
+            fn arity(&self) -> usize {
+                <[&str]>::len(&[$(stringify!($a)),*])
+            }
+
+            #[allow(unused_variables, unused_mut)]
+            fn invoke(&self, args: &[LPXLOPER12]) -> LPXLOPER12 {
+                let mut iter = args.iter();
+                $(let $a = *iter.next().unwrap();)*
+                self($($a),*)
+            }
+        }
+    }
+}
+
+impl_export!();
+impl_export!(a);
+impl_export!(a b);
+impl_export!(a b c);
+impl_export!(a b c d);
+impl_export!(a b c d e);
+impl_export!(a b c d e f);
+impl_export!(a b c d e f g);
+impl_export!(a b c d e f g h);
+
+// Functions may also take their arguments as VariantRef, which Excel passes as LPXLOPER12
+macro_rules! view_arg {
+    ($a:ident) => { VariantRef }
+}
+
+macro_rules! impl_export_view {
+    ($($a:ident)*) => {
+        impl Export for extern "system" fn($(view_arg!($a)),*) -> LPXLOPER12 {
+            fn arity(&self) -> usize {
+                <[&str]>::len(&[$(stringify!($a)),*])
+            }
+
+            fn invoke(&self, args: &[LPXLOPER12]) -> LPXLOPER12 {
+                let mut iter = args.iter();
+                $(let $a = unsafe { VariantRef::from_raw(*iter.next().unwrap()) };)*
+                self($($a),*)
+            }
+        }
+    }
+}
+
+impl_export_view!(a);
+impl_export_view!(a b);
+impl_export_view!(a b c);
+impl_export_view!(a b c d);
+impl_export_view!(a b c d e);
+impl_export_view!(a b c d e f);
+impl_export_view!(a b c d e f g);
+impl_export_view!(a b c d e f g h);
+
+// Asynchronous functions return nothing, and take the handle as their last argument
+macro_rules! impl_export_async {
+    ($($a:ident)*) => {
+        impl Export for extern "system" fn($(view_arg!($a)),*) {
+            fn arity(&self) -> usize {
+                <[&str]>::len(&[$(stringify!($a)),*])
+            }
+
+            fn invoke(&self, args: &[LPXLOPER12]) -> LPXLOPER12 {
+                let mut iter = args.iter();
+                $(let $a = unsafe { VariantRef::from_raw(*iter.next().unwrap()) };)*
+                self($($a),*);
+                ::std::ptr::null_mut()
+            }
+        }
+    }
+}
+
+impl_export_async!(a);
+impl_export_async!(a b);
+impl_export_async!(a b c);
+impl_export_async!(a b c d);
+impl_export_async!(a b c d e);
+impl_export_async!(a b c d e f);
+impl_export_async!(a b c d e f g);
+impl_export_async!(a b c d e f g h);
+
+/// A small in-process Excel. Cloning a FakeExcel gives another handle to the same state,
+/// so a test can keep one handle to inspect while another is installed as the backend.
+#[derive(Clone)]
+pub struct FakeExcel {
+    state: Arc<Mutex<State>>,
+    // notified whenever an asynchronous function returns its result
+    returned: Arc<Condvar>,
+}
+
+struct State {
+    dll_path: String,
+    next_register_id: f64,
+    registrations: Vec<Registration>,
+    exports: HashMap<String, Arc<dyn Export>>,
+    // indexed by sheet ID less one, with None for sheets that have been removed
+    sheets: Vec<Option<String>>,
+    cells: HashMap<(usize, i32, i32), Variant>,
+    caller: Option<(usize, XLREF12)>,
+    caller_sref: bool,
+    next_async_handle: usize,
+    async_results: HashMap<usize, Variant>,
+    events: Vec<(i32, String)>,
+    // the serial of the time, and the commands waiting on xlcOnTime
+    now: f64,
+    on_time: Vec<(f64, String)>,
+    // the command bound to each key with xlcOnKey
+    keys: HashMap<String, String>,
+
+    // Results that we have handed out with xlbitXLFree set, keyed by the address of their
+    // data, waiting for a call to xlFree.
+    unfreed: HashMap<usize, Unfreed>,
+}
+
+// The contents are never read. They are only held so they can be dropped when freed.
+#[allow(dead_code)]
+enum Unfreed {
+    Value(Variant),
+    Ref(Box<XLMREF12>),
+}
+
+/// Keeps a backend installed for as long as it lives. See `install`.
+pub struct BackendGuard {
+    _lock: MutexGuard<'static, ()>
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        clear_backend();
+    }
+}
+
+// The time a FakeExcel starts at, which is midday on 2024-01-01
+const START_TIME: f64 = 45292.5;
+
+impl FakeExcel {
+    /// Creates a FakeExcel that reports the given path in response to xlGetName. It starts
+    /// with a single sheet, named "[Book1]Sheet1".
+    pub fn new(dll_path: &str) -> FakeExcel {
+        FakeExcel {
+            state: Arc::new(Mutex::new(State {
+                dll_path: dll_path.to_string(),
+                next_register_id: 1.0,
+                registrations: Vec::new(),
+                exports: HashMap::new(),
+                sheets: vec![Some("[Book1]Sheet1".to_string())],
+                cells: HashMap::new(),
+                caller: None,
+                caller_sref: false,
+                next_async_handle: 1,
+                async_results: HashMap::new(),
+                events: Vec::new(),
+                now: START_TIME,
+                on_time: Vec::new(),
+                keys: HashMap::new(),
+                unfreed: HashMap::new() })),
+            returned: Arc::new(Condvar::new())
+        }
+    }
+
+    /// Installs this FakeExcel as the backend for all calls into Excel. The backend is
+    /// removed when the returned guard is dropped.
+    pub fn install(&self) -> BackendGuard {
+        install(self.clone())
+    }
+
+    /// Tells the FakeExcel about a function exported by the addin, so that it can be
+    /// invoked once it has been registered. The procedure name is the one passed to
+    /// xlfRegister, which is the name of the export.
+    pub fn export<E: Export + 'static>(&self, procedure: &str, function: E) {
+        self.lock().exports.insert(procedure.to_string(), Arc::new(function));
+    }
+
+    /// Adds a sheet, returning its ID. The name should be in the form "[Book]Sheet".
+    pub fn add_sheet(&self, name: &str) -> IDSHEET {
+        let mut state = self.lock();
+        state.sheets.push(Some(name.to_string()));
+        state.sheets.len() as IDSHEET
+    }
+
+    /// Returns the ID of the sheet with the given name, if there is one
+    pub fn sheet_id(&self, name: &str) -> Option<IDSHEET> {
+        self.lock().sheets.iter().position(|s| s.as_deref() == Some(name)).map(|i| (i + 1) as IDSHEET)
+    }
+
+    /// Removes a sheet, as if it were deleted or its workbook closed, along with its cells
+    pub fn remove_sheet(&self, sheet: IDSHEET) {
+        let mut state = self.lock();
+        if let Some(name) = state.sheets.get_mut((sheet as usize).wrapping_sub(1)) {
+            *name = None;
+        }
+        state.cells.retain(|&(s, _, _), _| s != sheet as usize);
+    }
+
+    /// Sets the value of a cell in the toy grid. Rows and columns are zero-based.
+    pub fn set_cell(&self, sheet: IDSHEET, row: i32, col: i32, value: Variant) {
+        self.lock().cells.insert((sheet as usize, row, col), value);
+    }
+
+    /// Creates a reference to a range of cells on the given sheet, which can be passed as an
+    /// argument to a function, and coerced to a value with xlCoerce.
+    pub fn reference(&self, sheet: IDSHEET, rows: (i32, i32), cols: (i32, i32)) -> Variant {
+        Variant::from_refs(sheet, &[XLREF12 { rwFirst: rows.0, rwLast: rows.1, colFirst: cols.0, colLast: cols.1 }])
+    }
+
+    /// Sets the cell returned by xlfCaller, as if the next function call were made from it.
+    /// Pass None for a call that is not from a cell, such as from VBA.
+    pub fn set_caller(&self, caller: Option<(IDSHEET, i32, i32)>) {
+        match caller {
+            Some((sheet, row, col)) => self.set_caller_area(sheet, (row, row), (col, col)),
+            None => self.lock().caller = None
+        }
+    }
+
+    /// Sets the range that xlfCaller reports, as for an array formula entered across the
+    /// given first and last rows and columns
+    pub fn set_caller_area(&self, sheet: IDSHEET, rows: (i32, i32), cols: (i32, i32)) {
+        self.lock().caller = Some((sheet as usize,
+            XLREF12 { rwFirst: rows.0, rwLast: rows.1, colFirst: cols.0, colLast: cols.1 }));
+    }
+
+    /// Makes xlfCaller report the caller as a single reference (xltypeSRef), which does
+    /// not say which sheet it is on, rather than as a reference with its sheet
+    pub fn set_caller_sref(&self, sref: bool) {
+        self.lock().caller_sref = sref;
+    }
+
+    /// Returns all the functions that are currently registered
+    pub fn registrations(&self) -> Vec<Registration> {
+        self.lock().registrations.clone()
+    }
+
+    /// Returns the registration of the function with the given name, if there is one
+    pub fn registration(&self, function_text: &str) -> Option<Registration> {
+        self.lock().registrations.iter().find(|r| r.function_text == function_text).cloned()
+    }
+
+    /// Returns the number of results handed out with xlbitXLFree that have not yet been
+    /// freed by a call to xlFree. Once the addin has dropped everything, this should be
+    /// zero.
+    pub fn unfreed_results(&self) -> usize {
+        self.lock().unfreed.len()
+    }
+
+    /// Calls a registered function by the name it was registered under in Excel. The
+    /// arguments are passed as XLOPER12s, padded with missing values if there are too few.
+    /// The result is copied, then released through xlAutoFree12 if it has xlbitDLLFree set,
+    /// just as Excel would. Returns a #VALUE! error if the function is not registered
+    /// or was not exported.
+    ///
+    /// An asynchronous function, registered with a return type of `>`, is passed a handle
+    /// as its last argument, and the call waits up to ten seconds for the result to be
+    /// returned through xlAsyncReturn. If it is not, the result is #GETTING_DATA.
+    pub fn call(&self, function_text: &str, args: &[Variant]) -> Variant {
+        // Do not hold the lock while the function runs, as it may call back into Excel
+        let (export, asynchronous) = {
+            let state = self.lock();
+            let registration = match state.registrations.iter().find(|r| r.function_text == function_text) {
+                Some(registration) => registration,
+                None => return Variant::from_err(XlError::Value)
+            };
+            match state.exports.get(&registration.procedure) {
+                Some(export) => (export.clone(), registration.type_text.starts_with('>')),
+                None => return Variant::from_err(XlError::Value)
+            }
+        };
+
+        let arity = if asynchronous { export.arity().saturating_sub(1) } else { export.arity() };
+        let mut opers: Vec<Variant> = args.to_vec();
+        while opers.len() < arity {
+            opers.push(Variant::missing());
+        }
+        let mut pointers: Vec<LPXLOPER12> = opers.iter_mut()
+            .take(arity)
+            .map(|oper| oper.as_mut_xloper() as LPXLOPER12)
+            .collect();
+
+        if asynchronous {
+            let handle = {
+                let mut state = self.lock();
+                state.next_async_handle += 1;
+                state.next_async_handle
+            };
+            let mut handle_oper = XLOPER12 {
+                xltype: xltypeBigData,
+                val: xloper12__bindgen_ty_1 {
+                    bigdata: xloper12__bindgen_ty_1__bindgen_ty_5 {
+                        h: xloper12__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1 { hdata: handle as *mut _ },
+                        cbData: 0 } } };
+            pointers.push(&mut handle_oper);
+            export.invoke(&pointers);
+            return self.wait_for_async(handle, Duration::from_secs(10))
+        }
+
+        let result = export.invoke(&pointers);
+        if result.is_null() {
+            return Variant::new()
+        }
+        let copy = unsafe { Variant::from_xloper(result) };
+        if unsafe { (*result).xltype } & xlbitDLLFree != 0 {
+            xlAutoFree12(result);
+        }
+        copy
+    }
+
+    /// Runs a command registered with Reg::add_command, by the name it was registered under
+    /// in Excel, returning what the command returns to Excel. Returns 0 if there is no such
+    /// command.
+    pub fn run(&self, function_text: &str) -> i32 {
+        let procedure = match self.registration(function_text) {
+            Some(registration) => registration.procedure,
+            None => return 0
+        };
+        match command::trampoline(&procedure) {
+            Some(trampoline) => trampoline(),
+            None => 0
+        }
+    }
+
+    /// Runs the commands registered with xlEventRegister for the given event, such as
+    /// xleventCalculationCanceled, as Excel would when the event happens
+    pub fn fire_event(&self, event: u32) {
+        let commands: Vec<String> = self.lock().events.iter()
+            .filter(|e| e.0 == event as i32)
+            .map(|e| e.1.clone())
+            .collect();
+        for command in commands {
+            self.run(&command);
+        }
+    }
+
+    /// The time, as a serial such as NOW() returns. The clock only moves when advanced.
+    pub fn now(&self) -> f64 {
+        self.lock().now
+    }
+
+    /// The commands waiting on xlcOnTime, with the times they are due, in the order they
+    /// were scheduled
+    pub fn scheduled(&self) -> Vec<(f64, String)> {
+        self.lock().on_time.clone()
+    }
+
+    /// Moves the clock forward, running each command scheduled with xlcOnTime as its time
+    /// comes, with the clock set to that time
+    pub fn advance(&self, by: Duration) {
+        let end = self.now() + by.as_secs_f64() / 86400.0;
+        loop {
+            let command = {
+                let mut state = self.lock();
+                let next = state.on_time.iter().enumerate()
+                    .filter(|e| (e.1).0 <= end)
+                    .min_by(|a, b| (a.1).0.total_cmp(&(b.1).0))
+                    .map(|e| e.0);
+                match next {
+                    Some(i) => {
+                        let (at, command) = state.on_time.remove(i);
+                        state.now = state.now.max(at);
+                        command
+                    },
+                    None => {
+                        state.now = end;
+                        return
+                    }
+                }
+            };
+            self.run(&command);
+        }
+    }
+
+    /// The name of the macro or command bound to a key with xlcOnKey, if any. The key is
+    /// written as for keys::bind_key.
+    pub fn key_binding(&self, key: &str) -> Option<String> {
+        keys::parse_key(key).ok().and_then(|key| self.lock().keys.get(&key).cloned())
+    }
+
+    /// Runs the command bound to a key with xlcOnKey, as Excel would when the key is
+    /// pressed, returning what the command returns to Excel. The key is written as for
+    /// keys::bind_key. Returns 0 if the key is not bound.
+    pub fn press(&self, key: &str) -> i32 {
+        let command = keys::parse_key(key).ok().and_then(|key| self.lock().keys.get(&key).cloned());
+        match command {
+            Some(command) => self.run(&command),
+            None => 0
+        }
+    }
+
+    fn wait_for_async(&self, handle: usize, timeout: Duration) -> Variant {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            if let Some(result) = state.async_results.remove(&handle) {
+                return result
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Variant::from_err(XlError::GettingData)
+            }
+            state = self.returned.wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl ExcelBackend for FakeExcel {
+    fn excel12v(&self, xlfn: i32, oper_res: &mut XLOPER12, opers: &[LPXLOPER12]) -> i32 {
+        let args: Vec<Variant> = opers.iter().map(|&p| unsafe { Variant::from_xloper(p) }).collect();
+        let mut state = self.lock();
+        let result = match xlfn as u32 {
+            xlGetName => Ok(Variant::from_str(&state.dll_path)),
+            xlfRegister => state.register(&args),
+            xlfUnregister => state.unregister(&args),
+            xlCoerce => state.coerce(&args),
+            xlSheetNm => state.sheet_name(&args),
+            xlSheetId => state.sheet_id(&args),
+            xlEventRegister => state.event_register(&args),
+            xlfNow => Ok(Variant::from_float(state.now)),
+            xlcOnTime => state.on_time(&args),
+            xlcOnKey => state.on_key(&args),
+            xlAsyncReturn => {
+                let result = state.async_return(opers, &args);
+                self.returned.notify_all();
+                result
+            },
+            xlfCaller => return state.caller(oper_res),
+            xlFree => {
+                for &p in opers.iter() {
+                    state.free(p);
+                }
+                return xlretSuccess as i32
+            },
+            _ => Err(xlretInvXlfn)
+        };
+
+        match result {
+            Ok(value) => {
+                *oper_res = state.hand_out(value);
+                xlretSuccess as i32
+            },
+            Err(code) => code as i32
+        }
+    }
+
+    fn free(&self, xloper: LPXLOPER12) -> i32 {
+        self.lock().free(xloper);
+        xlretSuccess as i32
+    }
+}
+
+impl State {
+    // Gives a result to the addin. As in real Excel, the addin must free anything other
+    // than simple values by calling xlFree, so we keep the value here until then.
+    fn hand_out(&mut self, value: Variant) -> XLOPER12 {
+        let mut xloper = *value.as_xloper();
+        xloper.xltype = (xloper.xltype & !xlbitDLLFree) | xlbitXLFree;
+        if let Some(key) = data_address(&xloper) {
+            self.unfreed.insert(key, Unfreed::Value(value));
+        }
+        xloper
+    }
+
+    fn free(&mut self, xloper: LPXLOPER12) {
+        if xloper.is_null() {
+            return
+        }
+        if let Some(key) = data_address(unsafe { &*xloper }) {
+            self.unfreed.remove(&key);
+        }
+    }
+
+    fn register(&mut self, args: &[Variant]) -> Result<Variant, u32> {
+        if args.len() < 4 || args.len() > 255 {
+            return Err(xlretInvCount)
+        }
+        let text = |i: usize| args.get(i).and_then(|a| a.as_string()).unwrap_or_default();
+        let macro_type = args.get(5).map(|a|
+            a.as_i32().or_else(|| a.as_f64().map(|f| f as i32)).unwrap_or(1)).unwrap_or(1);
+
+        let register_id = self.next_register_id;
+        self.next_register_id += 1.0;
+
+        let registration = Registration {
+            register_id,
+            dll_name: text(0),
+            procedure: text(1),
+            type_text: text(2),
+            function_text: text(3),
+            argument_text: text(4),
+            macro_type,
+            category: text(6),
+            shortcut_text: text(7),
+            help_topic: text(8),
+            function_help: text(9),
+            argument_help: (10..args.len()).map(text).collect() };
+
+        // Registering the same name again replaces the previous registration
+        self.registrations.retain(|r| r.function_text != registration.function_text);
+        self.registrations.push(registration);
+        Ok(Variant::from_float(register_id))
+    }
+
+    fn event_register(&mut self, args: &[Variant]) -> Result<Variant, u32> {
+        if args.len() != 2 {
+            return Err(xlretInvCount)
+        }
+        let event = args[1].as_i32().or_else(|| args[1].as_f64().map(|f| f as i32));
+        match (args[0].as_string(), event) {
+            // a missing command stops the event being reported
+            (None, Some(event)) if args[0].is_missing() => {
+                self.events.retain(|e| e.0 != event);
+                Ok(Variant::from_bool(true))
+            },
+            (Some(command), Some(event)) => {
+                self.events.retain(|e| e.0 != event || e.1 != command);
+                self.events.push((event, command));
+                Ok(Variant::from_bool(true))
+            },
+            _ => Err(xlretFailed)
+        }
+    }
+
+    // Schedules a command, or with a fourth argument of FALSE, cancels one scheduled for
+    // exactly the same time, failing if there is none
+    fn on_time(&mut self, args: &[Variant]) -> Result<Variant, u32> {
+        if args.len() < 2 || args.len() > 4 {
+            return Err(xlretInvCount)
+        }
+        let (at, command) = match (args[0].as_f64(), args[1].as_string()) {
+            (Some(at), Some(command)) => (at, command),
+            _ => return Err(xlretFailed)
+        };
+        if args.get(3).and_then(|insert| insert.as_bool()) == Some(false) {
+            match self.on_time.iter().position(|e| e.0 == at && e.1 == command) {
+                Some(i) => { self.on_time.remove(i); },
+                None => return Err(xlretFailed)
+            }
+        } else {
+            self.on_time.push((at, command));
+        }
+        Ok(Variant::from_bool(true))
+    }
+
+    // Binds a key to a command, or with no command, unbinds it
+    fn on_key(&mut self, args: &[Variant]) -> Result<Variant, u32> {
+        let key = match args.first().and_then(|key| key.as_string()) {
+            Some(key) => key,
+            None => return Err(xlretInvCount)
+        };
+        match args.get(1).filter(|command| !command.is_missing()) {
+            None => { self.keys.remove(&key); },
+            Some(command) => match command.as_string() {
+                Some(command) => { self.keys.insert(key, command); },
+                None => return Err(xlretFailed)
+            }
+        }
+        Ok(Variant::from_bool(true))
+    }
+
+    // The handle is not a value, so we read it from the raw argument
+    fn async_return(&mut self, opers: &[LPXLOPER12], args: &[Variant]) -> Result<Variant, u32> {
+        if opers.len() != 2 {
+            return Err(xlretInvCount)
+        }
+        let handle = unsafe { &*opers[0] };
+        if handle.xltype & !(xlbitDLLFree | xlbitXLFree) != xltypeBigData {
+            return Err(xlretInvAsynchronousContext)
+        }
+        let handle = unsafe { handle.val.bigdata.h.hdata } as usize;
+        if handle == 0 || handle > self.next_async_handle || self.async_results.contains_key(&handle) {
+            return Err(xlretInvAsynchronousContext)
+        }
+        self.async_results.insert(handle, args[1].clone());
+        Ok(Variant::from_bool(true))
+    }
+
+    fn unregister(&mut self, args: &[Variant]) -> Result<Variant, u32> {
+        if args.len() != 1 {
+            return Err(xlretInvCount)
+        }
+        let before = self.registrations.len();
+        match (args[0].as_f64(), args[0].as_string()) {
+            (Some(id), _) => self.registrations.retain(|r| r.register_id != id),
+            (_, Some(name)) => self.registrations.retain(|r| r.function_text != name),
+            _ => return Err(xlretFailed)
+        }
+        Ok(Variant::from_bool(self.registrations.len() != before))
+    }
+
+    fn coerce(&self, args: &[Variant]) -> Result<Variant, u32> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(xlretInvCount)
+        }
+
+        // Anything that is not a reference is coerced to itself
+        let (sheet, area) = match self.resolve(&args[0]) {
+            Some(resolved) => resolved,
+            None => return Ok(args[0].clone())
+        };
+        let cell = |row: i32, col: i32| self.cells.get(&(sheet, row, col)).cloned()
+            .unwrap_or_else(Variant::new);
+
+        let (cols, rows) = (area.colLast - area.colFirst + 1, area.rwLast - area.rwFirst + 1);
+        let value = if cols == 1 && rows == 1 {
+            cell(area.rwFirst, area.colFirst)
+        } else {
+            let mut data = Vec::with_capacity((cols * rows) as usize);
+            for row in area.rwFirst..=area.rwLast {
+                for col in area.colFirst..=area.colLast {
+                    data.push(cell(row, col));
+                }
+            }
+            Variant::from_array(cols as usize, rows as usize, &data)
+        };
+
+        // A type mask restricts the result, which we handle crudely for the toy grid
+        match args.get(1).and_then(|a| a.as_i32().or_else(|| a.as_f64().map(|f| f as i32))) {
+            Some(mask) if (mask as u32) & xltypeNum != 0 && value.as_f64().is_none() =>
+                Ok(value.as_string()
+                    .and_then(|s| s.trim().parse::<f64>().ok())
+                    .map(Variant::from_float)
+                    .unwrap_or_else(|| Variant::from_err(XlError::Value))),
+            Some(mask) if (mask as u32) & xltypeStr != 0 && value.as_string().is_none() =>
+                Ok(Variant::from_str(&value.to_string())),
+            _ => Ok(value)
+        }
+    }
+
+    fn sheet_name(&self, args: &[Variant]) -> Result<Variant, u32> {
+        if args.len() != 1 {
+            return Err(xlretInvCount)
+        }
+        match self.resolve(&args[0]) {
+            Some((sheet, _)) => match self.sheets.get(sheet.wrapping_sub(1)) {
+                Some(Some(name)) => Ok(Variant::from_str(name)),
+                _ => Err(xlretFailed)
+            },
+            None => Err(xlretFailed)
+        }
+    }
+
+    // With no arguments, this is the ID of the active sheet, which we take to be the
+    // sheet of the caller
+    fn sheet_id(&self, args: &[Variant]) -> Result<Variant, u32> {
+        let sheet = match args.len() {
+            0 => self.caller.map(|c| c.0).unwrap_or(1),
+            1 => match args[0].as_string() {
+                Some(name) => match self.sheets.iter().position(|s| s.as_deref() == Some(name.as_str())) {
+                    Some(index) => index + 1,
+                    None => return Err(xlretFailed)
+                },
+                None => return Err(xlretFailed)
+            },
+            _ => return Err(xlretInvCount)
+        };
+        Ok(Variant::from_refs(sheet as IDSHEET, &[]))
+    }
+
+    fn caller(&mut self, oper_res: &mut XLOPER12) -> i32 {
+        let (sheet, area) = match self.caller {
+            Some(caller) => caller,
+            None => {
+                *oper_res = *Variant::from_err(XlError::Value).as_mut_xloper();
+                return xlretSuccess as i32
+            }
+        };
+        if self.caller_sref {
+            *oper_res = XLOPER12 {
+                xltype: xltypeSRef,
+                val: xloper12__bindgen_ty_1 { sref: xloper12__bindgen_ty_1__bindgen_ty_1 { count: 1, ref_: area } } };
+            return xlretSuccess as i32
+        }
+        let mut mref = Box::new(XLMREF12 { count: 1, reftbl: [area] });
+        *oper_res = XLOPER12 {
+            xltype: xltypeRef | xlbitXLFree,
+            val: xloper12__bindgen_ty_1 {
+                mref: xloper12__bindgen_ty_1__bindgen_ty_2 { lpmref: &mut *mref, idSheet: sheet as IDSHEET } } };
+        self.unfreed.insert(&*mref as *const XLMREF12 as usize, Unfreed::Ref(mref));
+        xlretSuccess as i32
+    }
+
+    // Finds the sheet and area of a reference. Single references (SRef) refer to the
+    // sheet of the caller, or the first sheet if there is no caller.
+    fn resolve(&self, var: &Variant) -> Option<(usize, XLREF12)> {
+        let xloper = var.as_xloper();
+        match xloper.xltype & !(xlbitDLLFree | xlbitXLFree) {
+            xltypeRef => unsafe {
+                let mref = xloper.val.mref.lpmref;
+                if mref.is_null() || (*mref).count != 1 {
+                    None
+                } else {
+                    Some((xloper.val.mref.idSheet as usize, (*mref).reftbl[0]))
+                }
+            },
+            xltypeSRef => {
+                let sheet = self.caller.map(|c| c.0).unwrap_or(1);
+                Some((sheet, unsafe { xloper.val.sref.ref_ }))
+            },
+            _ => None
+        }
+    }
+}
+
+// The Variants in the state are all owned by the FakeExcel, and only accessed under the
+// mutex, so it is safe to move the state between threads.
+unsafe impl Send for State {}
+
+// Returns the address of the heap data owned by an XLOPER12, if it has any.
+fn data_address(xloper: &XLOPER12) -> Option<usize> {
+    match xloper.xltype & !(xlbitDLLFree | xlbitXLFree) {
+        xltypeStr => Some(unsafe { xloper.val.str } as usize),
+        xltypeMulti => Some(unsafe { xloper.val.array.lparray } as usize),
+        xltypeRef => Some(unsafe { xloper.val.mref.lpmref } as usize),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use registrator::Reg;
+    use entrypoint::excel12;
+
+    extern "system" fn test_add(a: LPXLOPER12, b: LPXLOPER12) -> LPXLOPER12 {
+        let a = unsafe { Variant::from_xloper(a) }.as_f64().unwrap_or(0.0);
+        let b = unsafe { Variant::from_xloper(b) }.as_f64().unwrap_or(0.0);
+        Variant::from_float(a + b).into_excel_return()
+    }
+
+    extern "system" fn test_sum(range: LPXLOPER12) -> LPXLOPER12 {
+        let range = unsafe { Variant::from_xloper(range) };
+        let values = excel12(xlCoerce, &mut [range]);
+        let (cols, rows) = values.dim();
+        let mut total = 0.0;
+        for row in 0..rows {
+            for col in 0..cols {
+                total += values.at(col, row).as_f64().unwrap_or(0.0);
+            }
+        }
+        Variant::from_float(total).into_excel_return_slot()
+    }
+
+    fn auto_open() {
+        let reg = Reg::new();
+        reg.add("test_add", "QQQ$", "first, second", "Test", "Adds two numbers",
+            &["the first number", "the second number"]);
+        reg.add("test_sum", "QQ", "range", "Test", "Sums a range", &[]);
+    }
+
+    #[test]
+    fn open_register_invoke_free() {
+        let excel = FakeExcel::new("C:\\addins\\test.xll");
+        excel.export("test_add", test_add as extern "system" fn(LPXLOPER12, LPXLOPER12) -> LPXLOPER12);
+        excel.export("test_sum", test_sum as extern "system" fn(LPXLOPER12) -> LPXLOPER12);
+        let _guard = excel.install();
+
+        auto_open();
+
+        let registration = excel.registration("test_add").unwrap();
+        assert_eq!(registration.dll_name, "C:\\addins\\test.xll");
+        assert_eq!(registration.type_text, "QQQ$");
+        assert_eq!(registration.category, "Test");
+        assert_eq!(registration.argument_help, vec!["the first number", "the second number"]);
+
+        assert_eq!(excel.call("test_add", &[Variant::from_float(2.0), Variant::from_float(3.5)]).as_f64(), Some(5.5));
+        assert_eq!(excel.call("test_add", &[Variant::from_float(2.0)]).as_f64(), Some(2.0));
+        assert_eq!(excel.call("no_such_function", &[]).as_error(), Some(XlError::Value));
+
+        let sheet = excel.sheet_id("[Book1]Sheet1").unwrap();
+        excel.set_cell(sheet, 0, 0, Variant::from_float(1.0));
+        excel.set_cell(sheet, 1, 0, Variant::from_float(2.0));
+        excel.set_cell(sheet, 1, 1, Variant::from_str("ignored"));
+        let range = excel.reference(sheet, (0, 1), (0, 1));
+        assert_eq!(excel.call("test_sum", &[range]).as_f64(), Some(3.0));
+        assert_eq!(excel.unfreed_results(), 0);
+    }
+
+    #[test]
+    fn unregister_and_sheet_names() {
+        let excel = FakeExcel::new("test.xll");
+        let _guard = excel.install();
+
+        let reg = Reg::new();
+        reg.add("test_one", "QQ", "x", "Test", "One", &[]);
+        reg.add("test_two", "QQ", "x", "Test", "Two", &[]);
+        let id = excel.registration("test_one").unwrap().register_id;
+        excel12(xlfUnregister, &mut [Variant::from_float(id)]);
+        let names: Vec<String> = excel.registrations().into_iter().map(|r| r.function_text).collect();
+        assert_eq!(names, vec!["test_two"]);
+
+        let sheet = excel.add_sheet("[Book2]Prices");
+        excel.set_caller(Some((sheet, 4, 2)));
+        let caller = excel12(xlfCaller, &mut []);
+        assert!(caller.is_ref());
+        assert_eq!(excel12(xlSheetNm, &mut [caller]).to_string(), "[Book2]Prices");
+
+        // results from Excel, such as the dll name the registrator asked for, are copied
+        // and handed straight back
+        assert_eq!(excel.unfreed_results(), 0);
+    }
+}
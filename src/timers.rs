@@ -0,0 +1,293 @@
+//! Timers that run Rust closures at a given time, or every so often, for example to poll a
+//! file or a queue and push what arrives into the workbook.
+//!
+//! Excel can only run a command at a given time, through xlcOnTime, so the first timer
+//! registers a hidden command, `xladd_OnTime`, which runs whichever timers are due. Only
+//! one xlcOnTime is outstanding at a time, for the earliest timer, and it is moved
+//! whenever the earliest timer changes.
+//!
+//! ```
+//! # use std::time::Duration;
+//! # use xladd::timers;
+//! # use xladd::testing::FakeExcel;
+//! # let excel = FakeExcel::new("mine.xll");
+//! # let _guard = excel.install();
+//! let poll = timers::schedule_repeating(Duration::from_secs(5), || {
+//!     // look for new data
+//! }).unwrap();
+//! // later
+//! timers::cancel(poll);
+//! ```
+//!
+//! Excel runs the command on its main thread once it is idle, so the closures can call
+//! into Excel as any command can. Like other command-equivalent functions, xlcOnTime
+//! cannot be called from a UDF, so timers must be scheduled from xlAutoOpen, a command,
+//! or another timer. Any timers left outstanding are canceled by xlauto::close_all.
+
+#![allow(non_upper_case_globals)]
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use entrypoint::{try_excel12, XlRetError};
+use protect;
+use registrator::{Reg, RegisterError, debug_print, registered};
+use variant::Variant;
+use xlcall::{xlcOnTime, xlfNow};
+
+// The name of the command that Excel runs when a timer is due
+const COMMAND: &str = "xladd_OnTime";
+
+// Timers due within this many days of now are run together, as Excel only runs commands
+// to the nearest second or so
+const TOLERANCE: f64 = 0.5 / 86400.0;
+
+/// Identifies a timer, so that it can be canceled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+/// The reasons scheduling a timer can fail
+#[derive(Debug)]
+pub enum TimerError {
+    /// The command that runs the timers could not be registered
+    Register(RegisterError),
+    /// Excel refused the xlcOnTime, for example because it was called from a UDF
+    Schedule(XlRetError),
+}
+
+impl fmt::Display for TimerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TimerError::Register(ref e) => write!(f, "cannot register the timer command: {}", e),
+            TimerError::Schedule(ref e) => write!(f, "cannot schedule the timer: {}", e),
+        }
+    }
+}
+
+impl Error for TimerError {}
+
+enum Task {
+    Once(Box<dyn FnOnce() + Send>),
+    Repeating(Arc<dyn Fn() + Send + Sync>, f64),
+}
+
+struct Timer {
+    at: f64,
+    task: Task,
+}
+
+struct Timers {
+    next_id: u64,
+    queue: BTreeMap<u64, Timer>,
+    // the time of the outstanding xlcOnTime, if any
+    pending: Option<f64>,
+}
+
+static TIMERS: Mutex<Timers> = Mutex::new(Timers { next_id: 1, queue: BTreeMap::new(), pending: None });
+
+/// Runs a closure once, at the given time. The time is a serial number, in the same form
+/// as returned by NOW(). A time that has already passed runs as soon as Excel is idle.
+pub fn schedule<F>(at: f64, f: F) -> Result<TimerId, TimerError> where F: FnOnce() + Send + 'static {
+    add(Timer { at, task: Task::Once(Box::new(f)) })
+}
+
+/// Runs a closure once, after the given delay
+pub fn schedule_after<F>(delay: Duration, f: F) -> Result<TimerId, TimerError> where F: FnOnce() + Send + 'static {
+    add(Timer { at: now()? + days(delay), task: Task::Once(Box::new(f)) })
+}
+
+/// Runs a closure every interval, starting one interval from now, until it is canceled.
+/// The next run is timed from the end of the last, so runs that Excel delays, for example
+/// while it is busy calculating, are not made up afterwards.
+pub fn schedule_repeating<F>(interval: Duration, f: F) -> Result<TimerId, TimerError> where F: Fn() + Send + Sync + 'static {
+    let interval = days(interval);
+    add(Timer { at: now()? + interval, task: Task::Repeating(Arc::new(f), interval) })
+}
+
+/// Cancels a timer, returning false if it has already run or been canceled. A repeating
+/// timer may cancel itself from within its closure.
+pub fn cancel(id: TimerId) -> bool {
+    let removed = lock().queue.remove(&id.0).is_some();
+    if removed {
+        reschedule_or_log();
+    }
+    removed
+}
+
+/// Cancels every timer. This is called by xlauto::close_all, as Excel must not be left to
+/// run the command once the dll is unloaded.
+pub fn cancel_all() {
+    lock().queue.clear();
+    reschedule_or_log();
+}
+
+fn add(timer: Timer) -> Result<TimerId, TimerError> {
+    if !registered().iter().any(|r| r.name == COMMAND) {
+        Reg::new().add_command(COMMAND, None, run_due).map_err(TimerError::Register)?;
+    }
+
+    let id = {
+        let mut timers = lock();
+        let id = timers.next_id;
+        timers.next_id += 1;
+        timers.queue.insert(id, timer);
+        id
+    };
+    reschedule().map_err(|e| {
+        lock().queue.remove(&id);
+        TimerError::Schedule(e)
+    })?;
+    Ok(TimerId(id))
+}
+
+// Moves the outstanding xlcOnTime to the time of the earliest timer, or cancels it if
+// there are none. Excel does not call back into us, so the lock is held throughout.
+fn reschedule() -> Result<(), XlRetError> {
+    let mut timers = lock();
+    let earliest = timers.queue.values().map(|t| t.at).fold(None, |m: Option<f64>, at| Some(m.map_or(at, |m| m.min(at))));
+    if earliest == timers.pending {
+        return Ok(())
+    }
+    if let Some(pending) = timers.pending.take() {
+        let mut opers = [Variant::from_float(pending), Variant::from_str(COMMAND), Variant::missing(), Variant::from_bool(false)];
+        if let Err(e) = try_excel12(xlcOnTime, &mut opers) {
+            debug_print(&format!("failed to cancel {} at {}: {}", COMMAND, pending, e));
+        }
+    }
+    if let Some(at) = earliest {
+        let mut opers = [Variant::from_float(at), Variant::from_str(COMMAND)];
+        try_excel12(xlcOnTime, &mut opers)?;
+        timers.pending = Some(at);
+    }
+    Ok(())
+}
+
+// The body of the command. Runs every timer that is due, each with its own panic
+// protection, then schedules the next.
+fn run_due() {
+    lock().pending = None;
+    let now = match now() {
+        Ok(now) => now,
+        Err(e) => {
+            debug_print(&format!("{} cannot read the time: {}", COMMAND, e));
+            reschedule_or_log();
+            return
+        }
+    };
+
+    let mut due: Vec<Box<dyn FnOnce() + Send>> = Vec::new();
+    let mut repeating = Vec::new();
+    {
+        let mut timers = lock();
+        let ids: Vec<u64> = timers.queue.iter().filter(|t| t.1.at <= now + TOLERANCE).map(|t| *t.0).collect();
+        for id in ids {
+            if let Some(&mut Timer { ref mut at, task: Task::Repeating(ref f, interval) }) = timers.queue.get_mut(&id) {
+                // keeps the timer from running again should a closure reschedule
+                *at = now + interval;
+                repeating.push(id);
+                let f = f.clone();
+                due.push(Box::new(move || f()));
+            } else if let Some(Timer { task: Task::Once(f), .. }) = timers.queue.remove(&id) {
+                due.push(f);
+            }
+        }
+    }
+
+    // the lock is not held while the timers run, as they may schedule or cancel timers
+    for f in due {
+        let _ = protect::catch(COMMAND, f);
+    }
+
+    // time the next runs from now the closures have finished, unless they were canceled
+    if let Ok(end) = self::now() {
+        let mut timers = lock();
+        for id in repeating {
+            if let Some(&mut Timer { ref mut at, task: Task::Repeating(_, interval) }) = timers.queue.get_mut(&id) {
+                *at = end + interval;
+            }
+        }
+    }
+    reschedule_or_log();
+}
+
+fn reschedule_or_log() {
+    if let Err(e) = reschedule() {
+        debug_print(&format!("failed to schedule {}: {}", COMMAND, e));
+    }
+}
+
+fn now() -> Result<f64, TimerError> {
+    let now = try_excel12(xlfNow, &mut []).map_err(TimerError::Schedule)?;
+    now.as_f64().ok_or(TimerError::Schedule(XlRetError::Failed))
+}
+
+fn days(duration: Duration) -> f64 {
+    duration.as_secs_f64() / 86400.0
+}
+
+fn lock() -> ::std::sync::MutexGuard<'static, Timers> {
+    TIMERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use testing::{self, FakeExcel};
+    use xlcall::{LPXLOPER12, XLOPER12, xlretFailed};
+
+    #[test]
+    fn timers_run_when_due() {
+        static ONCE: AtomicUsize = AtomicUsize::new(0);
+        static REPEATED: AtomicUsize = AtomicUsize::new(0);
+        let excel = FakeExcel::new("timers.xll");
+        let _guard = excel.install();
+        cancel_all();
+
+        let start = excel.now();
+        schedule(start + days(Duration::from_secs(90)), || { ONCE.fetch_add(1, Ordering::SeqCst); }).unwrap();
+        let later = schedule_after(Duration::from_secs(600), || panic!("canceled")).unwrap();
+        let poll = schedule_repeating(Duration::from_secs(60), || { REPEATED.fetch_add(1, Ordering::SeqCst); }).unwrap();
+        assert_eq!(excel.scheduled().len(), 1);
+
+        excel.advance(Duration::from_secs(59));
+        assert_eq!(REPEATED.load(Ordering::SeqCst), 0);
+        excel.advance(Duration::from_secs(130));
+        assert_eq!(REPEATED.load(Ordering::SeqCst), 3);
+        assert_eq!(ONCE.load(Ordering::SeqCst), 1);
+
+        assert!(cancel(later));
+        assert!(!cancel(later));
+        assert!(cancel(poll));
+        assert!(excel.scheduled().is_empty());
+        excel.advance(Duration::from_secs(3600));
+        assert_eq!(REPEATED.load(Ordering::SeqCst), 3);
+        assert_eq!(ONCE.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn timers_are_rescheduled_when_the_time_cannot_be_read() {
+        static SCHEDULED: AtomicUsize = AtomicUsize::new(0);
+        let _guard = testing::install(|xlfn: i32, _: &mut XLOPER12, _: &[LPXLOPER12]| {
+            match xlfn as u32 {
+                xlfNow => xlretFailed as i32,
+                xlcOnTime => { SCHEDULED.fetch_add(1, Ordering::SeqCst); 0 }
+                _ => 0,
+            }
+        });
+        {
+            let mut timers = lock();
+            timers.queue.clear();
+            timers.pending = Some(1.0);
+            timers.queue.insert(0, Timer { at: 2.0, task: Task::Once(Box::new(|| ())) });
+        }
+
+        run_due();
+        assert_eq!(SCHEDULED.load(Ordering::SeqCst), 1);
+        assert_eq!(lock().pending, Some(2.0));
+        lock().queue.clear();
+        lock().pending = None;
+    }
+}
@@ -0,0 +1,181 @@
+//! Support for UDFs defined with the `#[xl_func]` attribute. The attribute turns an ordinary
+//! Rust function into one that Excel can call, by generating an exported shim that takes
+//! its arguments as XLOPER12s, converts them with FromArg, calls the function under panic
+//! protection, and converts the result with IntoReturn. It also submits an XlFunction
+//! describing the function, so that `register_all` can register every such function:
+//!
+//! ```ignore
+//! /// Adds two numbers
+//! ///
+//! /// * `a` - the first number
+//! /// * `b` - the second number
+//! #[xl_func(category = "Maths", threadsafe)]
+//! fn add(a: f64, b: f64) -> f64 {
+//!     a + b
+//! }
+//!
+//! #[no_mangle]
+//! pub extern "system" fn xlAutoOpen() -> i32 {
+//!     protect::auto("xlAutoOpen", || register_all(&Reg::new()).unwrap())
+//! }
+//! ```
+//!
+//! The attribute accepts `name = "..."` to register the function under a different name in
+//! Excel, `category = "..."`, `threadsafe` and `volatile`. The first paragraph of the doc
+//! comment becomes the help text, and lines of the form `` * `arg` - text `` become the help
+//! for each argument. The types that can be used for arguments are those that implement
+//! FromArg, and for the result those that implement IntoReturn.
+//!
+//! The functions here can equally be used by hand-written shims.
+
+use std::any::{self, Any};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use cache;
+use convert::ConversionError;
+use registrator::{Reg, RegisterError};
+use variant::Variant;
+use variant_ref::VariantRef;
+use xlerror::XlError;
+
+/// The description of a UDF generated by `#[xl_func]`, which is everything Reg::add
+/// needs to register it.
+#[derive(Debug)]
+pub struct XlFunction {
+    /// The name of the exported shim, which is also the name the function has in Excel
+    pub name: &'static str,
+    /// The type string, such as "QQQ$"
+    pub type_text: &'static str,
+    /// The names of the arguments, separated by commas
+    pub arg_text: &'static str,
+    /// The category in the function wizard, which may be empty
+    pub category: &'static str,
+    /// The help text, taken from the first paragraph of the doc comment
+    pub help: &'static str,
+    /// The help for each argument, taken from the doc comment
+    pub arg_help: &'static [&'static str],
+}
+
+collect!(XlFunction);
+
+/// Registers every function defined with `#[xl_func]` anywhere in the addin, stopping at
+/// the first that cannot be registered. This can only be called from within xlAutoOpen.
+pub fn register_all(reg: &Reg) -> Result<(), RegisterError> {
+    for function in functions() {
+        reg.try_add(function.name, function.type_text, function.arg_text, function.category,
+            function.help, function.arg_help)?;
+    }
+    Ok(())
+}
+
+/// Lists every function defined with `#[xl_func]` anywhere in the addin
+pub fn functions() -> impl Iterator<Item = &'static XlFunction> {
+    ::inventory::iter::<XlFunction>.into_iter()
+}
+
+/// Types that a UDF argument can be converted to. The conversions are those of TryFrom,
+/// which follow Excel's coercion rules. An Option is None if the argument is missing or
+/// empty.
+pub trait FromArg<'a>: Sized {
+    fn from_arg(arg: VariantRef<'a>) -> Result<Self, ConversionError>;
+}
+
+macro_rules! from_arg_by_try_from {
+    ($($t:ty)*) => {
+        $(
+            impl<'a> FromArg<'a> for $t {
+                fn from_arg(arg: VariantRef<'a>) -> Result<$t, ConversionError> {
+                    <$t>::try_from(arg)
+                }
+            }
+
+            impl<'a> FromArg<'a> for Option<$t> {
+                fn from_arg(arg: VariantRef<'a>) -> Result<Option<$t>, ConversionError> {
+                    if arg.is_missing() || arg.is_nil() {
+                        Ok(None)
+                    } else {
+                        <$t>::try_from(arg).map(Some)
+                    }
+                }
+            }
+        )*
+    }
+}
+
+from_arg_by_try_from!(f64 i32 bool String Vec<f64> Vec<i32> Vec<bool> Vec<String>
+    Vec<Vec<f64>> Vec<Vec<i32>> Vec<Vec<bool>> Vec<Vec<String>>);
+
+/// The argument as it is, without any conversion or copying
+impl<'a> FromArg<'a> for VariantRef<'a> {
+    fn from_arg(arg: VariantRef<'a>) -> Result<VariantRef<'a>, ConversionError> {
+        Ok(arg)
+    }
+}
+
+/// A copy of the argument
+impl<'a> FromArg<'a> for Variant {
+    fn from_arg(arg: VariantRef<'a>) -> Result<Variant, ConversionError> {
+        Ok(arg.to_variant())
+    }
+}
+
+/// An object in the cache, resolved from its handle. See cache.
+impl<'a, T: Any + Send + Sync> FromArg<'a> for Arc<T> {
+    fn from_arg(arg: VariantRef<'a>) -> Result<Arc<T>, ConversionError> {
+        arg.as_str()
+            .and_then(|handle| cache::get::<T>(&handle).ok())
+            .ok_or_else(|| ConversionError::of(any::type_name::<T>(), arg))
+    }
+}
+
+/// Types that a UDF can return. Anything that converts into a Variant can be returned. An
+/// Option shows None as #N/A, and a Result shows its error, which can be an XlError or a
+/// message.
+pub trait IntoReturn {
+    fn into_return(self) -> Variant;
+}
+
+impl<T: Into<Variant>> IntoReturn for T {
+    fn into_return(self) -> Variant {
+        self.into()
+    }
+}
+
+impl<T: Into<Variant>> IntoReturn for Option<T> {
+    fn into_return(self) -> Variant {
+        self.map(Into::into).unwrap_or_else(|| Variant::from_err(XlError::NA))
+    }
+}
+
+impl<T: Into<Variant>, E: Into<Variant>> IntoReturn for Result<T, E> {
+    fn into_return(self) -> Variant {
+        match self {
+            Ok(value) => value.into(),
+            Err(err) => err.into()
+        }
+    }
+}
+
+/// Converts an argument for a shim. If it cannot be converted, the error is the #VALUE!
+/// that the shim should return.
+pub fn arg<'a, T: FromArg<'a>>(arg: VariantRef<'a>) -> Result<T, Variant> {
+    T::from_arg(arg).map_err(|e| Variant::from(XlError::from(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arguments_and_results() {
+        let number = Variant::from_str("2.5");
+        assert_eq!(arg::<f64>(number.view()).ok(), Some(2.5));
+        assert_eq!(arg::<Option<f64>>(Variant::missing().view()).ok(), Some(None));
+        assert_eq!(arg::<bool>(Variant::from_str("x").view()).unwrap_err().as_error(), Some(XlError::Value));
+
+        assert_eq!(2.0.into_return().as_f64(), Some(2.0));
+        assert_eq!(None::<f64>.into_return().as_error(), Some(XlError::NA));
+        assert_eq!(Err::<f64, _>(XlError::Div0).into_return().as_error(), Some(XlError::Div0));
+        assert_eq!(Err::<f64, _>("no data").into_return().to_string(), "no data");
+    }
+}
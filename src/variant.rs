@@ -2,22 +2,41 @@
 
 use xlcall::XLREF12;
 use xlcall::XLMREF12;
-use std::{mem, fmt, slice};
-use xlcall::{XLOPER12, LPXLOPER12, xloper12__bindgen_ty_1, xloper12__bindgen_ty_1__bindgen_ty_3, 
-    xltypeNil, xltypeInt, xltypeStr, xltypeErr, xltypeMissing, xltypeNum, xltypeMulti, xltypeRef, xltypeSRef,
-    xlbitDLLFree, xlbitXLFree,
-    xlerrNull, xlerrDiv0, xlerrValue, xlerrRef, xlerrName, xlerrNum, xlerrNA, xlerrGettingData };
+use std::{alloc, mem, fmt, ptr, slice};
+use std::cell::RefCell;
+use xlcall::{XLOPER12, LPXLOPER12, xloper12__bindgen_ty_1, xloper12__bindgen_ty_1__bindgen_ty_2,
+    xloper12__bindgen_ty_1__bindgen_ty_3, IDSHEET,
+    xltypeNil, xltypeInt, xltypeBool, xltypeStr, xltypeErr, xltypeMissing, xltypeNum, xltypeMulti, xltypeRef, xltypeSRef,
+    xltypeFlow, xlbitDLLFree, xlbitXLFree };
 use entrypoint::excel_free;
+use xlerror::XlError;
+use variant_ref::VariantRef;
 
 const xltypeMask : u32 = !(xlbitDLLFree | xlbitXLFree);
-const xltypeStr_xlbitDLLFree: u32 = xltypeStr | xlbitDLLFree;
-const xltypeMulti_xlbitDLLFree: u32 = xltypeMulti | xlbitDLLFree;
 
-/// Variant is a wrapper around an XLOPER12. It can contain a string, i32 or f64, or a
+thread_local! {
+    // The value most recently returned by into_excel_return_slot on this thread
+    static RETURN_SLOT: RefCell<Variant> = RefCell::new(Variant::new());
+}
+
+/// Variant is a wrapper around an XLOPER12. It can contain a string, bool, i32 or f64, or a
 /// two dimensional of any mixture of these. Basically, it can contain anything that an
 /// Excel cell or array of cells can contain.
+///
+/// A Variant always owns everything it points to, which was allocated by Rust: strings are
+/// a `Vec<u16>`, arrays a `Vec<Variant>` and references a copy of the XLMREF12. It frees
+/// them when dropped, according to its type. Values that Excel owns are held instead by
+/// XlOwnedVariant, which gives them back to Excel through xlFree when dropped, or borrowed
+/// through a VariantRef. Strings, arrays and references in a Variant are marked with
+/// xlbitDLLFree, which tells Excel to call xlAutoFree12 when one is returned to it.
+#[repr(transparent)]
 pub struct Variant(XLOPER12);
 
+/// The result of a call into Excel, which Excel owns. Dropping it returns any memory to
+/// Excel through xlFree. It can be read in place through `view`, or copied into a Variant
+/// with `into_variant`, which frees the original straight away.
+pub struct XlOwnedVariant(XLOPER12);
+
 impl Variant {
     /// Construct a variant containing nil. This is used in Excel to represent cells that have
     /// nothing in them. It is also a sensible starting state for an uninitialized variant.
@@ -25,25 +44,32 @@ impl Variant {
         Variant(XLOPER12 { xltype : xltypeNil, val: xloper12__bindgen_ty_1 { w: 0 } })
     }
 
-    /// Construct a variant from an LPXLOPER12, for example supplied by Excel. The assumption
-    /// is that Excel continues to own the XLOPER12 and its lifetime is greater than that of
-    /// the Variant we construct here. For example, the LPXLOPER may be an argument to one
-    /// of our functions. We therefore do not want to own any of the data in this variant, so
-    /// we clear all ownership bits. This means we treat it as a kind of dynamic mut ref. 
-    pub fn from_xloper(xloper: LPXLOPER12) -> Variant {
-        let mut result = Variant(unsafe { *xloper });
-        result.0.xltype &= xltypeMask;    // no ownership bits
-        result
+    /// Construct a variant from an LPXLOPER12, for example supplied by Excel. Excel continues
+    /// to own the XLOPER12, so this makes a deep copy of it, including any string, array or
+    /// reference it points to. To read an argument without copying it, use a VariantRef,
+    /// which can also make the copy, through `to_variant`, without any unsafe code.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be non-null and point to a valid XLOPER12, including anything it in
+    /// turn points to.
+    pub unsafe fn from_xloper(xloper: LPXLOPER12) -> Variant {
+        Variant::copy_of(&*xloper)
     }
 
     /// Construct a variant containing an int (i32)
     pub fn from_int(w: i32) -> Variant {
-        Variant(XLOPER12 { xltype : xltypeInt, val: xloper12__bindgen_ty_1 { w: w } })
+        Variant(XLOPER12 { xltype : xltypeInt, val: xloper12__bindgen_ty_1 { w } })
     }
 
     /// Construct a variant containing a float (f64)
     pub fn from_float(num: f64) -> Variant {
-        Variant(XLOPER12 { xltype : xltypeNum, val: xloper12__bindgen_ty_1 { num: num } })
+        Variant(XLOPER12 { xltype : xltypeNum, val: xloper12__bindgen_ty_1 { num } })
+    }
+
+    /// Construct a variant containing a boolean, shown in Excel as TRUE or FALSE
+    pub fn from_bool(b: bool) -> Variant {
+        Variant(XLOPER12 { xltype : xltypeBool, val: xloper12__bindgen_ty_1 { xbool: b as i32 } })
     }
 
     /// Construct a variant containing a missing entry. This is used in function calls to
@@ -53,52 +79,51 @@ impl Variant {
     }
 
     /// Construct a variant containing an error. This is used in Excel to represent standard errors
-    /// that are shown as #DIV0 etc. Currently supported error codes are:
-    /// xlerrNull, xlerrDiv0, xlerrValue, xlerrRef, xlerrName, xlerrNum, xlerrNA, xlerrGettingData
-    pub fn from_err(xlerr: u32) -> Variant {
-        Variant(XLOPER12 { xltype : xltypeErr, val: xloper12__bindgen_ty_1 { err: xlerr as i32 } })
+    /// that are shown as #DIV/0! etc.
+    pub fn from_err(xlerr: XlError) -> Variant {
+        Variant(XLOPER12 { xltype : xltypeErr, val: xloper12__bindgen_ty_1 { err: xlerr.code() as i32 } })
     }
 
     /// Construct a variant containing a string. Strings in Excel (at least after Excel 97) are 16bit
     /// Unicode starting with a 16-bit length. The length is treated as signed, which means that
     /// strings can be no longer than 32k characters. If a string longer than this is supplied, or a 
-    /// string that is not valid 16bit Unicode, an xlerrValue error is stored instead.
+    /// string that is not valid 16bit Unicode, a #VALUE! error is stored instead.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Variant {
         let mut wstr : Vec<u16> = s.encode_utf16().collect();
         let len = wstr.len();
         if len > 32767 {
-            return Variant::from_err(xlerrValue)
+            return Variant::from_err(XlError::Value)
         }
 
-        // Pascal-style string with length at the start. Forget the string so we do not delete it.
-        // We are now relying on the drop method of Variant to clean it up for us. Note that the
-        // shrink_to_fit is essential, so the capacity is the same as the length. We have no way
-        // of storing the capacity otherwise.
+        // Pascal-style string with length at the start. Convert to a boxed slice and forget it,
+        // so we do not delete it. We are now relying on the drop method of Variant to clean it
+        // up for us. The boxed slice is essential, so the capacity is the same as the length.
+        // We have no way of storing the capacity otherwise.
         wstr.insert(0, len as u16);
-        wstr.shrink_to_fit();
-        let p = wstr.as_mut_ptr();
-        mem::forget(wstr);
+        let p = Box::into_raw(wstr.into_boxed_slice()) as *mut u16;
   
         Variant(XLOPER12 { xltype : xltypeStr + xlbitDLLFree, val: xloper12__bindgen_ty_1 { str: p } })
     }
 
     /// Constructs an XLOPER that contains an array of XLOPERs. The data should match the rows
-    /// and columns.
+    /// and columns. If there is too little data, the remaining elements are #N/A.
     pub fn from_array(cols: usize, rows: usize, data: &[Variant]) -> Variant {
         let size = cols * rows;
-        let data_len = data.len();
-        if data_len > size {
+        if data.len() > size {
             return Variant::from_str("Error: variant data size greater than array size")
         }
-        let mut array = vec![Variant::from_err(xlerrNA); size];
-        for (i, src) in data.iter().enumerate() {
-            let xloper = Box::new(src.clone());
-            let raw_xloper = Box::into_raw(xloper) as LPXLOPER12;
-            array[i] = Variant::from_xloper(raw_xloper);
-        }
+        let mut array = data.to_vec();
+        array.resize_with(size, || Variant::from_err(XlError::NA));
+        Variant::from_vec(cols, rows, array)
+    }
 
-        let lparray = array.as_mut_ptr() as LPXLOPER12;
-        mem::forget(array);
+    // Takes ownership of a vector of elements, stored row by row, which must hold exactly
+    // cols * rows of them. Drop reconstructs the vector from the pointer and size, so the
+    // capacity must match the length.
+    pub(crate) fn from_vec(cols: usize, rows: usize, array: Vec<Variant>) -> Variant {
+        debug_assert_eq!(array.len(), cols * rows);
+        let lparray = Box::into_raw(array.into_boxed_slice()) as LPXLOPER12;
 
         Variant(XLOPER12 { 
             xltype : xltypeMulti + xlbitDLLFree, 
@@ -107,6 +132,63 @@ impl Variant {
                     lparray, rows : rows as i32, columns : cols as i32 } } })
     }
 
+    // Makes a deep copy of any XLOPER12, so that all the memory it points to is owned by
+    // the copy. Big data and unrecognised types cannot be copied and become #VALUE!.
+    pub(crate) fn copy_of(xloper: &XLOPER12) -> Variant {
+        let view = VariantRef::new(xloper);
+        match xloper.xltype & xltypeMask {
+            xltypeStr => {
+                let wstr = view.as_wstr().unwrap_or(&[]);
+                Variant::from_wstr(wstr)
+            },
+            xltypeMulti => {
+                let array = view.array();
+                Variant::from_vec(array.columns(), array.rows(), array.iter().map(|cell| cell.to_variant()).collect())
+            },
+            xltypeRef => unsafe {
+                let mref = xloper.val.mref.lpmref;
+                let refs = if mref.is_null() {
+                    &[][..]
+                } else {
+                    slice::from_raw_parts(ptr::addr_of!((*mref).reftbl) as *const XLREF12, (*mref).count as usize)
+                };
+                Variant::from_refs(xloper.val.mref.idSheet, refs)
+            },
+            xltype @ (xltypeNum | xltypeInt | xltypeBool | xltypeErr | xltypeNil | xltypeMissing
+                | xltypeSRef | xltypeFlow) => Variant(XLOPER12 { xltype, val: xloper.val }),
+            _ => Variant::from_err(XlError::Value)
+        }
+    }
+
+    // Constructs a string from 16bit Unicode, truncating it at Excel's limit of 32767.
+    fn from_wstr(wstr: &[u16]) -> Variant {
+        let len = wstr.len().min(32767);
+        let mut owned = Vec::with_capacity(len + 1);
+        owned.push(len as u16);
+        owned.extend_from_slice(&wstr[..len]);
+        let p = Box::into_raw(owned.into_boxed_slice()) as *mut u16;
+        Variant(XLOPER12 { xltype : xltypeStr + xlbitDLLFree, val: xloper12__bindgen_ty_1 { str: p } })
+    }
+
+    /// Construct a variant containing a reference to one or more areas of a sheet. Excel
+    /// uses these, for example, to say which cell is calling a function.
+    pub fn from_refs(sheet: IDSHEET, refs: &[XLREF12]) -> Variant {
+        let layout = mref_layout(refs.len());
+        let mref = unsafe {
+            let mref = alloc::alloc(layout) as *mut XLMREF12;
+            if mref.is_null() {
+                alloc::handle_alloc_error(layout);
+            }
+            ptr::addr_of_mut!((*mref).count).write(refs.len() as u16);
+            ptr::copy_nonoverlapping(refs.as_ptr(), ptr::addr_of_mut!((*mref).reftbl) as *mut XLREF12, refs.len());
+            mref
+        };
+        Variant(XLOPER12 {
+            xltype : xltypeRef + xlbitDLLFree,
+            val: xloper12__bindgen_ty_1 {
+                mref: xloper12__bindgen_ty_1__bindgen_ty_2 { lpmref: mref, idSheet: sheet } } })
+    }
+
     /// Construct a variant containing an array from a slice of other variants. The variants
     /// may contain arrays or scalar strings or numbers, which are treated like single-cell 
     /// arrays. They are glued either horizontally (horiz=true) or vertically. If the arrays
@@ -141,27 +223,23 @@ impl Variant {
 
         // now clone the components into place
         let size = rows * columns;
-        let mut array = vec![Variant::from_err(xlerrNA); size];
+        let mut array = vec![Variant::from_err(XlError::NA); size];
         let mut col = 0;
         let mut row = 0;
         for var in from.iter() {
             match var.0.xltype & xltypeMask {
-                xltypeMulti => unsafe {
-                    let p = var.0.val.array.lparray;
-                    let var_cols = var.0.val.array.columns as usize;
-                    let var_rows = var.0.val.array.rows as usize;
-                    for x in 0..var_cols {
-                        for y in 0..var_rows {
-                            let src = (y * var_cols + x) as isize;
-                            let dest = (row + y) * columns + col + x;
-                            array[dest] = Variant::from_xloper(p.offset(src)).clone();
+                xltypeMulti => {
+                    let elements = var.view().array();
+                    for y in 0..elements.rows() {
+                        for (x, element) in elements.row(y).into_iter().flatten().enumerate() {
+                            array[(row + y) * columns + col + x] = element.to_variant();
                         }
                     }
 
                     if horiz {
-                        col += var_cols;
+                        col += elements.columns();
                     } else {
-                        row += var_rows;
+                        row += elements.rows();
                     }
                 },
                 xltypeMissing => {},
@@ -177,14 +255,7 @@ impl Variant {
             }
         }
 
-        let lparray = array.as_mut_ptr() as LPXLOPER12;
-        mem::forget(array);
-
-        Variant(XLOPER12 { 
-            xltype : xltypeMulti, 
-            val: xloper12__bindgen_ty_1 {
-                array: xloper12__bindgen_ty_1__bindgen_ty_3 {
-                    lparray, rows : rows as i32, columns : columns as i32 } } })
+        Variant::from_vec(columns, rows, array)
     }
 
     /// Creates a transposed clone of this Variant. If this Variant is a scalar type,
@@ -205,22 +276,15 @@ impl Variant {
         let len = dim.0 * dim.1;
         let mut array = Vec::with_capacity(len);
 
-        // Copy the elements transposed, cloning each one
-        for i in 0..dim.1 {
-            for j in 0..dim.0 {
-                array.push(self.at(j, i));
+        // Copy the elements transposed, cloning each one. Each column of this array
+        // becomes a row of the result.
+        for column in 0..dim.0 {
+            for row in 0..dim.1 {
+                array.push(self.at(column, row));
             }
         }
 
-        // Return as a Variant
-        let lparray = array.as_mut_ptr() as LPXLOPER12;
-        mem::forget(array);
-
-        Variant(XLOPER12 { 
-            xltype : xltypeMulti, 
-            val: xloper12__bindgen_ty_1 {
-                array: xloper12__bindgen_ty_1__bindgen_ty_3 {
-                    lparray, rows : dim.0 as i32, columns : dim.1 as i32 } } })
+        Variant::from_vec(dim.1, dim.0, array)
     }
 
     /// Converts this variant to a string. Alternatively, you can use Display or to_string,
@@ -263,33 +327,99 @@ impl Variant {
         }
     }
 
+    /// Converts this variant to a bool. If we do not contain a boolean, return None. Note
+    /// that Excel treats any non-zero value in a boolean XLOPER12 as TRUE.
+    pub fn as_bool(&self) -> Option<bool> {
+        if (self.0.xltype & xltypeMask) != xltypeBool {
+            None
+        } else {
+            Some(unsafe { self.0.val.xbool } != 0)
+        }
+    }
+
+    /// Converts this variant to an error. If we do not contain an error, or the error code
+    /// is not one that Excel defines, return None.
+    pub fn as_error(&self) -> Option<XlError> {
+        if (self.0.xltype & xltypeMask) != xltypeErr {
+            None
+        } else {
+            XlError::from_code(unsafe { self.0.val.err } as u32)
+        }
+    }
+
     /// Does this variant represent a missing entry?
     pub fn is_missing(&self) -> bool {
-        return self.0.xltype & xltypeMissing == xltypeMissing
+        self.0.xltype & xltypeMissing == xltypeMissing
     }
 
-    /// Exposes the underlying XLOPER12
-    pub fn as_mut_xloper(&mut self) -> &mut XLOPER12 {
+    /// Borrows this variant as a VariantRef, for reading without copying
+    pub fn view(&self) -> VariantRef<'_> {
+        VariantRef::new(&self.0)
+    }
+
+    /// Exposes the underlying XLOPER12 for reading
+    pub fn as_xloper(&self) -> &XLOPER12 {
+        &self.0
+    }
+
+    // Exposes the underlying XLOPER12, for example to pass as an argument to Excel. Anything
+    // written into it becomes owned by this Variant, and is freed as a Variant would free
+    // it, so it must have been made by Variant.
+    pub(crate) fn as_mut_xloper(&mut self) -> &mut XLOPER12 {
         &mut self.0
     }
 
+    /// Gives up ownership of the underlying XLOPER12, for example so that it can be written
+    /// into the result of a call handled by an ExcelBackend. Whoever receives it becomes
+    /// responsible for any string or array it contains.
+    pub fn into_xloper(self) -> XLOPER12 {
+        let xloper = self.0;
+        mem::forget(self);
+        xloper
+    }
+
+    /// Hands this variant to Excel as the result of a UDF. It is moved to the heap and
+    /// marked with xlbitDLLFree, so once Excel has copied it, Excel calls xlAutoFree12,
+    /// which frees it along with any string or array it contains. This is the safe default
+    /// for any UDF, including threadsafe ones:
+    ///
+    /// ```
+    /// # use xladd::variant::Variant;
+    /// # use xladd::xlcall::LPXLOPER12;
+    /// extern "system" fn greet() -> LPXLOPER12 {
+    ///     Variant::from_str("hello").into_excel_return()
+    /// }
+    /// ```
+    pub fn into_excel_return(mut self) -> LPXLOPER12 {
+        self.0.xltype |= xlbitDLLFree;
+        Box::into_raw(Box::new(self)) as LPXLOPER12
+    }
+
+    /// Hands this variant to Excel as the result of a UDF, by parking it in a slot owned by
+    /// the calling thread. Excel copies a result as soon as the UDF returns, before it calls
+    /// anything else on that thread, so the slot only has to live until the next UDF on the
+    /// same thread returns, which drops it. Excel is not asked to call xlAutoFree12, which
+    /// saves a heap allocation and a callback for every call. As each calculation thread has
+    /// its own slot, this is safe for threadsafe UDFs too.
+    pub fn into_excel_return_slot(mut self) -> LPXLOPER12 {
+        self.0.xltype &= !xlbitDLLFree;
+        RETURN_SLOT.with(|slot| {
+            let mut slot = slot.borrow_mut();
+            *slot = self;
+            slot.as_mut_xloper() as LPXLOPER12
+        })
+    }
+
     /// Is this a cell reference?
     pub fn is_ref(&self) -> bool {
-        let xltype = self.0.xltype & xltypeMissing;
-        return xltype == xltypeRef || xltype == xltypeSRef
+        let xltype = self.0.xltype & xltypeMask;
+        xltype == xltypeRef || xltype == xltypeSRef
     }
 
     /// Gets the count of rows and columns. Scalars are treated as 1x1. Missing values are
     /// treated as 0x0.
     pub fn dim(&self) -> (usize, usize) {
-        match self.0.xltype & xltypeMask {
-            xltypeMulti => unsafe { (self.0.val.array.columns as usize, self.0.val.array.rows as usize) },
-            xltypeMissing => (0, 0),
-            xltypeSRef => get_sref_dim(unsafe { &self.0.val.sref.ref_ }),
-            xltypeRef => get_mref_dim(unsafe { self.0.val.mref.lpmref }),
-
-            _ => (1, 1)
-        }
+        self.view().dim()
     }
 
     /// Gets the element at the given column and row. If this is a scalar, treat it as a one-element
@@ -300,80 +430,44 @@ impl Variant {
             if column == 0 && row == 0 {
                 self.clone()
             } else {
-                Self::from_err(xlerrNA)
+                Self::from_err(XlError::NA)
             }
         } else {
             let (columns, rows) = unsafe {
                 (self.0.val.array.columns as usize, self.0.val.array.rows as usize) };
             if column >= columns || row >= rows {
-                Self::from_err(xlerrNA)
+                Self::from_err(XlError::NA)
             } else {
                 let index = row * columns + column;
-                Self::from_xloper( unsafe {
-                    self.0.val.array.lparray.offset(index as isize) }).clone()
+                Self::copy_of(unsafe { &*self.0.val.array.lparray.add(index) })
             }
         }
     }
 }
 
-// Gets the array size of a multi-cell reference. If the reference is badly formed,
-// returns (0, 0)
-fn get_mref_dim(mref: * const XLMREF12) -> (usize, usize) {
-    // currently we only handle single contiguous references
-    if mref.is_null() || unsafe { (*mref).count } != 1 {
-        return (0, 0)
+impl Default for Variant {
+    fn default() -> Variant {
+        Variant::new()
     }
-
-    return get_sref_dim(unsafe { &(*mref).reftbl[0] })
-}
-
-// Gets the array size of a single-cell reference
-fn get_sref_dim(sref: &XLREF12) -> (usize, usize) {
-    let rows = 1 + (sref.rwLast - sref.rwFirst) as usize;
-    let cols = 1 + (sref.colLast - sref.colFirst) as usize;
-    (cols, rows)
 }
 
 /// Implement Display, which means we do not need a method for converting to strings. Just use
 /// to_string.
 impl fmt::Display for Variant {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.0.xltype & xltypeMask {
-            xltypeErr => match unsafe { self.0.val.err } as u32 {
-                xlerrNull => write!(f, "#NULL"),
-                xlerrDiv0 => write!(f, "#DIV0"),
-                xlerrValue => write!(f, "#VALUE"),
-                xlerrRef => write!(f, "#REF"),
-                xlerrName => write!(f, "#NAME"),
-                xlerrNum => write!(f, "#NUM"),
-                xlerrNA => write!(f, "#NA"),
-                xlerrGettingData => write!(f, "#DATA"),
-                _ => write!(f, "#BAD_ERR")
-            }
-            xltypeInt => write!(f, "{}", unsafe { self.0.val.w }),
-            xltypeMissing => write!(f, "#MISSING"),
-            xltypeMulti => write!(f, "#MULTI"),
-            xltypeNil => write!(f, "#NIL"),
-            xltypeNum => write!(f, "{}", unsafe { self.0.val.num }),
-            xltypeStr => write!(f, "{}", self.as_string().unwrap()),
-            _ => write!(f, "#BAD_XLOPER")
-        }
+        fmt::Display::fmt(&self.view(), f)
     }
 }
 
 /// We need to implement Drop, as Variant is a wrapper around a union type that does
-/// not know how to handle its contained pointers.
+/// not know how to handle its contained pointers. A Variant owns all of them, so what to
+/// free depends only on the type.
 impl Drop for Variant {
     fn drop(&mut self) {
-        if (self.0.xltype & xlbitXLFree) != 0 {
-            excel_free(&mut self.0);
-            return
-        }
-        
-        match self.0.xltype {
-            xltypeStr_xlbitDLLFree => {
-                // We have a 16bit string that was originally allocated as a vector
-                // but then forgotten. Reconstruct the vector, so its drop method
+        match self.0.xltype & xltypeMask {
+            xltypeStr => {
+                // We have a 16bit string that was originally allocated as a boxed slice
+                // but then forgotten. Reconstruct it as a vector, so its drop method
                 // will clean up the memory for us.
                 unsafe {
                     let p = self.0.val.str;
@@ -382,9 +476,9 @@ impl Drop for Variant {
                     Vec::from_raw_parts(p, len, cap);
                 }
             },
-            xltypeMulti_xlbitDLLFree => {
-                // We have an array that was originally allocated as a vector of
-                // Variant but then forgotten. Reconstruct the vector, so its drop method
+            xltypeMulti => {
+                // We have an array that was originally allocated as a boxed slice of
+                // Variant but then forgotten. Reconstruct it as a vector, so its drop method
                 // will clean up the vector and its elements for us.
                 unsafe {
                     let p = self.0.val.array.lparray as *mut Variant;
@@ -393,6 +487,12 @@ impl Drop for Variant {
                     Vec::from_raw_parts(p, len, cap);
                 }
             },
+            xltypeRef => {
+                unsafe {
+                    let mref = self.0.val.mref.lpmref;
+                    alloc::dealloc(mref as *mut u8, mref_layout((*mref).count as usize));
+                }
+            },
             _ => {
                 // nothing to do
             }
@@ -400,56 +500,112 @@ impl Drop for Variant {
     }
 }
 
-/// We need to hand-code Clone, because of the ownership issues for strings and multi.
+/// Cloning makes a deep copy, so the clone owns its own strings and arrays
 impl Clone for Variant {
     fn clone(&self) -> Variant {
-        // a simple copy is good enough for most variant types, but make sure the addin
-        // is the owner
-        let mut copy = Variant(self.0.clone());
-        copy.0.xltype &= !xlbitXLFree;
-        copy.0.xltype |= xlbitDLLFree;
-
-        // Special handling for string and mult, to avoid double delete of the member
-        match copy.0.xltype {
-            xltypeStr_xlbitDLLFree => {
-
-                // We have a 16bit string that was originally allocated as a vector
-                // but then forgotten. Reconstruct the vector, so we can clone it.
-                unsafe {
-                    let p = copy.0.val.str;
-                    let len = *p as usize + 1;
-                    let cap = len;
-                    let string_vec = Vec::from_raw_parts(p, len, cap);
-                    let mut cloned = string_vec.clone();
-                    copy.0.val.str = cloned.as_mut_ptr();
+        Variant::copy_of(&self.0)
+    }
+}
 
-                    // now forget everything -- we do not want either string deallocated
-                    mem::forget(string_vec);
-                    mem::forget(cloned);
-                }
-            },
-            xltypeMulti_xlbitDLLFree => {
+// The layout of an XLMREF12 holding the given number of areas. The struct is declared
+// with one area, but Excel treats it as having as many as its count.
+fn mref_layout(count: usize) -> alloc::Layout {
+    let size = mem::size_of::<XLMREF12>() + count.saturating_sub(1) * mem::size_of::<XLREF12>();
+    alloc::Layout::from_size_align(size, mem::align_of::<XLMREF12>()).unwrap()
+}
 
-                // We have an array that was originally allocated as a vector
-                // but then forgotten. Reconstruct the vector, so we can clone it.
-                unsafe {
-                    let p = self.0.val.array.lparray as *mut Variant;
-                    let len = (self.0.val.array.rows * self.0.val.array.columns) as usize;
-                    let cap = len;
-                    let array = Vec::from_raw_parts(p, len, cap);
-                    let mut cloned = array.clone();
-                    copy.0.val.array.lparray = cloned.as_mut_ptr() as LPXLOPER12;
+impl XlOwnedVariant {
+    /// Takes charge of an XLOPER12 returned by Excel, such as the result of excel12v, so
+    /// that it is freed when dropped. If it is marked with xlbitXLFree, it is given back
+    /// to Excel through xlFree. If it is marked with xlbitDLLFree, which a backend may do
+    /// to hand over a value it made as a Variant, it is freed as a Variant would be.
+    ///
+    /// # Safety
+    ///
+    /// The XLOPER12 must have come from Excel or the installed backend, and must not be
+    /// freed by anyone else.
+    pub unsafe fn from_result(xloper: XLOPER12) -> XlOwnedVariant {
+        XlOwnedVariant(xloper)
+    }
 
-                    // now forget everything -- we do not want either string deallocated
-                    mem::forget(array);
-                    mem::forget(cloned);
-                }
-            },
-            _ => {
-                // nothing to do
-            }
+    /// Borrows the value, for reading without copying
+    pub fn view(&self) -> VariantRef<'_> {
+        VariantRef::new(&self.0)
+    }
+
+    /// Converts to a Variant, copying anything Excel owns and then freeing it
+    pub fn into_variant(self) -> Variant {
+        if self.0.xltype & xlbitDLLFree != 0 {
+            let variant = Variant(self.0);
+            mem::forget(self);
+            variant
+        } else {
+            Variant::copy_of(&self.0)
         }
+    }
+}
 
-        copy
+impl Drop for XlOwnedVariant {
+    fn drop(&mut self) {
+        if self.0.xltype & xlbitXLFree != 0 {
+            excel_free(&mut self.0);
+        } else if self.0.xltype & xlbitDLLFree != 0 {
+            drop(Variant(self.0));
+        }
+    }
+}
+
+impl fmt::Display for XlOwnedVariant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.view(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn booleans() {
+        let t = Variant::from_bool(true);
+        let f = Variant::from_bool(false);
+        assert_eq!(t.as_bool(), Some(true));
+        assert_eq!(f.as_bool(), Some(false));
+        assert_eq!(Variant::from_float(1.0).as_bool(), None);
+        assert_eq!(t.to_string(), "TRUE");
+        assert_eq!(f.clone().to_string(), "FALSE");
+
+        let array = Variant::concat(&[t.clone(), f.clone(), Variant::from_float(2.0)], true);
+        assert_eq!(array.dim(), (3, 2));
+        assert_eq!(array.at(0, 0).as_bool(), Some(true));
+        assert_eq!(array.at(1, 0).as_bool(), Some(false));
+        assert_eq!(array.at(0, 1).to_string(), "#N/A");
+
+        let transposed = array.transpose();
+        assert_eq!(transposed.dim(), (2, 3));
+        assert_eq!(transposed.at(0, 1).as_bool(), Some(false));
+        assert_eq!(t.at(0, 0).as_bool(), Some(true));
+    }
+
+    #[test]
+    fn references() {
+        let areas = [
+            XLREF12 { rwFirst: 0, rwLast: 2, colFirst: 1, colLast: 1 },
+            XLREF12 { rwFirst: 5, rwLast: 5, colFirst: 0, colLast: 3 }];
+        let multi = Variant::from_refs(7 as IDSHEET, &areas);
+        let single = Variant::from_refs(7 as IDSHEET, &areas[..1]);
+        assert!(multi.is_ref());
+        assert_eq!(single.dim(), (1, 3));
+        assert_eq!(multi.dim(), (0, 0));
+
+        // a clone has its own copy of the areas
+        let copy = multi.clone();
+        drop(multi);
+        unsafe {
+            let mref = copy.as_xloper().val.mref.lpmref;
+            assert_eq!((*mref).count, 2);
+            let second = *(ptr::addr_of!((*mref).reftbl) as *const XLREF12).add(1);
+            assert_eq!((second.rwFirst, second.colLast), (5, 3));
+        }
     }
-}
\ No newline at end of file
+}
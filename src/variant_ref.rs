@@ -0,0 +1,356 @@
+//! A borrowed, read-only view of an XLOPER12, for reading the arguments that Excel passes
+//! to a UDF without copying them. Variant::from_xloper makes a deep copy of the XLOPER12,
+//! and Variant::at clones each element. VariantRef instead borrows the XLOPER12, so
+//! strings can be read as slices of the original 16bit text and arrays can be walked cell
+//! by cell, with no allocation at all.
+//!
+//! VariantRef has the same layout as a pointer to an XLOPER12, so a UDF can take its
+//! arguments as VariantRef rather than LPXLOPER12 (registered with type `Q`):
+//!
+//! ```
+//! # use xladd::variant::Variant;
+//! # use xladd::variant_ref::VariantRef;
+//! # use xladd::xlcall::LPXLOPER12;
+//! extern "system" fn sum_positive(values: VariantRef) -> LPXLOPER12 {
+//!     let total: f64 = values.array().iter()
+//!         .filter_map(|cell| cell.as_f64())
+//!         .filter(|&value| value > 0.0)
+//!         .sum();
+//!     Variant::from_float(total).into_excel_return()
+//! }
+//! ```
+//!
+//! The lifetime of each argument is then that of the call, so the borrow checker rejects
+//! any attempt to keep one, or anything borrowed from it, after the UDF returns. Use
+//! `to_variant` to take a copy that can be kept.
+
+#![allow(non_upper_case_globals)]
+
+use std::borrow::Cow;
+use std::{char, fmt, slice};
+use variant::Variant;
+use xlcall::{XLOPER12, LPXLOPER12, XLREF12, XLMREF12, xltypeNil, xltypeInt, xltypeBool, xltypeStr, xltypeErr,
+    xltypeMissing, xltypeNum, xltypeMulti, xltypeRef, xltypeSRef, xlbitDLLFree, xlbitXLFree};
+use xlerror::XlError;
+
+const xltypeMask: u32 = !(xlbitDLLFree | xlbitXLFree);
+
+/// A borrowed view of a value owned by someone else, typically Excel. It can be copied
+/// freely, as it is just a reference.
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+pub struct VariantRef<'a>(&'a XLOPER12);
+
+/// A borrowed view of a two dimensional array, stored row by row. Scalars are treated as
+/// single-cell arrays, in the same way as Variant::dim.
+#[derive(Clone, Copy)]
+pub struct ArrayRef<'a> {
+    cells: &'a [XLOPER12],
+    columns: usize,
+    rows: usize,
+}
+
+impl<'a> VariantRef<'a> {
+    /// Borrows an XLOPER12
+    pub fn new(xloper: &'a XLOPER12) -> VariantRef<'a> {
+        VariantRef(xloper)
+    }
+
+    /// Borrows an XLOPER12 through a raw pointer, for example one passed to a UDF as an
+    /// LPXLOPER12.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be non-null and point to a valid XLOPER12, which must not be changed
+    /// or freed for the whole of the lifetime 'a. Prefer taking VariantRef arguments
+    /// directly, which lets the compiler choose the lifetime.
+    pub unsafe fn from_raw(xloper: LPXLOPER12) -> VariantRef<'a> {
+        VariantRef(&*xloper)
+    }
+
+    /// Exposes the underlying XLOPER12
+    pub fn as_xloper(&self) -> &'a XLOPER12 {
+        self.0
+    }
+
+    /// The type of the value, without any ownership bits
+    fn xltype(&self) -> u32 {
+        self.0.xltype & xltypeMask
+    }
+
+    /// Is this a nil value, as found in an empty cell?
+    pub fn is_nil(&self) -> bool {
+        self.xltype() == xltypeNil
+    }
+
+    /// Is this a missing argument?
+    pub fn is_missing(&self) -> bool {
+        self.xltype() == xltypeMissing
+    }
+
+    /// Is this a cell reference?
+    pub fn is_ref(&self) -> bool {
+        let xltype = self.xltype();
+        xltype == xltypeRef || xltype == xltypeSRef
+    }
+
+    /// Reads a float. If we do not contain a float, return None.
+    pub fn as_f64(&self) -> Option<f64> {
+        if self.xltype() == xltypeNum { Some(unsafe { self.0.val.num }) } else { None }
+    }
+
+    /// Reads an int. If we do not contain an int, return None.
+    pub fn as_i32(&self) -> Option<i32> {
+        if self.xltype() == xltypeInt { Some(unsafe { self.0.val.w }) } else { None }
+    }
+
+    /// Reads a boolean. If we do not contain a boolean, return None.
+    pub fn as_bool(&self) -> Option<bool> {
+        if self.xltype() == xltypeBool { Some(unsafe { self.0.val.xbool } != 0) } else { None }
+    }
+
+    /// Reads an error. If we do not contain an error Excel defines, return None.
+    pub fn as_error(&self) -> Option<XlError> {
+        if self.xltype() == xltypeErr {
+            XlError::from_code(unsafe { self.0.val.err } as u32)
+        } else {
+            None
+        }
+    }
+
+    /// Borrows a string as the 16bit Unicode that Excel stores, without its length
+    /// prefix. If we do not contain a string, return None.
+    pub fn as_wstr(&self) -> Option<&'a [u16]> {
+        if self.xltype() != xltypeStr {
+            return None
+        }
+        unsafe {
+            let p: *const u16 = self.0.val.str;
+            Some(slice::from_raw_parts(p.offset(1), *p as usize))
+        }
+    }
+
+    /// Reads a string. The text has to be decoded from 16bit Unicode, which means a new
+    /// String, except for the empty string. Text that is not valid 16bit Unicode has the
+    /// replacement character in place of the bad code units. To compare strings or scan
+    /// them without allocating, use `as_wstr` or `chars`.
+    pub fn as_str(&self) -> Option<Cow<'a, str>> {
+        self.as_wstr().map(|wstr| {
+            if wstr.is_empty() {
+                Cow::Borrowed("")
+            } else {
+                Cow::Owned(String::from_utf16_lossy(wstr))
+            }
+        })
+    }
+
+    /// Iterates over the characters of a string, decoding as it goes. If we do not contain
+    /// a string, return None.
+    pub fn chars(&self) -> Option<impl Iterator<Item = char> + 'a> {
+        self.as_wstr().map(|wstr| char::decode_utf16(wstr.iter().cloned())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)))
+    }
+
+    /// Views the value as an array. Arrays are viewed as they are, missing values as an
+    /// empty array, and anything else, including references, as a single-cell array.
+    pub fn array(&self) -> ArrayRef<'a> {
+        match self.xltype() {
+            xltypeMulti => unsafe {
+                let columns = self.0.val.array.columns as usize;
+                let rows = self.0.val.array.rows as usize;
+                let lparray = self.0.val.array.lparray;
+                if lparray.is_null() || columns == 0 || rows == 0 {
+                    ArrayRef { cells: &[], columns: 0, rows: 0 }
+                } else {
+                    ArrayRef { cells: slice::from_raw_parts(lparray as *const XLOPER12, columns * rows), columns, rows }
+                }
+            },
+            xltypeMissing => ArrayRef { cells: &[], columns: 0, rows: 0 },
+            _ => ArrayRef { cells: slice::from_ref(self.0), columns: 1, rows: 1 }
+        }
+    }
+
+    /// Gets the count of columns and rows. Scalars are treated as 1x1 and missing values
+    /// as 0x0. For a reference, this is the size of the area referred to.
+    pub fn dim(&self) -> (usize, usize) {
+        match self.xltype() {
+            xltypeSRef => get_sref_dim(unsafe { &self.0.val.sref.ref_ }),
+            xltypeRef => get_mref_dim(unsafe { self.0.val.mref.lpmref }),
+            _ => {
+                let array = self.array();
+                (array.columns, array.rows)
+            }
+        }
+    }
+
+    /// Borrows the element at the given column and row, treating a scalar as a single-cell
+    /// array. Returns None if the column or row is out of bounds.
+    pub fn at(&self, column: usize, row: usize) -> Option<VariantRef<'a>> {
+        self.array().get(column, row)
+    }
+
+    /// Makes an owned copy of the value, which can be kept after the borrow ends. The copy
+    /// is deep, so strings, arrays and the areas of a reference are copied too, and nothing
+    /// in it points into Excel's memory.
+    pub fn to_variant(&self) -> Variant {
+        Variant::copy_of(self.0)
+    }
+}
+
+// Gets the array size of a multi-cell reference. If the reference is badly formed,
+// returns (0, 0)
+fn get_mref_dim(mref: * const XLMREF12) -> (usize, usize) {
+    // currently we only handle single contiguous references
+    if mref.is_null() || unsafe { (*mref).count } != 1 {
+        return (0, 0)
+    }
+
+    get_sref_dim(unsafe { &(*mref).reftbl[0] })
+}
+
+// Gets the array size of a single-cell reference
+fn get_sref_dim(sref: &XLREF12) -> (usize, usize) {
+    let rows = 1 + (sref.rwLast - sref.rwFirst) as usize;
+    let cols = 1 + (sref.colLast - sref.colFirst) as usize;
+    (cols, rows)
+}
+
+impl<'a> From<&'a Variant> for VariantRef<'a> {
+    fn from(variant: &'a Variant) -> VariantRef<'a> {
+        variant.view()
+    }
+}
+
+/// Displays the value in the same way as Variant, but without allocating
+impl fmt::Display for VariantRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.xltype() {
+            xltypeErr => match self.as_error() {
+                Some(err) => write!(f, "{}", err),
+                None => write!(f, "#BAD_ERR")
+            }
+            xltypeInt => write!(f, "{}", unsafe { self.0.val.w }),
+            xltypeBool => write!(f, "{}", if unsafe { self.0.val.xbool } != 0 { "TRUE" } else { "FALSE" }),
+            xltypeMissing => write!(f, "#MISSING"),
+            xltypeMulti => write!(f, "#MULTI"),
+            xltypeNil => write!(f, "#NIL"),
+            xltypeNum => write!(f, "{}", unsafe { self.0.val.num }),
+            xltypeStr => {
+                for c in self.chars().into_iter().flatten() {
+                    fmt::Write::write_char(f, c)?;
+                }
+                Ok(())
+            },
+            _ => write!(f, "#BAD_XLOPER")
+        }
+    }
+}
+
+impl fmt::Debug for VariantRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VariantRef({})", self)
+    }
+}
+
+impl<'a> ArrayRef<'a> {
+    /// The number of columns
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    /// The number of rows
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Is the array empty?
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// Borrows the element at the given column and row, or None if out of bounds
+    pub fn get(&self, column: usize, row: usize) -> Option<VariantRef<'a>> {
+        if column >= self.columns || row >= self.rows {
+            None
+        } else {
+            self.cells.get(row * self.columns + column).map(VariantRef)
+        }
+    }
+
+    /// Iterates over every element, row by row
+    pub fn iter(&self) -> impl Iterator<Item = VariantRef<'a>> + 'a {
+        self.cells.iter().map(VariantRef)
+    }
+
+    /// Iterates over the rows. Each row is an iterator over its elements.
+    pub fn rows_iter(&self) -> impl Iterator<Item = impl Iterator<Item = VariantRef<'a>> + 'a> + 'a {
+        self.cells.chunks(self.columns.max(1)).map(|row| row.iter().map(VariantRef))
+    }
+
+    /// Borrows a row, or None if out of bounds
+    pub fn row(&self, row: usize) -> Option<impl Iterator<Item = VariantRef<'a>> + 'a> {
+        if row >= self.rows {
+            None
+        } else {
+            let start = row * self.columns;
+            Some(self.cells[start..start + self.columns].iter().map(VariantRef))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scalars() {
+        let text = Variant::from_str("héllo");
+        let view = text.view();
+        assert_eq!(view.as_wstr().map(|w| w.len()), Some(5));
+        assert_eq!(view.as_str().unwrap(), "héllo");
+        assert_eq!(view.chars().unwrap().filter(|&c| c == 'l').count(), 2);
+        assert_eq!(view.to_string(), "héllo");
+        assert_eq!(view.to_variant().as_string(), Some("héllo".to_string()));
+        assert_eq!(view.as_f64(), None);
+
+        let num = Variant::from_float(2.5);
+        assert_eq!(num.view().as_f64(), Some(2.5));
+        assert_eq!(num.view().at(0, 0).and_then(|v| v.as_f64()), Some(2.5));
+        assert!(num.view().at(1, 0).is_none());
+        assert_eq!(Variant::from_err(XlError::NA).view().as_error(), Some(XlError::NA));
+        assert!(Variant::missing().view().array().is_empty());
+    }
+
+    #[test]
+    fn arrays() {
+        let array = Variant::from(vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]]);
+        let view = array.view();
+        assert_eq!(view.dim(), (3, 2));
+        let cells = view.array();
+        assert_eq!(cells.get(2, 1).and_then(|v| v.as_f64()), Some(6.0));
+        assert!(cells.get(3, 0).is_none());
+        let total: f64 = cells.iter().filter_map(|v| v.as_f64()).sum();
+        assert_eq!(total, 21.0);
+        let row_sums: Vec<f64> = cells.rows_iter().map(|row| row.filter_map(|v| v.as_f64()).sum()).collect();
+        assert_eq!(row_sums, vec![6.0, 15.0]);
+        assert_eq!(cells.row(1).unwrap().count(), 3);
+
+        let copy = view.to_variant();
+        drop(array);
+        assert_eq!(copy.at(1, 1).as_f64(), Some(5.0));
+    }
+
+    extern "system" fn count_text(values: VariantRef) -> i32 {
+        values.array().iter().filter(|v| v.as_wstr().is_some()).count() as i32
+    }
+
+    #[test]
+    fn udf_arguments() {
+        let values = Variant::from(vec![Variant::from("a"), Variant::from(1.0), Variant::from("b")]);
+        let p = values.as_xloper() as *const XLOPER12 as LPXLOPER12;
+        // This is how Excel calls a UDF that takes a VariantRef: it passes a pointer
+        let udf: extern "system" fn(LPXLOPER12) -> i32 = unsafe {
+            ::std::mem::transmute(count_text as extern "system" fn(VariantRef) -> i32) };
+        assert_eq!(udf(p), 2);
+    }
+}
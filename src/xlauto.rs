@@ -1,14 +1,172 @@
 //! Functions that are exported from the xll and invoked by Excel
 //! The only two essential functions are xlAutoOpen and xlAutoFree12.
 //! The first of these is implemented by the dll that uses xladd, as
-//! only it knows what it wants to export. Other xlAuto methods can be added
-//! here as required.
+//! only it knows what it wants to export. xladd itself exports xlAutoFree12.
+//!
+//! The simplest way to provide xlAutoOpen and the other optional functions is to
+//! implement the Addin trait and pass it to `xl_addin!`:
+//!
+//! ```
+//! # #[macro_use] extern crate xladd;
+//! use xladd::xlauto::Addin;
+//!
+//! struct MyAddin;
+//!
+//! impl Addin for MyAddin {
+//!     fn manager_info() -> Option<String> {
+//!         Some("My Analytics".to_string())
+//!     }
+//! }
+//!
+//! xl_addin!(MyAddin);
+//! # fn main() {}
+//! ```
 
+use std::convert::TryFrom;
+use async_udf;
+use events;
+use keys;
+use protect;
+use shared;
+use timers;
+use registrator::{Reg, unregister_all};
+use udf::register_all;
+use variant::Variant;
+use variant_ref::VariantRef;
 use xlcall::LPXLOPER12;
+use xlerror::XlError;
 
+/// The lifecycle of an addin. Each function is called by Excel through the matching
+/// export generated by `xl_addin!`, with panic protection, so a panic is reported and
+/// turned into a failure rather than taking Excel down. All have defaults, so an addin
+/// need only implement those it cares about.
+pub trait Addin {
+    /// Called by xlAutoOpen when the addin is loaded. This is where functions and commands
+    /// are registered. The default registers every function defined with `#[xl_func]`,
+    /// panicking if any cannot be registered, so that the reason is logged and xlAutoOpen
+    /// fails.
+    fn open(reg: &Reg) {
+        register_all(reg).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    /// Called by xlAutoClose when the addin is closed, before xladd unregisters everything
+    /// registered through Reg
+    fn close() {}
+
+    /// Called by xlAutoAdd when the user adds the addin in the Add-in Manager
+    fn add() {}
+
+    /// Called by xlAutoRemove when the user removes the addin in the Add-in Manager
+    fn remove() {}
+
+    /// The long name shown for the addin in the Add-in Manager, or None to show the name
+    /// of the file
+    fn manager_info() -> Option<String> {
+        None
+    }
+}
+
+/// Emits xlAutoOpen, xlAutoClose, xlAutoAdd, xlAutoRemove and xlAddInManagerInfo12 for a
+/// type that implements Addin. Use this at most once in an addin, and do not also export
+/// any of these functions by hand.
+#[macro_export]
+macro_rules! xl_addin {
+    ($addin:ty) => {
+        #[no_mangle]
+        pub extern "system" fn xlAutoOpen() -> i32 {
+            $crate::xlauto::open::<$addin>()
+        }
+
+        #[no_mangle]
+        pub extern "system" fn xlAutoClose() -> i32 {
+            $crate::xlauto::close::<$addin>()
+        }
+
+        #[no_mangle]
+        pub extern "system" fn xlAutoAdd() -> i32 {
+            $crate::xlauto::add::<$addin>()
+        }
+
+        #[no_mangle]
+        pub extern "system" fn xlAutoRemove() -> i32 {
+            $crate::xlauto::remove::<$addin>()
+        }
+
+        #[no_mangle]
+        pub extern "system" fn xlAddInManagerInfo12(action: $crate::variant_ref::VariantRef) -> $crate::xlcall::LPXLOPER12 {
+            $crate::xlauto::manager_info::<$addin>(action)
+        }
+    }
+}
+
+/// The body of xlAutoOpen generated by `xl_addin!`
+pub fn open<A: Addin>() -> i32 {
+    protect::auto("xlAutoOpen", || A::open(&Reg::new()))
+}
+
+/// The body of xlAutoAdd generated by `xl_addin!`
+pub fn add<A: Addin>() -> i32 {
+    protect::auto("xlAutoAdd", A::add)
+}
+
+/// The body of xlAutoRemove generated by `xl_addin!`
+pub fn remove<A: Addin>() -> i32 {
+    protect::auto("xlAutoRemove", A::remove)
+}
+
+/// The body of xlAddInManagerInfo12 generated by `xl_addin!`. Excel asks for the long
+/// name by passing an action of 1. Anything else gets #VALUE!, as does an addin with no
+/// long name, which makes Excel show the name of the file.
+pub fn manager_info<A: Addin>(action: VariantRef) -> LPXLOPER12 {
+    protect::value("xlAddInManagerInfo12", || {
+        match (i32::try_from(action), A::manager_info()) {
+            (Ok(1), Some(name)) => Variant::from_str(&name),
+            _ => Variant::from_err(XlError::Value)
+        }
+    }).into_excel_return_slot()
+}
+
+/// The body of xlAutoClose generated by `xl_addin!`. Runs the addin's `close`, then
+/// `close_all`.
+pub fn close<A: Addin>() -> i32 {
+    protect::auto("xlAutoClose", A::close) & close_all()
+}
+
+/// Undoes everything xladd has set up on behalf of the addin: cancels any asynchronous work
+/// and waits for the threads running it to stop, cancels any timers, unbinds any keys,
+/// removes the calculation event hooks and the objects this xll stored in the shared
+/// registry, then unregisters everything registered through Reg, so that no stale
+/// functions are left pointing at the dll once it is unloaded. This is also what makes it
+/// safe to rebuild and reload an addin during development. Returns 1 if it completes, or
+/// 0 if it panics.
+///
+/// The xlAutoClose generated by `xl_addin!` calls this. An addin that exports its own
+/// xlAutoClose should call it from there.
+///
+/// Note that when Excel is closing, xlAutoClose is called before the user is asked whether
+/// to save, so if they cancel, the functions are gone until the addin is reloaded.
+pub fn close_all() -> i32 {
+    protect::auto("xlAutoClose", || {
+        async_udf::shut_down();
+        shared::remove_owned();
+        events::clear();
+        timers::cancel_all();
+        keys::unbind_all();
+        unregister_all();
+    })
+}
+
+/// Called by Excel once it has copied a result marked with xlbitDLLFree. Such results are
+/// only ever made by Variant::into_excel_return, which boxes a Variant, so we can free
+/// the box and everything the Variant contains.
 #[no_mangle]
-pub extern "stdcall" fn xlAutoFree12(px_free: LPXLOPER12) {
-    // take ownership of this xloper. Then when our xloper goes
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "system" fn xlAutoFree12(px_free: LPXLOPER12) {
+    if px_free.is_null() {
+        return
+    }
+
+    // take ownership of this variant. Then when our variant goes
     // out of scope, its drop method will free any resources.
-    unsafe { Box::from_raw(px_free) };
+    drop(unsafe { Box::from_raw(px_free as *mut Variant) });
 }
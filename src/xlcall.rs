@@ -3,6 +3,9 @@
 
 // +EDIT
 #![allow(non_snake_case, non_camel_case_types, non_upper_case_globals)]
+
+// The layout tests originally took field addresses through a null pointer, which is
+// undefined behaviour. They now use offset_of!, which checks the same offsets safely.
 // -EDIT
 
 pub const xltypeNum: u32 = 1;
@@ -1070,7 +1073,7 @@ fn bindgen_test_layout_tagPOINT() {
         concat!("Alignment of ", stringify!(tagPOINT))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<tagPOINT>())).x as *const _ as usize },
+        ::std::mem::offset_of!(tagPOINT, x),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1080,7 +1083,7 @@ fn bindgen_test_layout_tagPOINT() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<tagPOINT>())).y as *const _ as usize },
+        ::std::mem::offset_of!(tagPOINT, y),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1117,7 +1120,7 @@ fn bindgen_test_layout_xlref() {
         concat!("Alignment of ", stringify!(xlref))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xlref>())).rwFirst as *const _ as usize },
+        ::std::mem::offset_of!(xlref, rwFirst),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1127,7 +1130,7 @@ fn bindgen_test_layout_xlref() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xlref>())).rwLast as *const _ as usize },
+        ::std::mem::offset_of!(xlref, rwLast),
         2usize,
         concat!(
             "Offset of field: ",
@@ -1137,7 +1140,7 @@ fn bindgen_test_layout_xlref() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xlref>())).colFirst as *const _ as usize },
+        ::std::mem::offset_of!(xlref, colFirst),
         4usize,
         concat!(
             "Offset of field: ",
@@ -1147,7 +1150,7 @@ fn bindgen_test_layout_xlref() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xlref>())).colLast as *const _ as usize },
+        ::std::mem::offset_of!(xlref, colLast),
         5usize,
         concat!(
             "Offset of field: ",
@@ -1178,7 +1181,7 @@ fn bindgen_test_layout_xlmref() {
         concat!("Alignment of ", stringify!(xlmref))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xlmref>())).count as *const _ as usize },
+        ::std::mem::offset_of!(xlmref, count),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1188,7 +1191,7 @@ fn bindgen_test_layout_xlmref() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xlmref>())).reftbl as *const _ as usize },
+        ::std::mem::offset_of!(xlmref, reftbl),
         2usize,
         concat!(
             "Offset of field: ",
@@ -1221,7 +1224,7 @@ fn bindgen_test_layout_xlref12() {
         concat!("Alignment of ", stringify!(xlref12))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xlref12>())).rwFirst as *const _ as usize },
+        ::std::mem::offset_of!(xlref12, rwFirst),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1231,7 +1234,7 @@ fn bindgen_test_layout_xlref12() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xlref12>())).rwLast as *const _ as usize },
+        ::std::mem::offset_of!(xlref12, rwLast),
         4usize,
         concat!(
             "Offset of field: ",
@@ -1241,7 +1244,7 @@ fn bindgen_test_layout_xlref12() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xlref12>())).colFirst as *const _ as usize },
+        ::std::mem::offset_of!(xlref12, colFirst),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1251,7 +1254,7 @@ fn bindgen_test_layout_xlref12() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xlref12>())).colLast as *const _ as usize },
+        ::std::mem::offset_of!(xlref12, colLast),
         12usize,
         concat!(
             "Offset of field: ",
@@ -1282,7 +1285,7 @@ fn bindgen_test_layout_xlmref12() {
         concat!("Alignment of ", stringify!(xlmref12))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xlmref12>())).count as *const _ as usize },
+        ::std::mem::offset_of!(xlmref12, count),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1292,7 +1295,7 @@ fn bindgen_test_layout_xlmref12() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xlmref12>())).reftbl as *const _ as usize },
+        ::std::mem::offset_of!(xlmref12, reftbl),
         4usize,
         concat!(
             "Offset of field: ",
@@ -1324,12 +1327,12 @@ fn bindgen_test_layout__FP() {
         concat!("Alignment of ", stringify!(_FP))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_FP>())).rows as *const _ as usize },
+        ::std::mem::offset_of!(_FP, rows),
         0usize,
         concat!("Offset of field: ", stringify!(_FP), "::", stringify!(rows))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_FP>())).columns as *const _ as usize },
+        ::std::mem::offset_of!(_FP, columns),
         2usize,
         concat!(
             "Offset of field: ",
@@ -1339,7 +1342,7 @@ fn bindgen_test_layout__FP() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_FP>())).array as *const _ as usize },
+        ::std::mem::offset_of!(_FP, array),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1370,7 +1373,7 @@ fn bindgen_test_layout__FP12() {
         concat!("Alignment of ", stringify!(_FP12))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_FP12>())).rows as *const _ as usize },
+        ::std::mem::offset_of!(_FP12, rows),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1380,7 +1383,7 @@ fn bindgen_test_layout__FP12() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_FP12>())).columns as *const _ as usize },
+        ::std::mem::offset_of!(_FP12, columns),
         4usize,
         concat!(
             "Offset of field: ",
@@ -1390,7 +1393,7 @@ fn bindgen_test_layout__FP12() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_FP12>())).array as *const _ as usize },
+        ::std::mem::offset_of!(_FP12, array),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1444,10 +1447,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_1>())).count as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_1, count),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1457,9 +1457,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_1>())).ref_ as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_1, ref_),
         2usize,
         concat!(
             "Offset of field: ",
@@ -1491,10 +1489,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_2() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_2>())).lpmref as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_2, lpmref),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1504,10 +1499,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_2() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_2>())).idSheet as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_2, idSheet),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1540,10 +1532,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_3() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_3>())).lparray as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_3, lparray),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1553,9 +1542,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_3() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_3>())).rows as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_3, rows),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1565,10 +1552,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_3() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_3>())).columns as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_3, columns),
         10usize,
         concat!(
             "Offset of field: ",
@@ -1613,10 +1597,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1>())).level
-                as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1, level),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1626,10 +1607,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1>())).tbctrl
-                as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1, tbctrl),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1639,10 +1617,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1>())).idSheet
-                as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1, idSheet),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1668,10 +1643,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_4() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_4>())).valflow as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_4, valflow),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1681,9 +1653,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_4() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_4>())).rw as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_4, rw),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1693,9 +1663,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_4() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_4>())).col as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_4, col),
         10usize,
         concat!(
             "Offset of field: ",
@@ -1705,10 +1673,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_4() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_4>())).xlflow as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_4, xlflow),
         11usize,
         concat!(
             "Offset of field: ",
@@ -1750,10 +1715,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1>())).lpbData
-                as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1, lpbData),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1763,10 +1725,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1>())).hdata
-                as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1, hdata),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1792,9 +1751,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_5() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_5>())).h as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_5, h),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1804,10 +1761,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1__bindgen_ty_5() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper__bindgen_ty_1__bindgen_ty_5>())).cbData as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1__bindgen_ty_5, cbData),
         8usize,
         concat!(
             "Offset of field: ",
@@ -1830,7 +1784,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1() {
         concat!("Alignment of ", stringify!(xloper__bindgen_ty_1))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper__bindgen_ty_1>())).num as *const _ as usize },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1, num),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1840,7 +1794,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper__bindgen_ty_1>())).str as *const _ as usize },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1, str),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1850,7 +1804,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper__bindgen_ty_1>())).bool_ as *const _ as usize },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1, bool_),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1860,7 +1814,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper__bindgen_ty_1>())).err as *const _ as usize },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1, err),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1870,7 +1824,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper__bindgen_ty_1>())).w as *const _ as usize },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1, w),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1880,7 +1834,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper__bindgen_ty_1>())).sref as *const _ as usize },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1, sref),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1890,7 +1844,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper__bindgen_ty_1>())).mref as *const _ as usize },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1, mref),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1900,7 +1854,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper__bindgen_ty_1>())).array as *const _ as usize },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1, array),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1910,7 +1864,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper__bindgen_ty_1>())).flow as *const _ as usize },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1, flow),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1920,7 +1874,7 @@ fn bindgen_test_layout_xloper__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper__bindgen_ty_1>())).bigdata as *const _ as usize },
+        ::std::mem::offset_of!(xloper__bindgen_ty_1, bigdata),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1943,7 +1897,7 @@ fn bindgen_test_layout_xloper() {
         concat!("Alignment of ", stringify!(xloper))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper>())).val as *const _ as usize },
+        ::std::mem::offset_of!(xloper, val),
         0usize,
         concat!(
             "Offset of field: ",
@@ -1953,7 +1907,7 @@ fn bindgen_test_layout_xloper() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper>())).xltype as *const _ as usize },
+        ::std::mem::offset_of!(xloper, xltype),
         16usize,
         concat!(
             "Offset of field: ",
@@ -2011,10 +1965,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_1>())).count as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_1, count),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2024,10 +1975,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_1>())).ref_ as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_1, ref_),
         4usize,
         concat!(
             "Offset of field: ",
@@ -2062,10 +2010,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_2() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_2>())).lpmref as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_2, lpmref),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2075,10 +2020,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_2() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_2>())).idSheet as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_2, idSheet),
         8usize,
         concat!(
             "Offset of field: ",
@@ -2114,10 +2056,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_3() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_3>())).lparray as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_3, lparray),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2127,10 +2066,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_3() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_3>())).rows as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_3, rows),
         8usize,
         concat!(
             "Offset of field: ",
@@ -2140,10 +2076,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_3() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_3>())).columns as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_3, columns),
         12usize,
         concat!(
             "Offset of field: ",
@@ -2188,10 +2121,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1>())).level
-                as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1, level),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2201,10 +2131,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1>())).tbctrl
-                as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1, tbctrl),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2214,10 +2141,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1>())).idSheet
-                as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_4__bindgen_ty_1, idSheet),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2246,10 +2170,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_4() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_4>())).valflow as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_4, valflow),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2259,9 +2180,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_4() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_4>())).rw as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_4, rw),
         8usize,
         concat!(
             "Offset of field: ",
@@ -2271,10 +2190,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_4() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_4>())).col as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_4, col),
         12usize,
         concat!(
             "Offset of field: ",
@@ -2284,10 +2200,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_4() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_4>())).xlflow as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_4, xlflow),
         16usize,
         concat!(
             "Offset of field: ",
@@ -2329,10 +2242,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1>())).lpbData
-                as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1, lpbData),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2342,10 +2252,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1>())).hdata
-                as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_5__bindgen_ty_1, hdata),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2374,9 +2281,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_5() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_5>())).h as *const _ as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_5, h),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2386,10 +2291,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1__bindgen_ty_5() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<xloper12__bindgen_ty_1__bindgen_ty_5>())).cbData as *const _
-                as usize
-        },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1__bindgen_ty_5, cbData),
         8usize,
         concat!(
             "Offset of field: ",
@@ -2412,7 +2314,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1() {
         concat!("Alignment of ", stringify!(xloper12__bindgen_ty_1))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper12__bindgen_ty_1>())).num as *const _ as usize },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1, num),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2422,7 +2324,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper12__bindgen_ty_1>())).str as *const _ as usize },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1, str),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2432,7 +2334,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper12__bindgen_ty_1>())).xbool as *const _ as usize },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1, xbool),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2442,7 +2344,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper12__bindgen_ty_1>())).err as *const _ as usize },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1, err),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2452,7 +2354,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper12__bindgen_ty_1>())).w as *const _ as usize },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1, w),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2462,7 +2364,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper12__bindgen_ty_1>())).sref as *const _ as usize },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1, sref),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2472,7 +2374,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper12__bindgen_ty_1>())).mref as *const _ as usize },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1, mref),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2482,7 +2384,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper12__bindgen_ty_1>())).array as *const _ as usize },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1, array),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2492,7 +2394,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper12__bindgen_ty_1>())).flow as *const _ as usize },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1, flow),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2502,7 +2404,7 @@ fn bindgen_test_layout_xloper12__bindgen_ty_1() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper12__bindgen_ty_1>())).bigdata as *const _ as usize },
+        ::std::mem::offset_of!(xloper12__bindgen_ty_1, bigdata),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2525,7 +2427,7 @@ fn bindgen_test_layout_xloper12() {
         concat!("Alignment of ", stringify!(xloper12))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper12>())).val as *const _ as usize },
+        ::std::mem::offset_of!(xloper12, val),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2535,7 +2437,7 @@ fn bindgen_test_layout_xloper12() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<xloper12>())).xltype as *const _ as usize },
+        ::std::mem::offset_of!(xloper12, xltype),
         24usize,
         concat!(
             "Offset of field: ",
@@ -2554,8 +2456,10 @@ pub type LPXLOPER12 = *mut xloper12;
 // calling convention which is not currently supported by Rust. This is the same as "stdcall"
 // except that the parameters claim at be passed left-to-right rather than right-to-left.
 // This appears not to be the case, so we therefore leave the parameter order alone.
+// We write "system" rather than "stdcall", which means stdcall on 32bit Windows and the
+// platform's C convention elsewhere, so the crate also compiles on non-Windows targets.
 
-extern "cdecl" {
+extern "C" {
     #[link_name = "\u{1}_Excel4"]
     pub fn Excel4(
         xlfn: ::std::os::raw::c_int,
@@ -2564,7 +2468,7 @@ extern "cdecl" {
         ...
     ) -> ::std::os::raw::c_int;
 }
-extern "stdcall" /*pascal*/ {
+extern "system" /*pascal*/ {
     #[link_name = "\u{1}EXCEL4V"]
     pub fn Excel4v(
         xlfn: ::std::os::raw::c_int,
@@ -2573,18 +2477,18 @@ extern "stdcall" /*pascal*/ {
         opers: *mut LPXLOPER,
     ) -> ::std::os::raw::c_int;
 }
-extern "stdcall" /*pascal*/ {
+extern "system" /*pascal*/ {
     #[link_name = "\u{1}XLCALLVER"]
     pub fn XLCallVer() -> ::std::os::raw::c_int;
 }
-extern "stdcall" /*pascal*/ {
+extern "system" /*pascal*/ {
     #[link_name = "\u{1}LPENHELPER"]
     pub fn LPenHelper(
         wCode: ::std::os::raw::c_int, 
         lpv: *mut VOID,
     ) -> ::std::os::raw::c_long;
 }
-extern "cdecl" {
+extern "C" {
     #[link_name = "\u{1}_Excel12"]
     pub fn Excel12(
         xlfn: ::std::os::raw::c_int,
@@ -2593,7 +2497,7 @@ extern "cdecl" {
         ...
     ) -> ::std::os::raw::c_int;
 }
-extern "stdcall" /*pascal*/ {
+extern "system" /*pascal*/ {
     #[link_name = "\u{1}EXCEL12V"]
     pub fn Excel12v(
         xlfn: ::std::os::raw::c_int,
@@ -2603,7 +2507,7 @@ extern "stdcall" /*pascal*/ {
     ) -> ::std::os::raw::c_int;
 }
 pub type PXL_HPC_ASYNC_CALLBACK = ::std::option::Option<
-    unsafe extern "stdcall" fn(dwAsyncHandle: DWORD, operReturn: LPXLOPER12) -> ::std::os::raw::c_int,
+    unsafe extern "system" fn(dwAsyncHandle: DWORD, operReturn: LPXLOPER12) -> ::std::os::raw::c_int,
 >;
 // -EDIT
 
@@ -2630,7 +2534,7 @@ fn bindgen_test_layout__fmlainfo() {
         concat!("Alignment of ", stringify!(_fmlainfo))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_fmlainfo>())).wPointMode as *const _ as usize },
+        ::std::mem::offset_of!(_fmlainfo, wPointMode),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2640,7 +2544,7 @@ fn bindgen_test_layout__fmlainfo() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_fmlainfo>())).cch as *const _ as usize },
+        ::std::mem::offset_of!(_fmlainfo, cch),
         4usize,
         concat!(
             "Offset of field: ",
@@ -2650,7 +2554,7 @@ fn bindgen_test_layout__fmlainfo() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_fmlainfo>())).lpch as *const _ as usize },
+        ::std::mem::offset_of!(_fmlainfo, lpch),
         8usize,
         concat!(
             "Offset of field: ",
@@ -2660,7 +2564,7 @@ fn bindgen_test_layout__fmlainfo() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_fmlainfo>())).ichFirst as *const _ as usize },
+        ::std::mem::offset_of!(_fmlainfo, ichFirst),
         16usize,
         concat!(
             "Offset of field: ",
@@ -2670,7 +2574,7 @@ fn bindgen_test_layout__fmlainfo() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_fmlainfo>())).ichLast as *const _ as usize },
+        ::std::mem::offset_of!(_fmlainfo, ichLast),
         20usize,
         concat!(
             "Offset of field: ",
@@ -2680,7 +2584,7 @@ fn bindgen_test_layout__fmlainfo() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_fmlainfo>())).ichCaret as *const _ as usize },
+        ::std::mem::offset_of!(_fmlainfo, ichCaret),
         24usize,
         concat!(
             "Offset of field: ",
@@ -2714,7 +2618,7 @@ fn bindgen_test_layout__mouseinfo() {
         concat!("Alignment of ", stringify!(_mouseinfo))
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_mouseinfo>())).hwnd as *const _ as usize },
+        ::std::mem::offset_of!(_mouseinfo, hwnd),
         0usize,
         concat!(
             "Offset of field: ",
@@ -2724,7 +2628,7 @@ fn bindgen_test_layout__mouseinfo() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_mouseinfo>())).pt as *const _ as usize },
+        ::std::mem::offset_of!(_mouseinfo, pt),
         8usize,
         concat!(
             "Offset of field: ",
@@ -2734,7 +2638,7 @@ fn bindgen_test_layout__mouseinfo() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_mouseinfo>())).dt as *const _ as usize },
+        ::std::mem::offset_of!(_mouseinfo, dt),
         24usize,
         concat!(
             "Offset of field: ",
@@ -2744,7 +2648,7 @@ fn bindgen_test_layout__mouseinfo() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_mouseinfo>())).ht as *const _ as usize },
+        ::std::mem::offset_of!(_mouseinfo, ht),
         28usize,
         concat!(
             "Offset of field: ",
@@ -2754,7 +2658,7 @@ fn bindgen_test_layout__mouseinfo() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_mouseinfo>())).rw as *const _ as usize },
+        ::std::mem::offset_of!(_mouseinfo, rw),
         32usize,
         concat!(
             "Offset of field: ",
@@ -2764,7 +2668,7 @@ fn bindgen_test_layout__mouseinfo() {
         )
     );
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_mouseinfo>())).col as *const _ as usize },
+        ::std::mem::offset_of!(_mouseinfo, col),
         36usize,
         concat!(
             "Offset of field: ",
@@ -0,0 +1,149 @@
+//! The standard Excel errors, such as #N/A and #VALUE!, that a cell can contain
+
+#![allow(non_upper_case_globals)]
+
+use std::{error, fmt};
+use std::str::FromStr;
+use convert::ConversionError;
+use xlcall::{xlerrNull, xlerrDiv0, xlerrValue, xlerrRef, xlerrName, xlerrNum, xlerrNA,
+    xlerrGettingData};
+
+/// One of the errors that Excel shows in a cell. The discriminants are the xlerr codes
+/// from xlcall, which are what Excel stores in an XLOPER12 of type xltypeErr.
+///
+/// XlError implements std::error::Error, and a Variant can be made from one, so a UDF
+/// body can return `Result<Variant, XlError>`, use `?` on anything that converts to
+/// XlError, and turn any error into the matching error cell at the end.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum XlError {
+    /// #NULL!, an intersection of ranges that do not intersect
+    Null = xlerrNull,
+    /// #DIV/0!, division by zero
+    Div0 = xlerrDiv0,
+    /// #VALUE!, an argument of the wrong type
+    Value = xlerrValue,
+    /// #REF!, a reference to a cell that is not valid
+    Ref = xlerrRef,
+    /// #NAME?, a name that is not recognised
+    Name = xlerrName,
+    /// #NUM!, a number that is not valid
+    Num = xlerrNum,
+    /// #N/A, a value that is not available
+    NA = xlerrNA,
+    /// #GETTING_DATA, shown while Excel waits for an asynchronous or external result
+    GettingData = xlerrGettingData,
+}
+
+impl XlError {
+    /// Looks up an xlerr code, returning None if it is not one Excel defines
+    pub fn from_code(code: u32) -> Option<XlError> {
+        match code {
+            xlerrNull => Some(XlError::Null),
+            xlerrDiv0 => Some(XlError::Div0),
+            xlerrValue => Some(XlError::Value),
+            xlerrRef => Some(XlError::Ref),
+            xlerrName => Some(XlError::Name),
+            xlerrNum => Some(XlError::Num),
+            xlerrNA => Some(XlError::NA),
+            xlerrGettingData => Some(XlError::GettingData),
+            _ => None
+        }
+    }
+
+    /// Returns the xlerr code for this error
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+
+    /// Returns the text Excel shows for this error, such as "#DIV/0!"
+    pub fn as_str(self) -> &'static str {
+        match self {
+            XlError::Null => "#NULL!",
+            XlError::Div0 => "#DIV/0!",
+            XlError::Value => "#VALUE!",
+            XlError::Ref => "#REF!",
+            XlError::Name => "#NAME?",
+            XlError::Num => "#NUM!",
+            XlError::NA => "#N/A",
+            XlError::GettingData => "#GETTING_DATA",
+        }
+    }
+}
+
+impl fmt::Display for XlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl error::Error for XlError {}
+
+/// The error returned when parsing text that is not one of Excel's error values
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseXlErrorError(String);
+
+impl fmt::Display for ParseXlErrorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\" is not an Excel error value", self.0)
+    }
+}
+
+impl error::Error for ParseXlErrorError {}
+
+/// Parses Excel's text for an error, such as "#N/A". As in Excel, case does not matter.
+impl FromStr for XlError {
+    type Err = ParseXlErrorError;
+
+    fn from_str(s: &str) -> Result<XlError, ParseXlErrorError> {
+        let all = [XlError::Null, XlError::Div0, XlError::Value, XlError::Ref,
+            XlError::Name, XlError::Num, XlError::NA, XlError::GettingData];
+        all.iter()
+            .find(|e| e.as_str().eq_ignore_ascii_case(s))
+            .cloned()
+            .ok_or_else(|| ParseXlErrorError(s.to_string()))
+    }
+}
+
+/// A value of the wrong type is shown as #VALUE! in Excel
+impl From<ConversionError> for XlError {
+    fn from(_: ConversionError) -> XlError {
+        XlError::Value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use variant::Variant;
+
+    #[test]
+    fn excel_spelling() {
+        assert_eq!(XlError::Div0.to_string(), "#DIV/0!");
+        assert_eq!(XlError::NA.to_string(), "#N/A");
+        assert_eq!(XlError::Name.to_string(), "#NAME?");
+        assert_eq!("#value!".parse::<XlError>(), Ok(XlError::Value));
+        assert_eq!("#GETTING_DATA".parse::<XlError>(), Ok(XlError::GettingData));
+        assert!("#NA".parse::<XlError>().is_err());
+        assert_eq!(XlError::from_code(xlerrNum), Some(XlError::Num));
+        assert_eq!(XlError::from_code(99), None);
+        assert_eq!(XlError::Ref.code(), xlerrRef);
+    }
+
+    fn halve(arg: &Variant) -> Result<Variant, XlError> {
+        let value = f64::try_from(arg)?;
+        if value < 0.0 {
+            return Err(XlError::Num)
+        }
+        Ok(Variant::from_float(value / 2.0))
+    }
+
+    #[test]
+    fn question_mark_in_udfs() {
+        let result = |arg: Variant| halve(&arg).unwrap_or_else(Variant::from);
+        assert_eq!(result(Variant::from_float(3.0)).as_f64(), Some(1.5));
+        assert_eq!(result(Variant::from_str("abc")).as_error(), Some(XlError::Value));
+        assert_eq!(result(Variant::from_float(-1.0)).to_string(), "#NUM!");
+    }
+}
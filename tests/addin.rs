@@ -0,0 +1,55 @@
+//! Checks the exports generated by xl_addin!, by calling them as Excel would with a
+//! FakeExcel installed.
+
+#[macro_use]
+extern crate xladd;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use xladd::registrator::Reg;
+use xladd::testing::FakeExcel;
+use xladd::variant::Variant;
+use xladd::xlauto::Addin;
+use xladd::xlerror::XlError;
+
+static CLOSED: AtomicUsize = AtomicUsize::new(0);
+
+struct TestAddin;
+
+impl Addin for TestAddin {
+    fn open(reg: &Reg) {
+        reg.add("addinFunction", "QQ", "x", "Test", "A function", &[]);
+    }
+
+    fn close() {
+        CLOSED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn remove() {
+        panic!("cannot remove");
+    }
+
+    fn manager_info() -> Option<String> {
+        Some("Test Addin".to_string())
+    }
+}
+
+xl_addin!(TestAddin);
+
+#[test]
+fn lifecycle() {
+    let excel = FakeExcel::new("C:\\addins\\lifecycle.xll");
+    let _guard = excel.install();
+
+    assert_eq!(xlAutoOpen(), 1);
+    assert!(excel.registration("addinFunction").is_some());
+    assert_eq!(xlAutoAdd(), 1);
+    assert_eq!(xlAutoRemove(), 0);
+
+    let info = |action: Variant| unsafe { Variant::from_xloper(xlAddInManagerInfo12(action.view())) };
+    assert_eq!(info(Variant::from_float(1.0)).to_string(), "Test Addin");
+    assert_eq!(info(Variant::from_int(2)).as_error(), Some(XlError::Value));
+
+    assert_eq!(xlAutoClose(), 1);
+    assert_eq!(CLOSED.load(Ordering::SeqCst), 1);
+    assert!(excel.registrations().is_empty());
+}
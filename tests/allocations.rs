@@ -0,0 +1,124 @@
+//! Checks that Variant frees everything it allocates, by counting allocations made by the
+//! current thread. Tests run in parallel, so the counts are kept per thread.
+
+extern crate xladd;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::convert::TryFrom;
+use xladd::entrypoint::{excel12, excel12_1, try_excel12_xl};
+use xladd::testing::FakeExcel;
+use xladd::variant::Variant;
+use xladd::xlauto::xlAutoFree12;
+use xladd::xlcall::xlbitDLLFree;
+use xladd::xlcall::{xlCoerce, xlfCaller, xlSheetNm};
+
+struct CountingAllocator;
+
+thread_local! {
+    static LIVE: Cell<isize> = const { Cell::new(0) };
+}
+
+fn adjust(delta: isize) {
+    // try_with, as the allocator may be used while the thread is being torn down
+    let _ = LIVE.try_with(|live| live.set(live.get() + delta));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        adjust(1);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        adjust(-1);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Runs the closure and drops what it returns, returning the number of allocations made
+// that were not freed
+fn leaked<T, F: FnOnce() -> T>(f: F) -> isize {
+    let before = LIVE.with(|live| live.get());
+    drop(f());
+    LIVE.with(|live| live.get()) - before
+}
+
+#[test]
+fn constructing_and_dropping() {
+    assert_eq!(leaked(|| ::std::mem::forget(Variant::from_str("forgotten"))), 1);
+    assert_eq!(leaked(|| Variant::from_str("hello")), 0);
+    assert_eq!(leaked(|| Variant::from_array(2, 2, &[Variant::from_str("a"), Variant::from_float(1.0)])), 0);
+    assert_eq!(leaked(|| Variant::from(vec![vec!["a", "b"], vec!["c"]])), 0);
+
+    let array = Variant::from(vec![vec![1.0, 2.0], vec![3.0, 4.0]]);
+    let text = Variant::from_str("text");
+    assert_eq!(leaked(|| (array.clone(), text.clone())), 0);
+    assert_eq!(leaked(|| Variant::concat(&[array.clone(), text.clone()], true)), 0);
+    assert_eq!(leaked(|| Variant::concat(&[text.clone(), array.clone()], false).transpose()), 0);
+    assert_eq!(leaked(|| (array.at(1, 1), array.transpose().at(0, 1))), 0);
+}
+
+#[test]
+fn reading_without_copying() {
+    let array = Variant::from(vec![vec!["a", "b"], vec!["c", "d"]]);
+    let mut count = 0;
+    assert_eq!(leaked(|| {
+        let view = array.view();
+        count = view.array().iter().filter(|cell| cell.as_wstr() == Some(&[b'c' as u16][..])).count();
+    }), 0);
+    assert_eq!(count, 1);
+
+    assert_eq!(leaked(|| Vec::<Vec<String>>::try_from(&array).unwrap()), 0);
+    assert_eq!(leaked(|| array.view().to_variant()), 0);
+}
+
+#[test]
+fn results_from_excel() {
+    let excel = FakeExcel::new("test.xll");
+    let sheet = excel.add_sheet("[Book1]Data");
+    excel.set_cell(sheet, 0, 0, Variant::from_str("x"));
+    excel.set_cell(sheet, 1, 0, Variant::from_float(2.0));
+    let range = excel.reference(sheet, (0, 1), (0, 0));
+    excel.set_caller(Some((sheet, 3, 3)));
+    let _guard = excel.install();
+
+    // Excel's copies are freed as soon as they are copied, or when an XlOwnedVariant drops
+    let values = excel12_1(xlCoerce, range.clone());
+    assert_eq!(values.at(0, 1).as_f64(), Some(2.0));
+    assert_eq!(excel.unfreed_results(), 0);
+    {
+        let values = try_excel12_xl(xlCoerce, &mut [range.clone()]).unwrap();
+        assert_eq!(values.view().at(0, 0).and_then(|v| v.as_str()).unwrap(), "x");
+        assert_eq!(excel.unfreed_results(), 1);
+    }
+    assert_eq!(excel.unfreed_results(), 0);
+
+    let caller = excel12(xlfCaller, &mut []);
+    assert!(caller.is_ref());
+    assert_eq!(excel.unfreed_results(), 0);
+    assert_eq!(excel12_1(xlSheetNm, caller).to_string(), "[Book1]Data");
+    assert_eq!(leaked(|| range.clone()), 0);
+}
+
+#[test]
+fn returning_results() {
+    // Excel calls xlAutoFree12 on anything returned with xlbitDLLFree
+    assert_eq!(leaked(|| {
+        let result = Variant::from(vec![vec!["a", "b"], vec!["c", "d"]]).into_excel_return();
+        assert!(unsafe { (*result).xltype } & xlbitDLLFree != 0);
+        xlAutoFree12(result);
+    }), 0);
+    assert_eq!(leaked(|| xlAutoFree12(Variant::from_float(1.0).into_excel_return())), 0);
+
+    // The return slot holds on to one result per thread, freeing it on the next return
+    assert_eq!(leaked(|| {
+        let result = Variant::from_str("first").into_excel_return_slot();
+        assert_eq!(unsafe { (*result).xltype } & xlbitDLLFree, 0);
+    }), 1);
+    assert_eq!(leaked(|| Variant::from_str("second").into_excel_return_slot()), 0);
+    assert_eq!(leaked(|| Variant::from_float(3.0).into_excel_return_slot()), -1);
+}
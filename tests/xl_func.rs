@@ -0,0 +1,74 @@
+//! Checks the code generated by #[xl_func], by registering and calling the functions in a
+//! FakeExcel.
+
+#[macro_use]
+extern crate xladd;
+
+use xladd::registrator::Reg;
+use xladd::testing::FakeExcel;
+use xladd::udf::{functions, register_all};
+use xladd::variant::Variant;
+use xladd::variant_ref::VariantRef;
+use xladd::xlcall::LPXLOPER12;
+use xladd::xlerror::XlError;
+
+/// Adds two numbers, or a number to one
+/// if the second is missing
+///
+/// * `a` - the first number
+/// * `b` - the second number, which defaults to one
+#[xl_func(category = "Test Maths", threadsafe)]
+fn add(a: f64, b: Option<f64>) -> f64 {
+    a + b.unwrap_or(1.0)
+}
+
+/// Counts the strings in a range
+#[xl_func(name = "CountText", volatile)]
+fn count_text(range: VariantRef) -> i32 {
+    range.array().iter().filter(|cell| cell.as_wstr().is_some()).count() as i32
+}
+
+/// Fails on request
+#[xl_func]
+fn fail(how: String) -> Result<String, XlError> {
+    match how.as_str() {
+        "error" => Err(XlError::Num),
+        "panic" => panic!("asked to panic"),
+        _ => Ok(how)
+    }
+}
+
+type Binary = extern "system" fn(VariantRef, VariantRef) -> LPXLOPER12;
+type Unary = extern "system" fn(VariantRef) -> LPXLOPER12;
+
+#[test]
+fn generated_functions() {
+    let excel = FakeExcel::new("C:\\addins\\generated.xll");
+    excel.export("add", __xladd_export_add as Binary);
+    excel.export("CountText", __xladd_export_count_text as Unary);
+    excel.export("fail", __xladd_export_fail as Unary);
+    let _guard = excel.install();
+
+    assert_eq!(functions().count(), 3);
+    register_all(&Reg::new()).unwrap();
+
+    let add = excel.registration("add").unwrap();
+    assert_eq!(add.type_text, "QQQ$");
+    assert_eq!(add.argument_text, "a, b");
+    assert_eq!(add.category, "Test Maths");
+    assert_eq!(add.function_help, "Adds two numbers, or a number to one if the second is missing");
+    assert_eq!(add.argument_help, vec!["the first number", "the second number, which defaults to one"]);
+    assert_eq!(excel.registration("CountText").unwrap().type_text, "QQ!");
+
+    assert_eq!(excel.call("add", &[Variant::from_float(2.0), Variant::from_str("3")]).as_f64(), Some(5.0));
+    assert_eq!(excel.call("add", &[Variant::from_float(2.0)]).as_f64(), Some(3.0));
+    assert_eq!(excel.call("add", &[Variant::from_str("x")]).as_error(), Some(XlError::Value));
+
+    let range = Variant::from(vec![Variant::from("a"), Variant::from(1.0), Variant::from("b")]);
+    assert_eq!(excel.call("CountText", &[range]).as_i32(), Some(2));
+    assert_eq!(excel.call("CountText", &[Variant::from_str("a")]).as_i32(), Some(1));
+
+    assert_eq!(excel.call("fail", &[Variant::from_str("fine")]).to_string(), "fine");
+    assert_eq!(excel.call("fail", &[Variant::from_str("error")]).as_error(), Some(XlError::Num));
+    assert_eq!(excel.call("fail", &[Variant::from_str("panic")]).as_error(), Some(XlError::Value));
+}
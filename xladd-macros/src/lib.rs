@@ -0,0 +1,186 @@
+//! Procedural macros for xladd. These are re-exported by xladd, and should be used from
+//! there rather than by depending on this crate directly. See xladd::udf for how to use
+//! `#[xl_func]`.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Attribute, Expr, ExprLit, FnArg, ItemFn, Lit, LitStr, Meta, Pat};
+
+// The settings given in the attribute, such as #[xl_func(category = "Maths", threadsafe)]
+#[derive(Default)]
+struct Settings {
+    name: Option<LitStr>,
+    category: Option<LitStr>,
+    threadsafe: bool,
+    volatile: bool,
+}
+
+/// Makes an ordinary Rust function callable from Excel. This generates an exported shim
+/// that converts the arguments, calls the function with panic protection and converts the
+/// result, and submits a description of the function for xladd::udf::register_all.
+#[proc_macro_attribute]
+pub fn xl_func(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut settings = Settings::default();
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            settings.name = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("category") {
+            settings.category = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("threadsafe") {
+            settings.threadsafe = true;
+        } else if meta.path.is_ident("volatile") {
+            settings.volatile = true;
+        } else {
+            return Err(meta.error("expected name, category, threadsafe or volatile"));
+        }
+        Ok(())
+    });
+    parse_macro_input!(attr with parser);
+    let function = parse_macro_input!(item as ItemFn);
+
+    match expand(settings, function) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => compile_errors(err).into()
+    }
+}
+
+// Reports errors with a bare compile_error!, rather than syn's to_compile_error, which
+// names the macro by its path in core and so cannot be resolved from 2015 edition crates
+fn compile_errors(err: syn::Error) -> proc_macro2::TokenStream {
+    err.into_iter().map(|e| {
+        let message = e.to_string();
+        quote_spanned!(e.span()=> compile_error!(#message);)
+    }).collect()
+}
+
+fn expand(settings: Settings, function: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    let sig = &function.sig;
+    if !sig.generics.params.is_empty() || sig.asyncness.is_some() || sig.variadic.is_some() {
+        return Err(syn::Error::new_spanned(sig, "#[xl_func] functions cannot be generic, async or variadic"));
+    }
+    if sig.inputs.len() > 255 {
+        return Err(syn::Error::new_spanned(&sig.inputs, "Excel functions can have at most 255 arguments"));
+    }
+
+    let mut arg_names = Vec::new();
+    for input in sig.inputs.iter() {
+        match input {
+            FnArg::Typed(typed) => match *typed.pat {
+                Pat::Ident(ref ident) => arg_names.push(ident.ident.clone()),
+                _ => return Err(syn::Error::new_spanned(&typed.pat, "#[xl_func] arguments must be simple names"))
+            },
+            FnArg::Receiver(_) => return Err(syn::Error::new_spanned(input, "#[xl_func] functions cannot take self"))
+        }
+    }
+
+    let ident = &sig.ident;
+    let excel_name = settings.name.unwrap_or_else(|| LitStr::new(&ident.to_string(), ident.span()));
+    let category = settings.category.unwrap_or_else(|| LitStr::new("", Span::call_site()));
+    let shim = format_ident!("__xladd_export_{}", ident);
+
+    // Every argument and the result are passed as XLOPER12s
+    let mut type_text = "Q".repeat(arg_names.len() + 1);
+    if settings.volatile {
+        type_text.push('!');
+    }
+    if settings.threadsafe {
+        type_text.push('$');
+    }
+
+    let arg_text = arg_names.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(", ");
+    let (help, documented) = parse_docs(&function.attrs);
+    let arg_help: Vec<String> = arg_names.iter()
+        .map(|a| documented.iter().find(|d| *a == d.0).map(|d| d.1.clone()).unwrap_or_default())
+        .collect();
+
+    // Excel rejects registrations with any text longer than 255 characters, so catch that
+    // here rather than when the addin is loaded
+    let doc_span = function.attrs.iter().find(|attr| attr.path().is_ident("doc"))
+        .map(|attr| attr.span()).unwrap_or_else(|| ident.span());
+    check_length("category", &category.value(), category.span())?;
+    check_length("argument list", &arg_text, sig.inputs.span())?;
+    check_length("help text", &help, doc_span)?;
+    for (name, text) in arg_names.iter().zip(arg_help.iter()) {
+        check_length(&format!("help for `{}`", name), text, doc_span)?;
+    }
+
+    let protect_name = ident.to_string();
+    Ok(quote! {
+        #function
+
+        #[export_name = #excel_name]
+        #[doc(hidden)]
+        #[allow(non_snake_case)]
+        extern "system" fn #shim(#(#arg_names: ::xladd::variant_ref::VariantRef),*) -> ::xladd::xlcall::LPXLOPER12 {
+            ::xladd::protect::udf(#protect_name, || {
+                #(
+                    let #arg_names = match ::xladd::udf::arg(#arg_names) {
+                        Ok(value) => value,
+                        Err(err) => return err
+                    };
+                )*
+                ::xladd::udf::IntoReturn::into_return(#ident(#(#arg_names),*))
+            })
+        }
+
+        ::xladd::__submit! {
+            ::xladd::udf::XlFunction {
+                name: #excel_name,
+                type_text: #type_text,
+                arg_text: #arg_text,
+                category: #category,
+                help: #help,
+                arg_help: &[#(#arg_help),*],
+            }
+        }
+    })
+}
+
+// The longest text that Excel accepts in a registration
+const MAX_TEXT: usize = 255;
+
+// Checks a text against Excel's limit, which is in UTF-16 units
+fn check_length(what: &str, text: &str, span: Span) -> syn::Result<()> {
+    let len = text.encode_utf16().count();
+    if len > MAX_TEXT {
+        let message = format!("the {} is {} characters long, but Excel allows at most {}", what, len, MAX_TEXT);
+        return Err(syn::Error::new(span, message))
+    }
+    Ok(())
+}
+
+// Reads the doc comments, returning the first paragraph as the help text, and the help
+// for any arguments documented in the form "* `name` - text".
+fn parse_docs(attrs: &[Attribute]) -> (String, Vec<(String, String)>) {
+    let lines: Vec<String> = attrs.iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match attr.meta {
+            Meta::NameValue(ref nv) => match nv.value {
+                Expr::Lit(ExprLit { lit: Lit::Str(ref s), .. }) => Some(s.value().trim().to_string()),
+                _ => None
+            },
+            _ => None
+        })
+        .collect();
+
+    let help = lines.iter()
+        .take_while(|line| !line.is_empty() && !line.starts_with('*') && !line.starts_with('#'))
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let args = lines.iter()
+        .filter_map(|line| {
+            let rest = line.strip_prefix("* `")?;
+            let end = rest.find('`')?;
+            let text = rest[end + 1..].trim_start().trim_start_matches(['-', ':']).trim();
+            Some((rest[..end].to_string(), text.to_string()))
+        })
+        .collect();
+
+    (help, args)
+}